@@ -0,0 +1,481 @@
+//! minifb window showing the frames and reading the keyboard
+
+use std::{
+    cell::RefCell,
+    fs,
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{info, warn};
+use minifb::{Key, KeyRepeat, Window};
+
+use crate::gameboy::{
+    AudioSink, Button, Command, DebugLayer, Frame, FrameSink, GameBoy, Image, InputSource,
+    PostProcessing, VramView, FILTER_PRESETS,
+};
+
+mod audio;
+mod bindings;
+pub use audio::{AudioBackend, AudioOutput, NullAudio};
+pub use bindings::KeyBindings;
+
+const WIDTH: usize = 160;
+const HEIGHT: usize = 144;
+/// 70224 cycles at 4194304Hz, slightly longer than a 60Hz frame
+const FRAME_DURATION: Duration = Duration::from_nanos(16_742_706);
+/// Volume change of the - and = keys
+const VOLUME_STEP: f32 = 0.1;
+
+const EXIT_KEY: Key = Key::Escape;
+const PALETTE_KEY: Key = Key::P;
+const FILTER_KEY: Key = Key::F;
+const HIGHLIGHT_KEY: Key = Key::F4;
+const MUTE_KEY: Key = Key::M;
+const VOLUME_DOWN_KEY: Key = Key::Minus;
+const VOLUME_UP_KEY: Key = Key::Equal;
+const RECORD_KEY: Key = Key::R;
+const SCREENSHOT_KEY: Key = Key::F12;
+/// Moves the input to the other console of a split screen
+const SWITCH_KEY: Key = Key::Tab;
+const VRAM_VIEW_KEY: Key = Key::V;
+const VRAM_PALETTE_KEY: Key = Key::C;
+
+const CHANNEL_KEYS: [Key; 4] = [Key::Key1, Key::Key2, Key::Key3, Key::Key4];
+
+const LAYER_KEYS: [(Key, DebugLayer); 3] = [
+    (Key::F1, DebugLayer::Background),
+    (Key::F2, DebugLayer::Window),
+    (Key::F3, DebugLayer::Objects),
+];
+
+/// Every key the windows use, which the joypad should not be bound to
+fn hotkeys() -> impl Iterator<Item = Key> {
+    [
+        EXIT_KEY,
+        PALETTE_KEY,
+        FILTER_KEY,
+        HIGHLIGHT_KEY,
+        MUTE_KEY,
+        VOLUME_DOWN_KEY,
+        VOLUME_UP_KEY,
+        RECORD_KEY,
+        SCREENSHOT_KEY,
+        SWITCH_KEY,
+        VRAM_VIEW_KEY,
+        VRAM_PALETTE_KEY,
+    ]
+    .into_iter()
+    .chain(CHANNEL_KEYS)
+    .chain(LAYER_KEYS.map(|(key, _)| key))
+}
+
+struct ScreenWindow {
+    window: Window,
+    post_processing: PostProcessing,
+    /// Position in `FILTER_PRESETS` of the filters, None for a custom chain
+    filter_preset: Option<usize>,
+    commands: Vec<Command>,
+    bindings: KeyBindings,
+    /// Directory and game title of the screenshots
+    screenshots: Option<(PathBuf, String)>,
+    /// Directory and game title of the recordings
+    recordings: Option<(PathBuf, String)>,
+    audio: Option<AudioOutput>,
+}
+
+/// `<directory>/<title>-<unix time in ms>.<extension>`
+pub fn capture_path(directory: &Path, title: &str, extension: &str) -> PathBuf {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0);
+    let title: String = title
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    directory.join(format!("{}-{}.{}", title, timestamp, extension))
+}
+
+fn save_screenshot(image: &Image, directory: &Path, title: &str) {
+    let path = capture_path(directory, title, "png");
+    let saved = fs::create_dir_all(directory).and_then(|_| image.save_png(&path));
+    match saved {
+        Ok(_) => info!("Screenshot saved to {:?}", path),
+        Err(e) => warn!("Could not save screenshot to {:?}: {}", path, e),
+    }
+}
+
+/// Window for `width` x `height` images, reopened when the filters change
+/// their size
+fn open_window(width: usize, height: usize) -> Window {
+    // frames not scaled by the post-processing are shown twice as big
+    let scale = if width < WIDTH * 2 {
+        minifb::Scale::X2
+    } else {
+        minifb::Scale::X1
+    };
+    let window_opts = minifb::WindowOptions {
+        scale,
+        ..Default::default()
+    };
+
+    let mut window =
+        Window::new("Test - ESC to exit", width, height, window_opts).unwrap_or_else(|e| {
+            panic!("{}", e);
+        });
+
+    // the audio output adjusts its rate to this pace
+    window.limit_update_rate(Some(FRAME_DURATION));
+    window
+}
+
+/// A window showing a single console.
+///
+/// P cycles the DMG palettes, F the post-processing filters, F12 saves a
+/// screenshot and R starts or stops recording. F1/F2/F3 show or hide the
+/// background, window and objects, F4 tints them. M mutes the audio, - and =
+/// change the volume and 1-4 mute each channel. The handle is cloned to be
+/// both the frame sink and the input source of a console.
+#[derive(Clone)]
+pub struct Screen {
+    screen: Rc<RefCell<ScreenWindow>>,
+}
+
+impl FrameSink for Screen {
+    fn present(&mut self, frame: &Frame) {
+        let mut screen = self.screen.borrow_mut();
+        if !screen.window.is_open() || screen.window.is_key_down(EXIT_KEY) {
+            panic!("window deado")
+        }
+
+        if screen.window.is_key_pressed(PALETTE_KEY, KeyRepeat::No) {
+            screen.commands.push(Command::NextPalette);
+        }
+        if screen.window.is_key_pressed(FILTER_KEY, KeyRepeat::No) {
+            let preset = screen
+                .filter_preset
+                .map_or(0, |i| (i + 1) % FILTER_PRESETS.len());
+            info!("Filters: {:?}", FILTER_PRESETS[preset]);
+            let post_processing = PostProcessing::parse(FILTER_PRESETS[preset]).unwrap();
+            let size = post_processing.output_size(frame.width, frame.height);
+            if size
+                != screen
+                    .post_processing
+                    .output_size(frame.width, frame.height)
+            {
+                screen.window = open_window(size.0, size.1);
+            }
+            screen.post_processing = post_processing;
+            screen.filter_preset = Some(preset);
+        }
+        for (key, layer) in LAYER_KEYS {
+            if screen.window.is_key_pressed(key, KeyRepeat::No) {
+                screen.commands.push(Command::ToggleLayer(layer));
+            }
+        }
+        if screen.window.is_key_pressed(HIGHLIGHT_KEY, KeyRepeat::No) {
+            screen.commands.push(Command::ToggleHighlight);
+        }
+        for (channel, key) in CHANNEL_KEYS.iter().enumerate() {
+            if screen.window.is_key_pressed(*key, KeyRepeat::No) {
+                screen.commands.push(Command::ToggleChannel(channel));
+            }
+        }
+        let mute = screen.window.is_key_pressed(MUTE_KEY, KeyRepeat::No);
+        let volume_down = screen
+            .window
+            .is_key_pressed(VOLUME_DOWN_KEY, KeyRepeat::Yes);
+        let volume_up = screen.window.is_key_pressed(VOLUME_UP_KEY, KeyRepeat::Yes);
+        if let Some(audio) = screen.audio.as_mut() {
+            if mute {
+                audio.toggle_mute();
+            }
+            if volume_down {
+                audio.set_volume(audio.volume() - VOLUME_STEP);
+            }
+            if volume_up {
+                audio.set_volume(audio.volume() + VOLUME_STEP);
+            }
+        }
+        if screen.window.is_key_pressed(RECORD_KEY, KeyRepeat::No) {
+            if let Some((directory, title)) = screen.recordings.as_ref() {
+                let path = capture_path(directory, title, "y4m");
+                screen.commands.push(Command::ToggleRecording(path));
+            }
+        }
+
+        let screenshot = screen.window.is_key_pressed(SCREENSHOT_KEY, KeyRepeat::No);
+
+        let ScreenWindow {
+            window,
+            post_processing,
+            screenshots,
+            ..
+        } = &mut *screen;
+        let (pixels, width, height) = if post_processing.is_empty() {
+            (&frame.pixels, frame.width, frame.height)
+        } else {
+            let image = post_processing.process(&frame.pixels, frame.width, frame.height);
+            (&image.pixels, image.width, image.height)
+        };
+        window.update_with_buffer(pixels, width, height).unwrap();
+
+        if let (true, Some((directory, title))) = (screenshot, screenshots.as_ref()) {
+            let image = Image {
+                pixels: pixels.clone(),
+                width,
+                height,
+            };
+            save_screenshot(&image, directory, title);
+        }
+    }
+}
+
+impl AudioSink for Screen {
+    fn play(&mut self, samples: &[(i16, i16)]) {
+        if let Some(audio) = self.screen.borrow_mut().audio.as_mut() {
+            audio.play(samples);
+        }
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: u32) {
+        if let Some(audio) = self.screen.borrow_mut().audio.as_mut() {
+            audio.set_input_rate(sample_rate);
+        }
+    }
+}
+
+impl InputSource for Screen {
+    fn pressed_buttons(&mut self) -> Vec<Button> {
+        let screen = self.screen.borrow();
+        screen.bindings.pressed(&screen.window.get_keys())
+    }
+
+    fn commands(&mut self) -> Vec<Command> {
+        std::mem::take(&mut self.screen.borrow_mut().commands)
+    }
+}
+
+impl Screen {
+    /// Opens a window for `width` x `height` frames, see `GameBoy::frame`
+    pub fn open(width: usize, height: usize, post_processing: PostProcessing) -> Self {
+        let (width, height) = post_processing.output_size(width, height);
+        let window = open_window(width, height);
+        let filter_preset = if post_processing.is_empty() {
+            Some(0)
+        } else {
+            None
+        };
+        Screen {
+            screen: Rc::new(RefCell::new(ScreenWindow {
+                window,
+                post_processing,
+                filter_preset,
+                commands: Vec::new(),
+                bindings: KeyBindings::default(),
+                screenshots: None,
+                recordings: None,
+                audio: None,
+            })),
+        }
+    }
+
+    /// F12 saves what is shown in `directory`, named after the game
+    pub fn save_screenshots(&self, directory: PathBuf, title: &str) {
+        self.screen.borrow_mut().screenshots = Some((directory, title.to_string()));
+    }
+
+    /// R records the game to a video in `directory`, named after the game
+    pub fn save_recordings(&self, directory: PathBuf, title: &str) {
+        self.screen.borrow_mut().recordings = Some((directory, title.to_string()));
+    }
+
+    /// Keys of the joypad buttons, see `KeyBindings::load`
+    pub fn set_key_bindings(&self, bindings: KeyBindings) {
+        self.screen.borrow_mut().bindings = bindings;
+    }
+
+    /// Plays the samples the handle gets as an `AudioSink`
+    pub fn set_audio(&self, audio: AudioOutput) {
+        self.screen.borrow_mut().audio = Some(audio);
+    }
+}
+
+/// Largest VRAM view, both tile maps side by side
+const VRAM_WIDTH: usize = 520;
+const VRAM_HEIGHT: usize = 256;
+
+/// A second window showing the VRAM of a console, refreshed every frame.
+///
+/// V switches between the tiles, tile maps, OAM and palettes, C cycles the
+/// palette the tiles are shown with.
+pub struct VramWindow {
+    window: Window,
+    view: VramView,
+    buffer: Vec<u32>,
+}
+
+impl VramWindow {
+    pub fn open(view: VramView) -> Self {
+        let window_opts = minifb::WindowOptions {
+            scale: minifb::Scale::X2,
+            ..Default::default()
+        };
+        let window = Window::new(
+            "VRAM - V to switch view, C to switch palette",
+            VRAM_WIDTH,
+            VRAM_HEIGHT,
+            window_opts,
+        )
+        .unwrap_or_else(|e| {
+            panic!("{}", e);
+        });
+
+        VramWindow {
+            window,
+            view,
+            buffer: vec![0; VRAM_WIDTH * VRAM_HEIGHT],
+        }
+    }
+
+    /// Draws the current view, closing the window only stops the updates
+    pub fn update(&mut self, gb: &GameBoy) {
+        if !self.window.is_open() {
+            return;
+        }
+        if self.window.is_key_pressed(VRAM_VIEW_KEY, KeyRepeat::No) {
+            self.view = self.view.next();
+            info!("VRAM view: {:?}", self.view);
+        }
+        if self.window.is_key_pressed(VRAM_PALETTE_KEY, KeyRepeat::No) {
+            if let VramView::Tiles(palette) = self.view {
+                self.view = VramView::Tiles(palette.next());
+                info!("VRAM view: {:?}", self.view);
+            }
+        }
+
+        let image = gb.vram_view(self.view);
+        self.buffer.fill(0);
+        for (y, line) in image.pixels.chunks(image.width).enumerate() {
+            let start = y * VRAM_WIDTH;
+            self.buffer[start..start + image.width].copy_from_slice(line);
+        }
+        self.window
+            .update_with_buffer(&self.buffer, VRAM_WIDTH, VRAM_HEIGHT)
+            .unwrap();
+    }
+}
+
+/// A single window showing two consoles side by side.
+///
+/// Input goes to one console at a time, Tab switches between them.
+pub struct SplitScreen {
+    window: Window,
+    buffer: Vec<u32>,
+    refreshed: [bool; 2],
+    focus: usize,
+}
+
+impl SplitScreen {
+    fn present(&mut self) {
+        if !self.window.is_open() || self.window.is_key_down(EXIT_KEY) {
+            panic!("window deado")
+        }
+        if self.window.is_key_pressed(SWITCH_KEY, KeyRepeat::No) {
+            self.focus = 1 - self.focus;
+        }
+        self.window
+            .update_with_buffer(&self.buffer, WIDTH * 2, HEIGHT)
+            .unwrap();
+        self.refreshed = [false, false];
+    }
+
+    /// Both halves of a new window, to be given to each console
+    pub fn open() -> (SplitScreenHalf, SplitScreenHalf) {
+        let window_opts = minifb::WindowOptions {
+            scale: minifb::Scale::X2,
+            ..Default::default()
+        };
+
+        let mut window = Window::new(
+            "Link - TAB to switch player, ESC to exit",
+            WIDTH * 2,
+            HEIGHT,
+            window_opts,
+        )
+        .unwrap_or_else(|e| {
+            panic!("{}", e);
+        });
+        window.limit_update_rate(Some(FRAME_DURATION));
+
+        let screen = Rc::new(RefCell::new(SplitScreen {
+            window,
+            buffer: vec![0; WIDTH * 2 * HEIGHT],
+            refreshed: [false, false],
+            focus: 0,
+        }));
+        (
+            SplitScreenHalf {
+                screen: screen.clone(),
+                index: 0,
+                bindings: KeyBindings::default(),
+            },
+            SplitScreenHalf {
+                screen,
+                index: 1,
+                bindings: KeyBindings::default(),
+            },
+        )
+    }
+}
+
+#[derive(Clone)]
+pub struct SplitScreenHalf {
+    screen: Rc<RefCell<SplitScreen>>,
+    index: usize,
+    bindings: KeyBindings,
+}
+
+impl SplitScreenHalf {
+    /// Keys of the joypad buttons when this console has the focus, to be set
+    /// before handing the half to the console
+    pub fn set_key_bindings(&mut self, bindings: KeyBindings) {
+        self.bindings = bindings;
+    }
+}
+
+impl FrameSink for SplitScreenHalf {
+    fn present(&mut self, frame: &Frame) {
+        let mut split = self.screen.borrow_mut();
+        // the other console did not finish a frame since
+        if split.refreshed[self.index] {
+            split.present();
+        }
+
+        // only the LCD is shown, without the SGB border
+        let (left, top) = ((frame.width - WIDTH) / 2, (frame.height - HEIGHT) / 2);
+        let lines = frame.pixels.chunks(frame.width).skip(top).take(HEIGHT);
+        for (y, line) in lines.enumerate() {
+            let start = y * WIDTH * 2 + self.index * WIDTH;
+            split.buffer[start..start + WIDTH].copy_from_slice(&line[left..left + WIDTH]);
+        }
+        split.refreshed[self.index] = true;
+
+        if split.refreshed == [true, true] {
+            split.present();
+        }
+    }
+}
+
+impl InputSource for SplitScreenHalf {
+    fn pressed_buttons(&mut self) -> Vec<Button> {
+        let split = self.screen.borrow();
+        if split.focus == self.index {
+            self.bindings.pressed(&split.window.get_keys())
+        } else {
+            vec![]
+        }
+    }
+}
@@ -0,0 +1,340 @@
+//! Host audio output: resamples the console samples to the device rate and
+//! keeps the device buffer around a target latency
+
+use log::info;
+#[cfg(feature = "audio")]
+use log::warn;
+
+use crate::gameboy::DEFAULT_SAMPLE_RATE;
+
+/// Latency the device buffer is kept around
+const TARGET_LATENCY_MS: usize = 60;
+/// Largest change of the resampling ratio, in both directions. The video is
+/// paced by the window at the Game Boy frame rate, this absorbs the drift
+/// between that timer and the audio clock without audible pitch changes.
+const MAX_RATE_DELTA: f64 = 0.005;
+
+/// Where the resampled stereo samples go
+pub trait AudioBackend {
+    /// Samples per second the device plays
+    fn sample_rate(&self) -> u32;
+    fn queue(&mut self, samples: &[(f32, f32)]);
+    /// Samples queued but not played yet
+    fn queued(&self) -> usize;
+}
+
+/// Throws the samples away, for machines without audio
+pub struct NullAudio;
+
+impl AudioBackend for NullAudio {
+    fn sample_rate(&self) -> u32 {
+        DEFAULT_SAMPLE_RATE
+    }
+
+    fn queue(&mut self, _samples: &[(f32, f32)]) {}
+
+    fn queued(&self) -> usize {
+        0
+    }
+}
+
+#[cfg(feature = "audio")]
+mod device {
+    use std::{
+        collections::VecDeque,
+        sync::{Arc, Mutex},
+    };
+
+    use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+    use log::warn;
+
+    use super::AudioBackend;
+
+    /// The default output device of the system
+    pub struct DeviceAudio {
+        queue: Arc<Mutex<VecDeque<(f32, f32)>>>,
+        sample_rate: u32,
+        _stream: cpal::Stream,
+    }
+
+    fn build_stream<T: cpal::SizedSample + cpal::FromSample<f32>>(
+        device: &cpal::Device,
+        config: &cpal::StreamConfig,
+        queue: Arc<Mutex<VecDeque<(f32, f32)>>>,
+    ) -> Result<cpal::Stream, cpal::BuildStreamError> {
+        let channels = config.channels as usize;
+        device.build_output_stream(
+            config,
+            move |data: &mut [T], _| {
+                let mut queue = queue.lock().unwrap();
+                for frame in data.chunks_mut(channels) {
+                    // silence when the emulator is late
+                    let (left, right) = queue.pop_front().unwrap_or((0.0, 0.0));
+                    for (channel, sample) in frame.iter_mut().enumerate() {
+                        let value = match (channels, channel) {
+                            (1, _) => (left + right) / 2.0,
+                            (_, 0) => left,
+                            (_, 1) => right,
+                            _ => 0.0,
+                        };
+                        *sample = T::from_sample(value);
+                    }
+                }
+            },
+            |e| warn!("Audio stream error: {}", e),
+            None,
+        )
+    }
+
+    impl DeviceAudio {
+        pub fn open() -> Result<Self, String> {
+            let device = cpal::default_host()
+                .default_output_device()
+                .ok_or("no output device")?;
+            let supported = device.default_output_config().map_err(|e| e.to_string())?;
+            let config: cpal::StreamConfig = supported.config();
+            let queue = Arc::new(Mutex::new(VecDeque::new()));
+
+            let stream = match supported.sample_format() {
+                cpal::SampleFormat::F32 => build_stream::<f32>(&device, &config, queue.clone()),
+                cpal::SampleFormat::I16 => build_stream::<i16>(&device, &config, queue.clone()),
+                cpal::SampleFormat::U16 => build_stream::<u16>(&device, &config, queue.clone()),
+                format => return Err(format!("unsupported sample format {}", format)),
+            }
+            .map_err(|e| e.to_string())?;
+            stream.play().map_err(|e| e.to_string())?;
+
+            Ok(DeviceAudio {
+                queue,
+                sample_rate: config.sample_rate.0,
+                _stream: stream,
+            })
+        }
+    }
+
+    impl AudioBackend for DeviceAudio {
+        fn sample_rate(&self) -> u32 {
+            self.sample_rate
+        }
+
+        fn queue(&mut self, samples: &[(f32, f32)]) {
+            self.queue.lock().unwrap().extend(samples);
+        }
+
+        fn queued(&self) -> usize {
+            self.queue.lock().unwrap().len()
+        }
+    }
+}
+
+/// Linear interpolation between the input samples
+struct Resampler {
+    /// Position of the next output sample, 0 being the last input sample of
+    /// the previous call
+    position: f64,
+    last: (f32, f32),
+}
+
+impl Resampler {
+    /// `ratio` output samples per input sample
+    fn process(&mut self, input: &[(f32, f32)], ratio: f64, output: &mut Vec<(f32, f32)>) {
+        let Some(&newest) = input.last() else {
+            return;
+        };
+        let point = |i: usize| if i == 0 { self.last } else { input[i - 1] };
+        let step = 1.0 / ratio;
+        let end = input.len() as f64;
+        while self.position < end {
+            let i = self.position as usize;
+            let t = (self.position - i as f64) as f32;
+            let (a, b) = (point(i), point(i + 1));
+            output.push((a.0 + (b.0 - a.0) * t, a.1 + (b.1 - a.1) * t));
+            self.position += step;
+        }
+        self.position -= end;
+        self.last = newest;
+    }
+}
+
+/// Plays the console samples on a backend, with a volume and mute
+pub struct AudioOutput {
+    backend: Box<dyn AudioBackend>,
+    resampler: Resampler,
+    input_rate: u32,
+    /// 0 to 1
+    volume: f32,
+    muted: bool,
+    buffer: Vec<(f32, f32)>,
+}
+
+impl AudioOutput {
+    pub fn new(backend: Box<dyn AudioBackend>, input_rate: u32) -> Self {
+        AudioOutput {
+            backend,
+            resampler: Resampler {
+                position: 0.0,
+                last: (0.0, 0.0),
+            },
+            input_rate,
+            volume: 1.0,
+            muted: false,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// The system audio device, or nothing if there is none or the `audio`
+    /// feature is off
+    pub fn open(input_rate: u32) -> Self {
+        #[cfg(feature = "audio")]
+        match device::DeviceAudio::open() {
+            Ok(device) => {
+                info!("Audio output at {}Hz", device.sample_rate());
+                return AudioOutput::new(Box::new(device), input_rate);
+            }
+            Err(e) => warn!("No audio output: {}", e),
+        }
+        AudioOutput::new(Box::new(NullAudio), input_rate)
+    }
+
+    /// Output samples per input sample: the rate conversion, corrected by
+    /// up to `MAX_RATE_DELTA` to bring the queue back to the target latency
+    fn ratio(&self) -> f64 {
+        let target = (self.backend.sample_rate() as usize * TARGET_LATENCY_MS / 1000) as f64;
+        let fill = (self.backend.queued() as f64 - target) / target;
+        let correction = 1.0 - MAX_RATE_DELTA * fill.clamp(-1.0, 1.0);
+        self.backend.sample_rate() as f64 / self.input_rate as f64 * correction
+    }
+
+    pub fn play(&mut self, samples: &[(i16, i16)]) {
+        let gain = if self.muted { 0.0 } else { self.volume } / i16::MAX as f32;
+        let input: Vec<(f32, f32)> = samples
+            .iter()
+            .map(|&(left, right)| (left as f32 * gain, right as f32 * gain))
+            .collect();
+
+        let ratio = self.ratio();
+        self.buffer.clear();
+        self.resampler.process(&input, ratio, &mut self.buffer);
+
+        // too far behind, after a pause: catch up instead of staying late
+        let target = self.backend.sample_rate() as usize * TARGET_LATENCY_MS / 1000;
+        if self.backend.queued() > target * 3 {
+            return;
+        }
+        self.backend.queue(&self.buffer);
+    }
+
+    /// Samples per second the console outputs, following the changes of its
+    /// sample rate
+    pub fn set_input_rate(&mut self, input_rate: u32) {
+        if input_rate != self.input_rate {
+            info!("Audio input at {}Hz", input_rate);
+            self.input_rate = input_rate;
+        }
+    }
+
+    pub fn volume(&self) -> f32 {
+        self.volume
+    }
+
+    pub fn set_volume(&mut self, volume: f32) {
+        self.volume = volume.clamp(0.0, 1.0);
+        info!("Volume: {:.0}%", self.volume * 100.0);
+    }
+
+    pub fn toggle_mute(&mut self) {
+        self.muted = !self.muted;
+        info!("Audio muted: {}", self.muted);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::{AudioBackend, AudioOutput, Resampler};
+
+    #[test]
+    fn resamples_by_ratio() {
+        let mut resampler = Resampler {
+            position: 0.0,
+            last: (0.0, 0.0),
+        };
+        let input: Vec<(f32, f32)> = (1..=100).map(|i| (i as f32, -i as f32)).collect();
+        let mut output = Vec::new();
+        resampler.process(&input[..50], 2.0, &mut output);
+        resampler.process(&input[50..], 2.0, &mut output);
+
+        assert_eq!(output.len(), 200);
+        // halfway between samples, across both calls
+        assert_eq!(output[99], (49.5, -49.5));
+        assert_eq!(output[100], (50.0, -50.0));
+        assert_eq!(output[101], (50.5, -50.5));
+    }
+
+    type Played = Rc<RefCell<Vec<(f32, f32)>>>;
+
+    /// Records what is queued, with a fixed fill level
+    struct TestBackend {
+        queued: usize,
+        played: Played,
+    }
+
+    impl AudioBackend for TestBackend {
+        fn sample_rate(&self) -> u32 {
+            44100
+        }
+
+        fn queue(&mut self, samples: &[(f32, f32)]) {
+            self.played.borrow_mut().extend(samples);
+        }
+
+        fn queued(&self) -> usize {
+            self.queued
+        }
+    }
+
+    fn output(queued: usize) -> (AudioOutput, Played) {
+        let played = Rc::new(RefCell::new(Vec::new()));
+        let backend = TestBackend {
+            queued,
+            played: played.clone(),
+        };
+        (AudioOutput::new(Box::new(backend), 48000), played)
+    }
+
+    #[test]
+    fn rate_follows_the_device_buffer() {
+        let target = 44100 * 60 / 1000;
+        let nominal = 44100.0 / 48000.0;
+        assert!((output(target).0.ratio() - nominal).abs() < 1e-9);
+        // buffer emptying: more samples, filling: fewer, by 0.5% at most
+        assert!((output(0).0.ratio() / nominal - 1.005).abs() < 1e-9);
+        assert!((output(target * 2).0.ratio() / nominal - 0.995).abs() < 1e-9);
+        assert!((output(target * 10).0.ratio() / nominal - 0.995).abs() < 1e-9);
+    }
+
+    #[test]
+    fn rate_follows_the_input_rate() {
+        let (mut audio, _) = output(44100 * 60 / 1000);
+        audio.set_input_rate(96000);
+        assert!((audio.ratio() - 44100.0 / 96000.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn volume_and_mute() {
+        let (mut audio, played) = output(44100 * 60 / 1000);
+        audio.set_volume(0.5);
+        audio.play(&[(i16::MAX, i16::MIN + 1); 480]);
+        let samples = played.borrow().clone();
+        assert!((441..=442).contains(&samples.len()));
+        assert!((samples[10].0 - 0.5).abs() < 1e-6);
+        assert!((samples[10].1 + 0.5).abs() < 1e-6);
+
+        audio.toggle_mute();
+        audio.play(&[(i16::MAX, i16::MAX); 480]);
+        assert!(played.borrow()[samples.len() + 10..]
+            .iter()
+            .all(|&sample| sample == (0.0, 0.0)));
+    }
+}
@@ -0,0 +1,215 @@
+//! Keyboard keys of the joypad buttons
+
+use std::{fs, path::Path};
+
+use minifb::Key;
+
+use crate::gameboy::Button;
+
+/// Every key, to find them by name
+#[rustfmt::skip]
+const KEYS: [Key; 106] = [
+    Key::Key0, Key::Key1, Key::Key2, Key::Key3, Key::Key4, Key::Key5, Key::Key6, Key::Key7,
+    Key::Key8, Key::Key9, Key::A, Key::B, Key::C, Key::D, Key::E, Key::F, Key::G, Key::H, Key::I,
+    Key::J, Key::K, Key::L, Key::M, Key::N, Key::O, Key::P, Key::Q, Key::R, Key::S, Key::T,
+    Key::U, Key::V, Key::W, Key::X, Key::Y, Key::Z, Key::F1, Key::F2, Key::F3, Key::F4, Key::F5,
+    Key::F6, Key::F7, Key::F8, Key::F9, Key::F10, Key::F11, Key::F12, Key::F13, Key::F14,
+    Key::F15, Key::Down, Key::Left, Key::Right, Key::Up, Key::Apostrophe, Key::Backquote,
+    Key::Backslash, Key::Comma, Key::Equal, Key::LeftBracket, Key::Minus, Key::Period,
+    Key::RightBracket, Key::Semicolon, Key::Slash, Key::Backspace, Key::Delete, Key::End,
+    Key::Enter, Key::Escape, Key::Home, Key::Insert, Key::Menu, Key::PageDown, Key::PageUp,
+    Key::Pause, Key::Space, Key::Tab, Key::NumLock, Key::CapsLock, Key::ScrollLock,
+    Key::LeftShift, Key::RightShift, Key::LeftCtrl, Key::RightCtrl, Key::NumPad0, Key::NumPad1,
+    Key::NumPad2, Key::NumPad3, Key::NumPad4, Key::NumPad5, Key::NumPad6, Key::NumPad7,
+    Key::NumPad8, Key::NumPad9, Key::NumPadDot, Key::NumPadSlash, Key::NumPadAsterisk,
+    Key::NumPadMinus, Key::NumPadPlus, Key::NumPadEnter, Key::LeftAlt, Key::RightAlt,
+    Key::LeftSuper, Key::RightSuper,
+];
+
+/// Case insensitive name of a key, as in `Debug`: `Z`, `Enter`, `Key1`,
+/// `NumPad8`...
+fn parse_key(name: &str) -> Option<Key> {
+    KEYS.into_iter()
+        .find(|key| format!("{:?}", key).eq_ignore_ascii_case(name))
+}
+
+/// Keys pressing each button, several keys can press the same one
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct KeyBindings {
+    /// In the order of `Button::ALL`
+    keys: [Vec<Key>; 8],
+}
+
+impl Default for KeyBindings {
+    /// Enter/Backspace for Start/Select, Z/X for A/B and the arrows
+    fn default() -> Self {
+        KeyBindings {
+            keys: [
+                vec![Key::Z],
+                vec![Key::X],
+                vec![Key::Backspace],
+                vec![Key::Enter],
+                vec![Key::Right],
+                vec![Key::Left],
+                vec![Key::Up],
+                vec![Key::Down],
+            ],
+        }
+    }
+}
+
+impl KeyBindings {
+    /// Reads a bindings file: a button then its keys on each line, `#`
+    /// starting a comment. Lines after a `[title]` header only apply to the
+    /// game with that title, replacing the keys of the buttons they bind.
+    ///
+    /// ```text
+    /// a = Z, Space
+    /// start = Enter
+    ///
+    /// [TETRIS]
+    /// a = Up
+    /// ```
+    pub fn load(path: &Path, title: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Self::parse(&text, title).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    fn parse(text: &str, title: &str) -> Result<Self, String> {
+        let mut bindings = KeyBindings::default();
+        let mut overrides = Vec::new();
+        // None outside of a game section, and whether it is this game
+        let mut section: Option<bool> = None;
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(game) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = Some(game.trim().eq_ignore_ascii_case(title));
+                continue;
+            }
+
+            let error = |message: String| format!("line {}: {}", number + 1, message);
+            let (button, keys) = line
+                .split_once('=')
+                .ok_or_else(|| error("expected <button> = <keys>".to_string()))?;
+            let button = Button::parse(button.trim())
+                .ok_or_else(|| error(format!("unknown button {}", button.trim())))?;
+            let keys: Vec<Key> = keys
+                .split(',')
+                .map(str::trim)
+                .map(|key| parse_key(key).ok_or_else(|| error(format!("unknown key {}", key))))
+                .collect::<Result<_, _>>()?;
+
+            match section {
+                None => bindings.bind(button, keys),
+                Some(true) => overrides.push((button, keys)),
+                Some(false) => (),
+            }
+        }
+
+        for (button, keys) in overrides {
+            bindings.bind(button, keys);
+        }
+        Ok(bindings)
+    }
+
+    /// Replaces the keys pressing `button`
+    pub fn bind(&mut self, button: Button, keys: Vec<Key>) {
+        self.keys[index(button)] = keys;
+    }
+
+    pub fn keys(&self, button: Button) -> &[Key] {
+        &self.keys[index(button)]
+    }
+
+    /// Buttons pressed by the keys held down
+    pub fn pressed(&self, keys: &[Key]) -> Vec<Button> {
+        Button::ALL
+            .into_iter()
+            .filter(|&button| self.keys(button).iter().any(|key| keys.contains(key)))
+            .collect()
+    }
+
+    /// Keys bound to several buttons or used by the window, described
+    pub fn conflicts(&self) -> Vec<String> {
+        let mut conflicts = Vec::new();
+        for key in KEYS {
+            let buttons: Vec<Button> = Button::ALL
+                .into_iter()
+                .filter(|&button| self.keys(button).contains(&key))
+                .collect();
+            if buttons.len() > 1 {
+                conflicts.push(format!("{:?} is bound to {:?}", key, buttons));
+            }
+            if !buttons.is_empty() && super::hotkeys().any(|hotkey| hotkey == key) {
+                conflicts.push(format!(
+                    "{:?} is bound to {:?} and a hotkey",
+                    key, buttons[0]
+                ));
+            }
+        }
+        conflicts
+    }
+}
+
+fn index(button: Button) -> usize {
+    Button::ALL.iter().position(|&b| b == button).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use minifb::Key;
+
+    use super::KeyBindings;
+    use crate::gameboy::Button;
+
+    const FILE: &str = "
+        # two keys for A
+        a = Z, space
+        start = Enter
+
+        [TETRIS]
+        a = J
+        [OTHER GAME]
+        b = K
+    ";
+
+    #[test]
+    fn applies_the_game_overrides() {
+        let bindings = KeyBindings::parse(FILE, "OTHER").unwrap();
+        assert_eq!(bindings.keys(Button::A), [Key::Z, Key::Space]);
+        assert_eq!(bindings.keys(Button::B), [Key::X]);
+        assert_eq!(
+            bindings.pressed(&[Key::Space, Key::Up]),
+            [Button::A, Button::Up]
+        );
+
+        let tetris = KeyBindings::parse(FILE, "Tetris").unwrap();
+        assert_eq!(tetris.keys(Button::A), [Key::J]);
+        assert_eq!(tetris.keys(Button::Start), [Key::Enter]);
+    }
+
+    #[test]
+    fn reports_errors_and_conflicts() {
+        assert!(KeyBindings::parse("jump = Z", "").is_err());
+        assert_eq!(
+            KeyBindings::parse("a = Z\nb = Shift", "").unwrap_err(),
+            "line 2: unknown key Shift"
+        );
+
+        assert!(KeyBindings::default().conflicts().is_empty());
+        let bindings = KeyBindings::parse("a = Z\nb = Z, P", "").unwrap();
+        assert_eq!(
+            bindings.conflicts(),
+            ["P is bound to B and a hotkey", "Z is bound to [A, B]"]
+        );
+        // a key of the VRAM window
+        assert_eq!(
+            KeyBindings::parse("select = C", "").unwrap().conflicts(),
+            ["C is bound to Select and a hotkey"]
+        );
+    }
+}
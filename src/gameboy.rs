@@ -1,23 +1,49 @@
-use std::{path, thread, time};
+use std::{io, path, thread, time};
 
+mod audio;
 mod cartridge;
 mod controls;
 mod cpu;
+mod dma;
+mod gbs;
 mod graphics;
+mod hdma;
 mod interrupts;
+mod linked;
 mod memory;
 mod memory_bus;
+mod recording;
 mod registers;
+mod serial;
+mod speed;
 mod timer;
 
+use audio::Apu;
+pub use audio::{AudioSink, DEFAULT_SAMPLE_RATE};
 use cartridge::Cartridge;
 use controls::Joypad;
+pub use controls::{Button, Command, InputSource};
+use dma::OamDma;
+pub use gbs::{Gbs, GbsPlayer};
 use graphics::Display;
-use log::{debug, info, trace};
+pub use graphics::{
+    DebugLayer, DebugLayers, DmgPalettes, Frame, FrameSink, Image, PostProcessing, Tile,
+    TilePalette, VramView, FILTER_PRESETS,
+};
+use hdma::Hdma;
+pub use linked::LinkedGameBoys;
+use log::{debug, info, trace, warn};
 use memory::Memory;
 use memory_bus::MemoryAccessor;
+use recording::Recorder;
+pub use recording::WavWriter;
 use registers::operations::Operations;
 use registers::Registers;
+use serial::Serial;
+pub use serial::{
+    CaptureDevice, LinkAddress, LinkCable, PrintedPage, Printer, SerialDevice, VirtualCable,
+};
+use speed::Speed;
 use timer::Timer;
 
 fn u16_to_u8s(input: u16) -> (u8, u8) {
@@ -37,9 +63,31 @@ pub struct GameBoy {
     pub registers: Registers,
     memory: Memory,
     timer: Timer,
+    dma: OamDma,
+    serial: Serial,
+    apu: Apu,
+    hdma: Hdma,
+    speed: Speed,
 
     cpu_cycles: u32,
+    /// CPU cycles of the current step already run, see `m_cycle`
+    cycles_run: u32,
     halt: bool,
+    /// In STOP mode, with the joypad lines last seen
+    stopped: Option<u8>,
+    /// Emulate the OAM corruption bug, off by default
+    oam_bug: bool,
+    /// Cycles run since power on
+    total_cycles: u64,
+    /// A frame was completed since `run_frame` started
+    frame_completed: bool,
+    frame_sink: Option<Box<dyn FrameSink>>,
+    input: Option<Box<dyn InputSource>>,
+    recorder: Option<Recorder>,
+    audio_sink: Option<Box<dyn AudioSink>>,
+    /// Samples output by the APU, waiting for `take_samples` when there is
+    /// no audio sink
+    samples: Vec<(i16, i16)>,
 
     // lcd_prev_state: bool,
     /// Interrupt Master Enable
@@ -49,22 +97,221 @@ pub struct GameBoy {
 }
 
 impl GameBoy {
-    pub fn step(&mut self) {
-        if self.interrupt_step() {
+    /// Runs a single instruction (or interrupt dispatch) and returns the
+    /// number of cycles it took
+    pub fn step(&mut self) -> u32 {
+        if self.stopped.is_some() {
+            return self.stopped_step();
+        }
+        let mut ticks = if self.interrupt_step() {
             self.cpu_cycles += 20; // todo 16 or 12?
-            return;
-            // todo should an interrupt still run gpu?
+            20
+        } else {
+            self.cpu_step()
+        };
+        // the internal cycles at the end of the instruction
+        self.components_step(ticks.saturating_sub(self.cycles_run));
+        self.cycles_run = 0;
+
+        // the CPU is stalled while VRAM DMA copies
+        let stall = self.hdma_step();
+        if stall > 0 {
+            self.components_step(stall);
+            ticks += stall;
+        }
+
+        self.cpu_cycles = 0;
+        self.total_cycles += ticks as u64;
+        ticks
+    }
+
+    /// Runs until the next frame is completed, about 70224 cycles (twice as
+    /// many in double speed), and returns it
+    pub fn run_frame(&mut self) -> &Frame {
+        self.frame_completed = false;
+        while !self.frame_completed {
+            self.step();
         }
+        self.display.frame()
+    }
+
+    /// Runs at least `cycles` cycles, returns how many were actually run
+    pub fn run_cycles(&mut self, cycles: u64) -> u64 {
+        let start = self.total_cycles;
+        while self.total_cycles - start < cycles {
+            self.step();
+        }
+        self.total_cycles - start
+    }
 
-        let ticks = self.cpu_step();
+    /// Runs instructions until `predicate` is true, checking it before each
+    /// one. Returns the cycles run.
+    pub fn run_until(&mut self, mut predicate: impl FnMut(&GameBoy) -> bool) -> u64 {
+        let start = self.total_cycles;
+        while !predicate(self) {
+            self.step();
+        }
+        self.total_cycles - start
+    }
+
+    /// The last completed frame
+    pub fn frame(&self) -> &Frame {
+        self.display.frame()
+    }
 
+    /// Frames completed since power on
+    pub fn frame_count(&self) -> u64 {
+        self.display.frame().number
+    }
+
+    /// Cycles run since power on
+    pub fn cycles(&self) -> u64 {
+        self.total_cycles
+    }
+
+    /// CGB double speed mode, where the CPU runs twice as many cycles per
+    /// frame
+    pub fn is_double_speed(&self) -> bool {
+        self.speed.is_double()
+    }
+
+    /// Runs one M-cycle of the current instruction: everything else advances
+    /// first, a memory access lands at the end of the cycle. Internal cycles
+    /// are run here when memory accesses follow them, the others at the end
+    /// of the step.
+    fn m_cycle(&mut self) {
+        self.components_step(4);
+        self.cycles_run += 4;
+    }
+
+    /// Read by the CPU, taking an M-cycle
+    fn read(&mut self, location: usize) -> u8 {
+        self.m_cycle();
+        self.memory_read(location)
+    }
+
+    /// Write by the CPU, taking an M-cycle
+    fn write(&mut self, location: usize, value: u8) {
+        self.m_cycle();
+        self.memory_write(location, value);
+    }
+
+    /// Advances everything but the CPU by `ticks` CPU cycles
+    fn components_step(&mut self, ticks: u32) {
         self.timer_step(ticks);
+        self.dma_step(ticks);
 
-        let (gpu_interrupts, keys) = self.display.gpu_step(self.cpu_cycles);
-        self.interrupt_flag |= gpu_interrupts;
-        self.joypad.key_pressed(keys);
+        // the PPU keeps its clock in double speed mode
+        let dots = if self.speed.is_double() {
+            ticks / 2
+        } else {
+            ticks
+        };
+        self.interrupt_flag |= self.display.gpu_step(dots);
+        self.apu.step(dots);
+        if self.display.take_frame_completed() {
+            self.end_frame();
+        }
+    }
 
-        self.cpu_cycles = 0;
+    /// Records the frame, sends it to the sink and polls the input
+    fn end_frame(&mut self) {
+        self.frame_completed = true;
+        let samples = self.apu.take_samples();
+        if let Some(recorder) = self.recorder.as_mut() {
+            if let Err(e) = recorder.write_frame(self.display.frame(), &samples) {
+                warn!("Recording stopped: {}", e);
+                self.recorder = None;
+            }
+        }
+        if let Some(sink) = self.audio_sink.as_mut() {
+            sink.play(&samples);
+        } else {
+            self.samples.extend(samples);
+            // nobody is pulling them, keep the last second
+            let max = self.apu.sample_rate() as usize;
+            if self.samples.len() > max {
+                self.samples.drain(..self.samples.len() - max);
+            }
+        }
+        if let Some(sink) = self.frame_sink.as_mut() {
+            sink.present(self.display.frame());
+        }
+        if let Some(input) = self.input.as_mut() {
+            self.joypad.set_pressed(input.pressed_buttons());
+            for command in input.commands() {
+                match command {
+                    Command::NextPalette => self.display.next_palette_preset(),
+                    Command::ToggleLayer(layer) => {
+                        let mut layers = self.display.debug_layers();
+                        layers.toggle(layer);
+                        info!("{:?} shown: {}", layer, layers.is_shown(layer));
+                        self.display.set_debug_layers(layers);
+                    }
+                    Command::ToggleChannel(channel) => {
+                        let muted = !self.apu.is_channel_muted(channel);
+                        info!("Audio channel {} muted: {}", channel + 1, muted);
+                        self.apu.set_channel_muted(channel, muted);
+                    }
+                    Command::ToggleHighlight => {
+                        let mut layers = self.display.debug_layers();
+                        layers.highlight = !layers.highlight;
+                        self.display.set_debug_layers(layers);
+                    }
+                    Command::ToggleRecording(path) => {
+                        let toggled = if self.recorder.is_some() {
+                            self.stop_recording()
+                        } else {
+                            self.start_recording(&path)
+                        };
+                        if let Err(e) = toggled {
+                            warn!("Could not record to {:?}: {}", path, e);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// STOP mode lasts until a joypad line goes low. Only the LCD keeps
+    /// going, showing a blank screen, so that frames and input keep coming.
+    fn stopped_step(&mut self) -> u32 {
+        let lines = self.joypad.get(controls::REGISTER_LOCATION) & 0x0f;
+        if let Some(previous) = self.stopped.replace(lines) {
+            if previous & !lines > 0 {
+                debug!("Leaving STOP");
+                self.stopped = None;
+                self.display.set_stopped(false);
+            }
+        }
+
+        let dots = if self.speed.is_double() { 2 } else { 4 };
+        self.display.gpu_step(dots);
+        if self.display.take_frame_completed() {
+            self.end_frame();
+        }
+        self.total_cycles += 4;
+        4
+    }
+
+    /// Runs the pending VRAM DMA transfers and returns how long the CPU is
+    /// stalled for
+    fn hdma_step(&mut self) -> u32 {
+        let hblank_started = self.display.take_hblank_started();
+        let bytes = self.hdma.transfer(hblank_started);
+        for &(source, destination) in &bytes {
+            let value = self.bus_read(source);
+            self.display.write(destination, value);
+        }
+
+        // 8 M-cycles per block, at the PPU clock
+        let blocks = (bytes.len() / hdma::BLOCK_LENGTH) as u32;
+        let ticks = blocks * 32;
+        if self.speed.is_double() {
+            ticks * 2
+        } else {
+            ticks
+        }
     }
 
     fn cpu_step(&mut self) -> u32 {
@@ -77,10 +324,44 @@ impl GameBoy {
         self.cpu_cycles - current_cpu_cycles
     }
 
+    /// Steps the timer and the components clocked by its system counter
     fn timer_step(&mut self, ticks: u32) {
-        if self.timer.step_timer(ticks) {
-            println!("enabling timer interrupt");
-            self.interrupt_flag |= interrupts::TIMER;
+        for _ in 0..ticks / 4 {
+            if self.timer.step() {
+                self.interrupt_flag |= interrupts::TIMER;
+            }
+
+            let edges = self.timer.take_falling_edges();
+            if self.serial.step(edges) {
+                self.interrupt_flag |= interrupts::SERIAL;
+            }
+            let apu_clock_bit = if self.speed.is_double() {
+                timer::APU_CLOCK_BIT << 1
+            } else {
+                timer::APU_CLOCK_BIT
+            };
+            if edges & apu_clock_bit > 0 {
+                self.apu.frame_sequencer_step();
+            }
+        }
+    }
+
+    fn dma_step(&mut self, ticks: u32) {
+        for _ in 0..ticks / 4 {
+            if let Some((source, index)) = self.dma.step() {
+                let value = self.dma_source_read(source);
+                self.dma.transferred(value);
+                self.display.oam[index] = value;
+            }
+        }
+    }
+
+    /// Sources above 0xDFFF read from the echo of the work RAM
+    fn dma_source_read(&self, location: usize) -> u8 {
+        if location >= 0xe000 {
+            self.bus_read(location - 0x2000)
+        } else {
+            self.bus_read(location)
         }
     }
 
@@ -101,6 +382,8 @@ impl GameBoy {
         }
 
         self.ime = false;
+        // 2 internal cycles, the push and setting PC
+        self.m_cycle();
         self.push_stack(self.registers.pc);
 
         if interrupts & interrupts::VBLANK > 0 {
@@ -111,35 +394,34 @@ impl GameBoy {
         }
         if interrupts & interrupts::STAT > 0 {
             self.interrupt_flag &= !interrupts::STAT;
-            debug!("VBlank Interrupt Handler from: {:#x}", self.registers.pc);
+            debug!("LCD STAT Interrupt Handler from: {:#x}", self.registers.pc);
             self.registers.set_pc(0x48);
             return true;
         }
 
         if interrupts & interrupts::TIMER > 0 {
             self.interrupt_flag &= !interrupts::TIMER;
-            println!("Timer Interrupt Handler from: {:#x}", self.registers.pc);
+            debug!("Timer Interrupt Handler from: {:#x}", self.registers.pc);
             self.registers.set_pc(0x50);
             return true;
         }
 
         if interrupts & interrupts::SERIAL > 0 {
             self.interrupt_flag &= !interrupts::SERIAL;
-            println!("Serial Interrupt Handler from: {:#x}", self.registers.pc);
+            debug!("Serial Interrupt Handler from: {:#x}", self.registers.pc);
             self.registers.set_pc(0x58);
             return true;
         }
 
         println!("Interrupt enable: {:#8b}", self.memory.interrupt_enable);
         println!("Interrupt flag: {:#8b}", self.interrupt_flag);
-        self.memory.dump_tile_data();
         panic!("found interrupt")
     }
 
     fn run_cpu_instruction(&mut self) {
         let location = self.registers.step_pc();
 
-        let op = self.memory_read(location);
+        let op = self.read(location);
         debug!("operator: {:#x} ({:#x})", op, location);
         match op {
             0xcb => {
@@ -155,27 +437,40 @@ impl GameBoy {
         }
     }
 
-    pub fn get_ffxx(&self, steps: usize) -> u8 {
+    pub fn get_ffxx(&mut self, steps: usize) -> u8 {
         let location = 0xff00 + steps;
-        self.memory_read(location)
+        self.read(location)
     }
 
     pub fn write_ffxx(&mut self, steps: u8, value: u8) {
         let location = 0xff00 + steps as usize;
-        self.memory_write(location, value);
+        self.write(location, value);
     }
 
     pub fn memory_read(&self, location: usize) -> u8 {
+        if let Some(value) = self.dma.conflict(location) {
+            trace!("Read during OAM DMA: {:#x}", location);
+            return value;
+        }
+        self.bus_read(location)
+    }
+
+    fn bus_read(&self, location: usize) -> u8 {
         match location {
             0x0000..=0x7FFF => self.cartridge.get(location),
 
             0xA000..=0xBFFF => self.cartridge.get(location),
 
-            0xff40..=0xff4b => self.display.get(location),
+            dma::REGISTER_LOCATION => self.dma.get(location),
+            speed::REGISTER_LOCATION => self.speed.get(location),
+            0xff51..=0xff55 => self.hdma.get(location),
+            0xff40..=0xff4b | 0xff4f | 0xff68..=0xff6c => self.display.get(location),
             0x8000..=0x97FF => self.display.get(location),
             0x9800..=0x9FFF => self.display.get(location),
             0xFE00..=0xFE9F => self.display.get(location),
 
+            serial::DATA_LOCATION | serial::CONTROL_LOCATION => self.serial.get(location),
+            0xff10..=0xff3f | 0xff76 | 0xff77 => self.apu.get(location),
             0xff04..=0xff07 => self.timer.get(location),
             0xff0f => self.interrupt_flag,
 
@@ -184,63 +479,72 @@ impl GameBoy {
             _ => self.memory.get(location),
         }
     }
+
     pub fn memory_write(&mut self, location: usize, value: u8) {
+        if self.dma.conflict(location).is_some() {
+            trace!("Dropping write during OAM DMA: {:#x}", location);
+            return;
+        }
+
         match location {
             0x0000..=0x7FFF => self.cartridge.write(location, value),
 
             0xA000..=0xBFFF => self.cartridge.write(location, value),
 
-            0xff46 => {
-                let location = (value as u16) << 8;
-                debug!(
-                    "Triggering DMA transfter to OAM! {:#x} --> {:#x}",
-                    value, location
-                );
-                for i in 0..0xA0 {
-                    self.display.oam[i] = self.memory_read(location as usize + i);
-                }
-            }
+            dma::REGISTER_LOCATION => self.dma.write(location, value),
+            speed::REGISTER_LOCATION => self.speed.write(location, value),
+            0xff51..=0xff55 => self.hdma.write(location, value),
             0xfe00..=0xfe9f => self.display.write(location, value),
-            0xff40..=0xff4b => self.display.write(location, value),
+            0xff40..=0xff4b | 0xff4f | 0xff68..=0xff6c => self.display.write(location, value),
             0x8000..=0x97FF => self.display.write(location, value),
             0x9800..=0x9FFF => self.display.write(location, value),
 
+            serial::DATA_LOCATION | serial::CONTROL_LOCATION => self.serial.write(location, value),
+            0xff10..=0xff3f | 0xff76 | 0xff77 => self.apu.write(location, value),
             0xff04..=0xff07 => self.timer.write(location, value),
             0xff0f => self.interrupt_flag = value,
 
-            controls::REGISTER_LOCATION => self.joypad.write(location, value),
+            controls::REGISTER_LOCATION => {
+                self.joypad.write(location, value);
+                if let Some(command) = self.joypad.take_sgb_command() {
+                    self.display.sgb_command(&command);
+                }
+            }
 
             _ => self.memory.write(location, value),
         }
     }
 
     fn pop_stack(&mut self) -> u16 {
-        let ls = self.memory_read(self.registers.sp as usize);
+        let ls = self.read(self.registers.sp as usize);
         self.registers.sp += 1;
-        let hs = self.memory_read(self.registers.sp as usize);
+        let hs = self.read(self.registers.sp as usize);
         self.registers.sp += 1;
         u8s_to_u16(ls, hs)
     }
 
+    /// Takes an internal cycle before the writes, as every instruction
+    /// pushing does
     fn push_stack(&mut self, value: u16) {
+        self.m_cycle();
         let (hs, ls) = u16_to_u8s(value);
         self.registers.sp -= 1;
-        self.memory_write(self.registers.sp as usize, hs);
+        self.write(self.registers.sp as usize, hs);
         self.registers.sp -= 1;
-        self.memory_write(self.registers.sp as usize, ls);
+        self.write(self.registers.sp as usize, ls);
     }
 
     fn get_u16(&mut self) -> u16 {
         let location = self.registers.step_pc();
-        let v1 = self.memory_read(location) as u16;
+        let v1 = self.read(location) as u16;
         let location = self.registers.step_pc();
-        let v2 = self.memory_read(location) as u16;
+        let v2 = self.read(location) as u16;
         v2 << 8 | v1
     }
 
     fn get_u8(&mut self) -> u8 {
         let location = self.registers.step_pc();
-        self.memory_read(location)
+        self.read(location)
     }
 
     fn run_instruction(&mut self, op: u8) {
@@ -400,16 +704,16 @@ impl GameBoy {
             // LD NN, A
             0x02 => {
                 trace!("LD (BC), A");
-                self.memory_write(self.registers.get_bc() as usize, self.registers.a);
+                self.write(self.registers.get_bc() as usize, self.registers.a);
             }
             0x12 => {
                 trace!("LD (DE), A");
-                self.memory_write(self.registers.get_de() as usize, self.registers.a);
+                self.write(self.registers.get_de() as usize, self.registers.a);
             }
             0xea => {
                 trace!("LD (nn),A");
                 let target = self.get_u16();
-                self.memory_write(target as usize, self.registers.a);
+                self.write(target as usize, self.registers.a);
             }
 
             // LD (nn), SP
@@ -417,8 +721,8 @@ impl GameBoy {
                 trace!("LD (nn), SP");
                 let loc = self.get_u16() as usize;
                 let (msb, lsb) = u16_to_u8s(self.registers.sp);
-                self.memory_write(loc, lsb);
-                self.memory_write(loc + 1, msb);
+                self.write(loc, lsb);
+                self.write(loc + 1, msb);
             }
 
             // LD SP, HL
@@ -431,7 +735,7 @@ impl GameBoy {
             0xe0 => {
                 let steps = self.get_u8();
                 trace!("LDH (n),A --> {} value: {}", steps, self.registers.a);
-                self.memory_write(0xff00 + steps as usize, self.registers.a);
+                self.write(0xff00 + steps as usize, self.registers.a);
             }
 
             // LDH A,(n)
@@ -448,7 +752,7 @@ impl GameBoy {
                     self.registers.get_hl(),
                     self.registers.a
                 );
-                self.memory_write(self.registers.get_hl() as usize, self.registers.a);
+                self.write(self.registers.get_hl() as usize, self.registers.a);
                 self.registers.set_hl(self.registers.get_hl() + 1)
             }
             // LDD (HL), A
@@ -458,20 +762,20 @@ impl GameBoy {
                     self.registers.get_hl(),
                     self.registers.a
                 );
-                self.memory_write(self.registers.get_hl() as usize, self.registers.a);
+                self.write(self.registers.get_hl() as usize, self.registers.a);
                 self.registers.set_hl(self.registers.get_hl() - 1)
             }
 
             // LDD A, (HL)
             0x3a => {
                 trace!("LDD A, (HL)");
-                self.registers.a = self.memory_read(self.registers.get_hl() as usize);
+                self.registers.a = self.read(self.registers.get_hl() as usize);
                 self.registers.set_hl(self.registers.get_hl() - 1)
             }
             // LDI A, (HL)
             0x2a => {
                 trace!("LDI A, (HL)");
-                self.registers.a = self.memory_read(self.registers.get_hl() as usize);
+                self.registers.a = self.read(self.registers.get_hl() as usize);
                 self.registers.set_hl(self.registers.get_hl() + 1)
             }
 
@@ -526,15 +830,15 @@ impl GameBoy {
             }
             0x0a => {
                 trace!("LD A, (BC)");
-                self.registers.a = self.memory_read(self.registers.get_bc() as usize);
+                self.registers.a = self.read(self.registers.get_bc() as usize);
             }
             0x1a => {
                 trace!("LD A, (DE)");
-                self.registers.a = self.memory_read(self.registers.get_de() as usize);
+                self.registers.a = self.read(self.registers.get_de() as usize);
             }
             0x7e => {
                 trace!("LD A, (HL)");
-                self.registers.a = self.memory_read(self.registers.get_hl() as usize);
+                self.registers.a = self.read(self.registers.get_hl() as usize);
                 debug!(
                     "LD A,(HL): {:#x} hl: {:#x}",
                     self.registers.a,
@@ -575,7 +879,7 @@ impl GameBoy {
             }
             0x46 => {
                 trace!("LD B, (HL)");
-                self.registers.b = self.memory_read(self.registers.get_hl() as usize);
+                self.registers.b = self.read(self.registers.get_hl() as usize);
             }
             0x06 => {
                 let value = self.get_u8();
@@ -611,7 +915,7 @@ impl GameBoy {
             }
             0x4e => {
                 trace!("LD C, (HL)");
-                self.registers.c = self.memory_read(self.registers.get_hl() as usize);
+                self.registers.c = self.read(self.registers.get_hl() as usize);
             }
             0x0e => {
                 let value = self.get_u8();
@@ -647,7 +951,7 @@ impl GameBoy {
             }
             0x56 => {
                 trace!("LD D, (HL)");
-                self.registers.d = self.memory_read(self.registers.get_hl() as usize);
+                self.registers.d = self.read(self.registers.get_hl() as usize);
             }
             0x16 => {
                 let value = self.get_u8();
@@ -683,7 +987,7 @@ impl GameBoy {
             }
             0x5e => {
                 trace!("LD E, (HL)");
-                self.registers.e = self.memory_read(self.registers.get_hl() as usize);
+                self.registers.e = self.read(self.registers.get_hl() as usize);
             }
             0x1e => {
                 let value = self.get_u8();
@@ -719,7 +1023,7 @@ impl GameBoy {
             }
             0x66 => {
                 trace!("LD H, (HL)");
-                self.registers.h = self.memory_read(self.registers.get_hl() as usize);
+                self.registers.h = self.read(self.registers.get_hl() as usize);
             }
             0x26 => {
                 let value = self.get_u8();
@@ -755,7 +1059,7 @@ impl GameBoy {
             0x6D => {}
             0x6E => {
                 trace!("LD L, (HL)");
-                self.registers.l = self.memory_read(self.registers.get_hl() as usize);
+                self.registers.l = self.read(self.registers.get_hl() as usize);
             }
             0x2e => {
                 let value = self.get_u8();
@@ -766,42 +1070,42 @@ impl GameBoy {
             // (HL)
             0x77 => {
                 trace!("LD (HL), A");
-                self.memory_write(self.registers.get_hl() as usize, self.registers.a);
+                self.write(self.registers.get_hl() as usize, self.registers.a);
             }
             0x70 => {
                 trace!("LD (HL), B");
-                self.memory_write(self.registers.get_hl() as usize, self.registers.b);
+                self.write(self.registers.get_hl() as usize, self.registers.b);
             }
             0x71 => {
                 trace!("LD (HL), C");
-                self.memory_write(self.registers.get_hl() as usize, self.registers.c);
+                self.write(self.registers.get_hl() as usize, self.registers.c);
             }
             0x72 => {
                 trace!("LD (HL), D");
-                self.memory_write(self.registers.get_hl() as usize, self.registers.d);
+                self.write(self.registers.get_hl() as usize, self.registers.d);
             }
             0x73 => {
                 trace!("LD (HL), E");
-                self.memory_write(self.registers.get_hl() as usize, self.registers.e);
+                self.write(self.registers.get_hl() as usize, self.registers.e);
             }
             0x74 => {
                 trace!("LD (HL), H");
-                self.memory_write(self.registers.get_hl() as usize, self.registers.h);
+                self.write(self.registers.get_hl() as usize, self.registers.h);
             }
             0x75 => {
                 trace!("LD (HL), L");
-                self.memory_write(self.registers.get_hl() as usize, self.registers.l);
+                self.write(self.registers.get_hl() as usize, self.registers.l);
             }
             0x36 => {
                 trace!("LD (HL), n");
                 let v = self.get_u8();
-                self.memory_write(self.registers.get_hl() as usize, v);
+                self.write(self.registers.get_hl() as usize, v);
             }
 
             0xfa => {
                 trace!("LD A, nn");
                 let source = self.get_u16();
-                self.registers.a = self.memory_read(source as usize);
+                self.registers.a = self.read(source as usize);
             }
 
             // LD A, (C)
@@ -848,7 +1152,7 @@ impl GameBoy {
             }
             0x86 => {
                 trace!("ADD A, (HL)");
-                let v = self.memory_read(self.registers.get_hl() as usize);
+                let v = self.read(self.registers.get_hl() as usize);
                 self.registers.f = self.registers.a.add(v);
             }
             0xc6 => {
@@ -967,7 +1271,7 @@ impl GameBoy {
             }
             0x8e => {
                 trace!("ADC A, (HL)");
-                let v = self.memory_read(self.registers.get_hl() as usize);
+                let v = self.read(self.registers.get_hl() as usize);
                 self.registers.f = self
                     .registers
                     .a
@@ -1013,7 +1317,7 @@ impl GameBoy {
             }
             0x96 => {
                 trace!("SUB (HL)");
-                let v = self.memory_read(self.registers.get_hl() as usize);
+                let v = self.read(self.registers.get_hl() as usize);
                 self.registers.f = self.registers.a.sub(v);
             }
 
@@ -1075,7 +1379,7 @@ impl GameBoy {
             }
             0x9e => {
                 trace!("SBC A, (HL)");
-                let v = self.memory_read(self.registers.get_hl() as usize);
+                let v = self.read(self.registers.get_hl() as usize);
                 self.registers.f = self
                     .registers
                     .a
@@ -1093,41 +1397,49 @@ impl GameBoy {
 
             // INC nn
             0x03 => {
+                self.inc_dec_oam_bug(self.registers.get_bc());
                 self.registers
                     .set_bc(self.registers.get_bc().wrapping_add(1));
             }
             0x13 => {
+                self.inc_dec_oam_bug(self.registers.get_de());
                 self.registers
                     .set_de(self.registers.get_de().wrapping_add(1));
             }
             0x23 => {
                 trace!("INC HL");
+                self.inc_dec_oam_bug(self.registers.get_hl());
                 self.registers
                     .set_hl(self.registers.get_hl().wrapping_add(1));
             }
             0x33 => {
                 trace!("INC SP");
+                self.inc_dec_oam_bug(self.registers.sp);
                 self.registers.sp = self.registers.sp.wrapping_add(1);
             }
 
             // DEC nn
             0x0B => {
                 trace!("DEC BC");
+                self.inc_dec_oam_bug(self.registers.get_bc());
                 self.registers
                     .set_bc(self.registers.get_bc().wrapping_sub(1));
             }
             0x1B => {
                 trace!("DEC DE");
+                self.inc_dec_oam_bug(self.registers.get_de());
                 self.registers
                     .set_de(self.registers.get_de().wrapping_sub(1));
             }
             0x2B => {
                 trace!("DEC HL");
+                self.inc_dec_oam_bug(self.registers.get_hl());
                 self.registers
                     .set_hl(self.registers.get_hl().wrapping_sub(1));
             }
             0x3B => {
                 trace!("DEC SP");
+                self.inc_dec_oam_bug(self.registers.sp);
                 self.registers.sp = self.registers.sp.wrapping_sub(1);
             }
 
@@ -1163,9 +1475,9 @@ impl GameBoy {
             0x34 => {
                 trace!("INC (HL)");
                 let location = self.registers.get_hl() as usize;
-                let mut value = self.memory_read(location);
+                let mut value = self.read(location);
                 value.inc(&mut self.registers.f);
-                self.memory_write(location, value);
+                self.write(location, value);
             }
 
             // DEC
@@ -1201,9 +1513,9 @@ impl GameBoy {
             0x35 => {
                 trace!("DEC (HL)");
                 let location = self.registers.get_hl() as usize;
-                let mut value = self.memory_read(location);
+                let mut value = self.read(location);
                 value.dec(&mut self.registers.f);
-                self.memory_write(location, value);
+                self.write(location, value);
             }
 
             // AND n
@@ -1237,7 +1549,7 @@ impl GameBoy {
             }
             0xa6 => {
                 trace!("AND (HL)");
-                let value = self.memory_read(self.registers.get_hl() as usize);
+                let value = self.read(self.registers.get_hl() as usize);
                 self.registers.f = self.registers.a.and(value);
             }
             0xe6 => {
@@ -1277,7 +1589,7 @@ impl GameBoy {
             }
             0xb6 => {
                 trace!("OR (HL)");
-                let value = self.memory_read(self.registers.get_hl() as usize);
+                let value = self.read(self.registers.get_hl() as usize);
                 self.registers.f = self.registers.a.or(value);
             }
             0xf6 => {
@@ -1317,7 +1629,7 @@ impl GameBoy {
             }
             0xae => {
                 trace!("XOR (HL)");
-                let value = self.memory_read(self.registers.get_hl() as usize);
+                let value = self.read(self.registers.get_hl() as usize);
                 self.registers.f = self.registers.a.xor(value);
             }
 
@@ -1360,7 +1672,7 @@ impl GameBoy {
             0xbe => {
                 trace!("CP (HL)");
                 let mem_loc = self.registers.get_hl() as usize;
-                self.registers.f = self.registers.a.cp(self.memory_read(mem_loc));
+                self.registers.f = self.registers.a.cp(self.read(mem_loc));
             }
 
             0xfe => {
@@ -1447,6 +1759,8 @@ impl GameBoy {
 
             0xc0 => {
                 debug!("RET NZ");
+                // checking the condition takes an internal cycle
+                self.m_cycle();
                 if !self.registers.f.has_flag(cpu::Flag::Z) {
                     let new_loc = self.pop_stack();
                     debug!("Made the jump");
@@ -1456,6 +1770,8 @@ impl GameBoy {
             }
             0xc8 => {
                 debug!("RET Z");
+                // checking the condition takes an internal cycle
+                self.m_cycle();
                 if self.registers.f.has_flag(cpu::Flag::Z) {
                     let new_loc = self.pop_stack();
                     debug!("Made the jump");
@@ -1465,6 +1781,8 @@ impl GameBoy {
             }
             0xd0 => {
                 debug!("RET NC");
+                // checking the condition takes an internal cycle
+                self.m_cycle();
                 if !self.registers.f.has_flag(cpu::Flag::C) {
                     let new_loc = self.pop_stack();
                     debug!("Made the jump");
@@ -1474,6 +1792,8 @@ impl GameBoy {
             }
             0xd8 => {
                 debug!("RET C");
+                // checking the condition takes an internal cycle
+                self.m_cycle();
                 if self.registers.f.has_flag(cpu::Flag::C) {
                     let new_loc = self.pop_stack();
                     debug!("Made the jump");
@@ -1605,6 +1925,17 @@ impl GameBoy {
                 self.registers.f = registers::set_flag(self.registers.f, cpu::Flag::Z, a == 0);
                 self.registers.a = a;
             }
+            0x10 => {
+                // STOP is followed by a padding byte
+                self.registers.step_pc();
+                self.timer.write(0xff04, 0);
+                if !self.speed.switch() {
+                    debug!("STOP");
+                    let lines = self.joypad.get(controls::REGISTER_LOCATION) & 0x0f;
+                    self.stopped = Some(lines);
+                    self.display.set_stopped(true);
+                }
+            }
             0x76 => {
                 self.halt = true;
                 debug!("HALT");
@@ -1662,9 +1993,6 @@ impl GameBoy {
             }
 
             _ => {
-                debug!("Info for debugging");
-                self.memory.dump_tile_data();
-
                 let time = time::Duration::from_secs(5);
                 thread::sleep(time);
                 panic!("missing operator {:#x}", op);
@@ -1682,9 +2010,9 @@ impl GameBoy {
             0x04 => self.registers.f = self.registers.h.rlc(),
             0x05 => self.registers.f = self.registers.l.rlc(),
             0x06 => {
-                let mut v = self.memory_read(self.registers.get_hl() as usize);
+                let mut v = self.read(self.registers.get_hl() as usize);
                 self.registers.f = v.rlc();
-                self.memory_write(self.registers.get_hl() as usize, v);
+                self.write(self.registers.get_hl() as usize, v);
             }
             0x07 => self.registers.f = self.registers.a.rlc(),
 
@@ -1696,9 +2024,9 @@ impl GameBoy {
             0x0c => self.registers.f = self.registers.h.rrc(),
             0x0d => self.registers.f = self.registers.l.rrc(),
             0x0e => {
-                let mut v = self.memory_read(self.registers.get_hl() as usize);
+                let mut v = self.read(self.registers.get_hl() as usize);
                 self.registers.f = v.rrc();
-                self.memory_write(self.registers.get_hl() as usize, v);
+                self.write(self.registers.get_hl() as usize, v);
             }
             0x0f => self.registers.f = self.registers.a.rrc(),
 
@@ -1711,9 +2039,9 @@ impl GameBoy {
             0x1c => self.registers.h.rr(&mut self.registers.f),
             0x1d => self.registers.l.rr(&mut self.registers.f),
             0x1e => {
-                let mut value = self.memory_read(self.registers.get_hl() as usize);
+                let mut value = self.read(self.registers.get_hl() as usize);
                 value.rr(&mut self.registers.f);
-                self.memory_write(self.registers.get_hl() as usize, value);
+                self.write(self.registers.get_hl() as usize, value);
             }
 
             // RL
@@ -1725,9 +2053,9 @@ impl GameBoy {
             0x14 => self.registers.h.rl(&mut self.registers.f),
             0x15 => self.registers.l.rl(&mut self.registers.f),
             0x16 => {
-                let mut value = self.memory_read(self.registers.get_hl() as usize);
+                let mut value = self.read(self.registers.get_hl() as usize);
                 value.rl(&mut self.registers.f);
-                self.memory_write(self.registers.get_hl() as usize, value);
+                self.write(self.registers.get_hl() as usize, value);
             }
 
             // SWAP
@@ -1738,9 +2066,9 @@ impl GameBoy {
             0x34 => self.registers.f = self.registers.h.swap(),
             0x35 => self.registers.f = self.registers.l.swap(),
             0x36 => {
-                let mut value = self.memory_read(self.registers.get_hl() as usize);
+                let mut value = self.read(self.registers.get_hl() as usize);
                 self.registers.f = value.swap();
-                self.memory_write(self.registers.get_hl() as usize, value);
+                self.write(self.registers.get_hl() as usize, value);
             }
             0x37 => self.registers.f = self.registers.a.swap(),
 
@@ -1752,9 +2080,9 @@ impl GameBoy {
             0x24 => self.registers.f = self.registers.h.sla(),
             0x25 => self.registers.f = self.registers.l.sla(),
             0x26 => {
-                let mut value = self.memory_read(self.registers.get_hl() as usize);
+                let mut value = self.read(self.registers.get_hl() as usize);
                 self.registers.f = value.sla();
-                self.memory_write(self.registers.get_hl() as usize, value);
+                self.write(self.registers.get_hl() as usize, value);
             }
             0x27 => self.registers.f = self.registers.a.sla(),
 
@@ -1766,9 +2094,9 @@ impl GameBoy {
             0x2c => self.registers.f = self.registers.h.sra(),
             0x2d => self.registers.f = self.registers.l.sra(),
             0x2e => {
-                let mut value = self.memory_read(self.registers.get_hl() as usize);
+                let mut value = self.read(self.registers.get_hl() as usize);
                 self.registers.f = value.sra();
-                self.memory_write(self.registers.get_hl() as usize, value);
+                self.write(self.registers.get_hl() as usize, value);
             }
             0x2f => self.registers.f = self.registers.a.sra(),
 
@@ -1780,9 +2108,9 @@ impl GameBoy {
             0x3c => self.registers.f = self.registers.h.srl(),
             0x3d => self.registers.f = self.registers.l.srl(),
             0x3e => {
-                let mut value = self.memory_read(self.registers.get_hl() as usize);
+                let mut value = self.read(self.registers.get_hl() as usize);
                 self.registers.f = value.srl();
-                self.memory_write(self.registers.get_hl() as usize, value);
+                self.write(self.registers.get_hl() as usize, value);
             }
             0x3f => self.registers.f = self.registers.a.srl(),
 
@@ -1853,44 +2181,44 @@ impl GameBoy {
             0xbd => self.registers.l.set_bit(7, false),
 
             0x86 => {
-                let mut value = self.memory_read(self.registers.get_hl() as usize);
+                let mut value = self.read(self.registers.get_hl() as usize);
                 value.set_bit(0, false);
-                self.memory_write(self.registers.get_hl() as usize, value);
+                self.write(self.registers.get_hl() as usize, value);
             }
             0x8e => {
-                let mut value = self.memory_read(self.registers.get_hl() as usize);
+                let mut value = self.read(self.registers.get_hl() as usize);
                 value.set_bit(1, false);
-                self.memory_write(self.registers.get_hl() as usize, value);
+                self.write(self.registers.get_hl() as usize, value);
             }
             0x96 => {
-                let mut value = self.memory_read(self.registers.get_hl() as usize);
+                let mut value = self.read(self.registers.get_hl() as usize);
                 value.set_bit(2, false);
-                self.memory_write(self.registers.get_hl() as usize, value);
+                self.write(self.registers.get_hl() as usize, value);
             }
             0x9e => {
-                let mut value = self.memory_read(self.registers.get_hl() as usize);
+                let mut value = self.read(self.registers.get_hl() as usize);
                 value.set_bit(3, false);
-                self.memory_write(self.registers.get_hl() as usize, value);
+                self.write(self.registers.get_hl() as usize, value);
             }
             0xa6 => {
-                let mut value = self.memory_read(self.registers.get_hl() as usize);
+                let mut value = self.read(self.registers.get_hl() as usize);
                 value.set_bit(4, false);
-                self.memory_write(self.registers.get_hl() as usize, value);
+                self.write(self.registers.get_hl() as usize, value);
             }
             0xae => {
-                let mut value = self.memory_read(self.registers.get_hl() as usize);
+                let mut value = self.read(self.registers.get_hl() as usize);
                 value.set_bit(5, false);
-                self.memory_write(self.registers.get_hl() as usize, value);
+                self.write(self.registers.get_hl() as usize, value);
             }
             0xb6 => {
-                let mut value = self.memory_read(self.registers.get_hl() as usize);
+                let mut value = self.read(self.registers.get_hl() as usize);
                 value.set_bit(6, false);
-                self.memory_write(self.registers.get_hl() as usize, value);
+                self.write(self.registers.get_hl() as usize, value);
             }
             0xbe => {
-                let mut value = self.memory_read(self.registers.get_hl() as usize);
+                let mut value = self.read(self.registers.get_hl() as usize);
                 value.set_bit(7, false);
-                self.memory_write(self.registers.get_hl() as usize, value);
+                self.write(self.registers.get_hl() as usize, value);
             }
 
             // SET
@@ -1958,44 +2286,44 @@ impl GameBoy {
             0xfc => self.registers.h.set_bit(7, true),
             0xfd => self.registers.l.set_bit(7, true),
             0xc6 => {
-                let mut value = self.memory_read(self.registers.get_hl() as usize);
+                let mut value = self.read(self.registers.get_hl() as usize);
                 value.set_bit(0, true);
-                self.memory_write(self.registers.get_hl() as usize, value);
+                self.write(self.registers.get_hl() as usize, value);
             }
             0xce => {
-                let mut value = self.memory_read(self.registers.get_hl() as usize);
+                let mut value = self.read(self.registers.get_hl() as usize);
                 value.set_bit(1, true);
-                self.memory_write(self.registers.get_hl() as usize, value);
+                self.write(self.registers.get_hl() as usize, value);
             }
             0xd6 => {
-                let mut value = self.memory_read(self.registers.get_hl() as usize);
+                let mut value = self.read(self.registers.get_hl() as usize);
                 value.set_bit(2, true);
-                self.memory_write(self.registers.get_hl() as usize, value);
+                self.write(self.registers.get_hl() as usize, value);
             }
             0xde => {
-                let mut value = self.memory_read(self.registers.get_hl() as usize);
+                let mut value = self.read(self.registers.get_hl() as usize);
                 value.set_bit(3, true);
-                self.memory_write(self.registers.get_hl() as usize, value);
+                self.write(self.registers.get_hl() as usize, value);
             }
             0xe6 => {
-                let mut value = self.memory_read(self.registers.get_hl() as usize);
+                let mut value = self.read(self.registers.get_hl() as usize);
                 value.set_bit(4, true);
-                self.memory_write(self.registers.get_hl() as usize, value);
+                self.write(self.registers.get_hl() as usize, value);
             }
             0xee => {
-                let mut value = self.memory_read(self.registers.get_hl() as usize);
+                let mut value = self.read(self.registers.get_hl() as usize);
                 value.set_bit(5, true);
-                self.memory_write(self.registers.get_hl() as usize, value);
+                self.write(self.registers.get_hl() as usize, value);
             }
             0xf6 => {
-                let mut value = self.memory_read(self.registers.get_hl() as usize);
+                let mut value = self.read(self.registers.get_hl() as usize);
                 value.set_bit(6, true);
-                self.memory_write(self.registers.get_hl() as usize, value);
+                self.write(self.registers.get_hl() as usize, value);
             }
             0xfe => {
-                let mut value = self.memory_read(self.registers.get_hl() as usize);
+                let mut value = self.read(self.registers.get_hl() as usize);
                 value.set_bit(7, true);
-                self.memory_write(self.registers.get_hl() as usize, value);
+                self.write(self.registers.get_hl() as usize, value);
             }
 
             // BIT b,r
@@ -2064,69 +2392,271 @@ impl GameBoy {
             0x7d => self.registers.f = self.registers.l.bit(7, self.registers.f),
 
             0x46 => {
-                let value = self.memory_read(self.registers.get_hl() as usize);
+                let value = self.read(self.registers.get_hl() as usize);
                 self.registers.f = value.bit(0, self.registers.f);
-                self.memory_write(self.registers.get_hl() as usize, value);
+                self.write(self.registers.get_hl() as usize, value);
             }
             0x4e => {
-                let value = self.memory_read(self.registers.get_hl() as usize);
+                let value = self.read(self.registers.get_hl() as usize);
                 self.registers.f = value.bit(1, self.registers.f);
-                self.memory_write(self.registers.get_hl() as usize, value);
+                self.write(self.registers.get_hl() as usize, value);
             }
             0x56 => {
-                let value = self.memory_read(self.registers.get_hl() as usize);
+                let value = self.read(self.registers.get_hl() as usize);
                 self.registers.f = value.bit(2, self.registers.f);
-                self.memory_write(self.registers.get_hl() as usize, value);
+                self.write(self.registers.get_hl() as usize, value);
             }
             0x5e => {
-                let value = self.memory_read(self.registers.get_hl() as usize);
+                let value = self.read(self.registers.get_hl() as usize);
                 self.registers.f = value.bit(3, self.registers.f);
-                self.memory_write(self.registers.get_hl() as usize, value);
+                self.write(self.registers.get_hl() as usize, value);
             }
             0x66 => {
-                let value = self.memory_read(self.registers.get_hl() as usize);
+                let value = self.read(self.registers.get_hl() as usize);
                 self.registers.f = value.bit(4, self.registers.f);
-                self.memory_write(self.registers.get_hl() as usize, value);
+                self.write(self.registers.get_hl() as usize, value);
             }
             0x6e => {
-                let value = self.memory_read(self.registers.get_hl() as usize);
+                let value = self.read(self.registers.get_hl() as usize);
                 self.registers.f = value.bit(5, self.registers.f);
-                self.memory_write(self.registers.get_hl() as usize, value);
+                self.write(self.registers.get_hl() as usize, value);
             }
             0x76 => {
-                let value = self.memory_read(self.registers.get_hl() as usize);
+                let value = self.read(self.registers.get_hl() as usize);
                 self.registers.f = value.bit(6, self.registers.f);
-                self.memory_write(self.registers.get_hl() as usize, value);
+                self.write(self.registers.get_hl() as usize, value);
             }
             0x7e => {
-                let value = self.memory_read(self.registers.get_hl() as usize);
+                let value = self.read(self.registers.get_hl() as usize);
                 self.registers.f = value.bit(7, self.registers.f);
-                self.memory_write(self.registers.get_hl() as usize, value);
+                self.write(self.registers.get_hl() as usize, value);
             }
         }
     }
 
+    /// Enables the OAM corruption caused by 16-bit INC/DEC during the OAM scan
+    pub fn set_oam_corruption(&mut self, enabled: bool) {
+        self.oam_bug = enabled;
+    }
+
+    fn inc_dec_oam_bug(&mut self, value: u16) {
+        if self.oam_bug && (0xfe00..=0xfeff).contains(&value) {
+            self.display.corrupt_oam();
+        }
+    }
+
+    /// Plugs a device into the link port, replacing the current one
+    pub fn connect_serial(&mut self, device: Box<dyn SerialDevice>) {
+        self.serial.connect(device);
+    }
+
+    /// The LCD as currently drawn, 160x144 pixels in 0RGB. See `frame` for the
+    /// last completed frame.
+    pub fn screen(&self) -> &[u32] {
+        self.display.screen()
+    }
+
+    /// The LCD as currently drawn
+    pub fn screenshot(&self) -> Image {
+        Image {
+            pixels: self.display.screen().to_vec(),
+            width: graphics::WIDTH,
+            height: graphics::HEIGHT,
+        }
+    }
+
+    /// Tile data, tile maps, OAM or palettes as an image, for debugging
+    pub fn vram_view(&self, view: VramView) -> Image {
+        self.display.vram_view(view)
+    }
+
+    pub fn debug_layers(&self) -> DebugLayers {
+        self.display.debug_layers()
+    }
+
+    /// Hides layers or objects whatever the game writes to LCDC, from the
+    /// next pixel drawn
+    pub fn set_debug_layers(&mut self, layers: DebugLayers) {
+        self.display.set_debug_layers(layers);
+    }
+
+    /// The 40 objects in OAM, decoded
+    pub fn oam_entries(&self) -> Vec<Tile> {
+        self.display.oam_entries()
+    }
+
+    /// Title from the cartridge header
+    pub fn title(&self) -> String {
+        (0x134..=0x143)
+            .map(|i| self.cartridge.get(i))
+            .take_while(|&c| c != 0)
+            .filter(|c| c.is_ascii_graphic() || *c == b' ')
+            .map(char::from)
+            .collect::<String>()
+            .trim()
+            .to_string()
+    }
+
+    /// Colors of the DMG shades, they can be changed while running
+    pub fn set_dmg_palettes(&mut self, palettes: DmgPalettes) {
+        self.display.set_dmg_palettes(palettes);
+    }
+
+    /// Buttons held down, until the next call or the next frame polling the
+    /// input source
+    pub fn set_pressed(&mut self, buttons: Vec<Button>) {
+        self.joypad.set_pressed(buttons);
+    }
+
+    pub fn set_frame_sink(&mut self, sink: Box<dyn FrameSink>) {
+        self.frame_sink = Some(sink);
+    }
+
+    pub fn set_input_source(&mut self, input: Box<dyn InputSource>) {
+        self.input = Some(input);
+    }
+
+    /// Shows the frames in and reads the buttons from the same frontend
+    pub fn set_frontend<F: FrameSink + InputSource + Clone + 'static>(&mut self, frontend: F) {
+        self.frame_sink = Some(Box::new(frontend.clone()));
+        self.input = Some(Box::new(frontend));
+    }
+
+    /// Stereo samples played since the last call, at `sample_rate`. They are
+    /// handed over at the end of each frame, and only the last second is
+    /// kept.
+    pub fn take_samples(&mut self) -> Vec<(i16, i16)> {
+        std::mem::take(&mut self.samples)
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.apu.sample_rate()
+    }
+
+    /// Sends the samples to `sink` at the end of each frame instead of
+    /// keeping them for `take_samples`
+    pub fn set_audio_sink(&mut self, mut sink: Box<dyn AudioSink>) {
+        sink.set_sample_rate(self.sample_rate());
+        self.audio_sink = Some(sink);
+    }
+
+    /// Leaves audio channel 0-3 out of the output, the game sees no
+    /// difference
+    pub fn set_channel_muted(&mut self, channel: usize, muted: bool) {
+        self.apu.set_channel_muted(channel, muted);
+    }
+
+    pub fn is_channel_muted(&self, channel: usize) -> bool {
+        self.apu.is_channel_muted(channel)
+    }
+
+    /// Rate of the samples output from now on, `DEFAULT_SAMPLE_RATE` (48kHz)
+    /// by default. A recording in progress is stopped by a change of rate, as
+    /// its WAV track can only have one.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        if sample_rate != self.apu.sample_rate() && self.is_recording() {
+            warn!("Recording stopped by the change of sample rate");
+            if let Err(e) = self.stop_recording() {
+                warn!("Could not complete the recording: {}", e);
+            }
+        }
+        self.apu.set_sample_rate(sample_rate);
+        if let Some(sink) = self.audio_sink.as_mut() {
+            sink.set_sample_rate(self.apu.sample_rate());
+        }
+    }
+
+    /// Records every frame from now on to a Y4M video at `path`, with a WAV
+    /// audio track next to it. A recording in progress is stopped first.
+    pub fn start_recording(&mut self, path: &path::Path) -> io::Result<()> {
+        self.stop_recording()?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        self.recorder = Some(Recorder::create(path, self.apu.sample_rate())?);
+        Ok(())
+    }
+
+    /// Completes the files of the current recording, if any
+    pub fn stop_recording(&mut self) -> io::Result<()> {
+        match self.recorder.take() {
+            Some(recorder) => recorder.finish(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    /// The palettes the GBC boot ROM would pick for this game
+    pub fn gbc_boot_palettes(&self) -> DmgPalettes {
+        let title: Vec<u8> = (0x134..=0x143).map(|i| self.cartridge.get(i)).collect();
+        // old licensee code, or the new one when it is 0x33
+        let nintendo = match self.cartridge.get(0x14b) {
+            0x01 => true,
+            0x33 => self.cartridge.get(0x144) == b'0' && self.cartridge.get(0x145) == b'1',
+            _ => false,
+        };
+        DmgPalettes::gbc_boot(&title, nintendo)
+    }
+
+    /// Runs forever, see `set_frontend`
     pub fn start(&mut self) {
-        self.display.start_window();
         loop {
             self.step();
         }
     }
 
     pub fn new(path: &str) -> GameBoy {
+        GameBoy::with_cartridge(cartridge::load(path::PathBuf::from(path)))
+    }
+
+    fn with_cartridge(cartridge: Box<dyn Cartridge>) -> GameBoy {
+        // CGB flag in the header
+        let cgb = cartridge.get(0x143) & 0x80 > 0;
+        info!("CGB mode: {}", cgb);
+        // SGB flag and the new licensee code in the header, CGB games run in CGB mode
+        let sgb = !cgb && cartridge.get(0x146) == 0x03 && cartridge.get(0x14b) == 0x33;
+        info!("SGB mode: {}", sgb);
+
+        let mut display = Display::new(cgb);
+        if sgb {
+            display.enable_sgb();
+        }
+
         GameBoy {
-            cartridge: cartridge::load(path::PathBuf::from(path)),
-            registers: Registers::new(),
-            memory: Memory::new(),
-            joypad: Joypad::new(),
+            cartridge,
+            registers: if cgb {
+                Registers::new_cgb()
+            } else {
+                Registers::new()
+            },
+            memory: Memory::new(cgb),
+            joypad: Joypad::new(sgb),
             timer: Timer::new(),
+            dma: OamDma::new(),
+            serial: Serial::new(cgb),
+            apu: Apu::new(cgb),
+            hdma: Hdma::new(cgb),
+            speed: Speed::new(cgb),
             ime: false,
             interrupt_flag: 0xe1,
             set_ei: false,
 
             cpu_cycles: 0,
+            cycles_run: 0,
             halt: false,
-            display: Display::new(),
+            stopped: None,
+            oam_bug: false,
+            total_cycles: 0,
+            frame_completed: false,
+            frame_sink: None,
+            input: None,
+            recorder: None,
+            audio_sink: None,
+            samples: Vec::new(),
+            display,
         }
     }
 }
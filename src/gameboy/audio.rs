@@ -0,0 +1,440 @@
+mod envelope;
+mod noise;
+mod square;
+mod wave;
+
+use std::mem;
+
+use log::trace;
+
+use super::memory_bus::MemoryAccessor;
+use noise::Noise;
+use square::Square;
+use wave::Wave;
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 48000;
+
+/// The APU clock, which stays the same in CGB double speed
+const CLOCK: u32 = 4194304;
+
+/// Bits reading as 1 in FF10-FF2F: unused and write-only bits
+const READ_MASKS: [u8; 0x20] = [
+    0x80, 0x3f, 0x00, 0xff, 0xbf, // NR10-NR14
+    0xff, 0x3f, 0x00, 0xff, 0xbf, // NR20-NR24
+    0x7f, 0xff, 0x9f, 0xff, 0xbf, // NR30-NR34
+    0xff, 0xff, 0x00, 0x00, 0xbf, // NR40-NR44
+    0x00, 0x00, 0x70, // NR50-NR52
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+];
+
+/// Registers as left by the boot ROM, from FF10 to FF25
+const BOOT_REGISTERS: [u8; 0x16] = [
+    0x80, 0xbf, 0xf3, 0xff, 0xbf, // NR10-NR14
+    0xff, 0x3f, 0x00, 0xff, 0xbf, // NR20-NR24
+    0x7f, 0xff, 0x9f, 0xff, 0xbf, // NR30-NR34
+    0xff, 0xff, 0x00, 0x00, 0xbf, // NR40-NR44
+    0x77, 0xf3, // NR50-NR51
+];
+
+/// Receives the samples of each frame as they are produced, see
+/// `GameBoy::set_audio_sink`
+pub trait AudioSink {
+    fn play(&mut self, samples: &[(i16, i16)]);
+
+    /// Samples per second of emulated time, given when the sink is set and
+    /// whenever it changes
+    fn set_sample_rate(&mut self, _sample_rate: u32) {}
+}
+
+/// Audio Processing Unit
+///
+/// The channels run on the 4MHz APU clock, their length counters, envelopes
+/// and sweep being clocked by the frame sequencer, itself stepped at 512Hz
+/// by the falling edges of DIV bit 4. The mix is averaged down to stereo
+/// samples at `sample_rate`.
+pub struct Apu {
+    square1: Square,
+    square2: Square,
+    wave: Wave,
+    noise: Noise,
+    /// FF10-FF2F as written, see `READ_MASKS`
+    registers: [u8; 0x20],
+    /// NR52 bit 7, every register but NR52 and wave RAM is cleared and
+    /// read-only while off
+    powered: bool,
+    /// Next step of the frame sequencer, 0-7
+    frame_step: u8,
+    cgb: bool,
+    /// Channels left out of the mix, bit 0 for channel 1, for debugging
+    muted_channels: u8,
+
+    sample_rate: u32,
+    /// Advances by `sample_rate` every T-cycle, a sample is output each time
+    /// it goes past `CLOCK`
+    sample_clock: u32,
+    /// Sum of the left and right mix since the last sample, and T-cycles
+    /// summed
+    mix_sum: (i32, i32),
+    mixed: i32,
+    /// High-pass filters removing the DC offset of the DACs, as on hardware
+    capacitors: (f32, f32),
+    charge_factor: f32,
+    samples: Vec<(i16, i16)>,
+}
+
+impl Apu {
+    /// Advances the channels by `cycles` T-cycles of the APU clock
+    pub fn step(&mut self, cycles: u32) {
+        for _ in 0..cycles {
+            if self.powered {
+                self.square1.tick();
+                self.square2.tick();
+                self.wave.tick();
+                self.noise.tick();
+            }
+
+            let (left, right) = self.mix();
+            self.mix_sum.0 += left;
+            self.mix_sum.1 += right;
+            self.mixed += 1;
+
+            self.sample_clock += self.sample_rate;
+            if self.sample_clock >= CLOCK {
+                self.sample_clock -= CLOCK;
+                self.output_sample();
+            }
+        }
+    }
+
+    /// Clocked at 512Hz: lengths on even steps, sweep on steps 2 and 6,
+    /// envelopes on step 7
+    pub fn frame_sequencer_step(&mut self) {
+        if !self.powered {
+            return;
+        }
+        let step = self.frame_step;
+        self.frame_step = (self.frame_step + 1) & 0x07;
+
+        if step & 1 == 0 {
+            self.square1.length_step();
+            self.square2.length_step();
+            self.wave.length_step();
+            self.noise.length_step();
+        }
+        if step == 2 || step == 6 {
+            self.square1.sweep_step();
+        }
+        if step == 7 {
+            self.square1.envelope_step();
+            self.square2.envelope_step();
+            self.noise.envelope_step();
+        }
+    }
+
+    /// Left and right output, from -480 to 480
+    fn mix(&self) -> (i32, i32) {
+        let outputs = [
+            self.square1.output(),
+            self.square2.output(),
+            self.wave.output(),
+            self.noise.output(),
+        ];
+        let panning = self.registers[0x15];
+        let (mut left, mut right) = (0, 0);
+        for (channel, output) in outputs.iter().enumerate() {
+            // the DACs output from -1 to 1, nothing when off
+            let Some(volume) = output else {
+                continue;
+            };
+            if self.muted_channels & (1 << channel) > 0 {
+                continue;
+            }
+            let analog = *volume as i32 * 2 - 15;
+            if panning & (0x10 << channel) > 0 {
+                left += analog;
+            }
+            if panning & (0x01 << channel) > 0 {
+                right += analog;
+            }
+        }
+
+        let master = self.registers[0x14];
+        let left_volume = ((master >> 4) & 0x07) as i32 + 1;
+        let right_volume = (master & 0x07) as i32 + 1;
+        (left * left_volume, right * right_volume)
+    }
+
+    fn output_sample(&mut self) {
+        let mixed = self.mixed.max(1) as f32 * 480.0;
+        let (left, right) = (self.mix_sum.0 as f32 / mixed, self.mix_sum.1 as f32 / mixed);
+        self.mix_sum = (0, 0);
+        self.mixed = 0;
+
+        let left_out = left - self.capacitors.0;
+        self.capacitors.0 = left - left_out * self.charge_factor;
+        let right_out = right - self.capacitors.1;
+        self.capacitors.1 = right - right_out * self.charge_factor;
+
+        let to_i16 = |value: f32| (value * i16::MAX as f32) as i16;
+        self.samples.push((to_i16(left_out), to_i16(right_out)));
+    }
+
+    /// Stereo samples output since the last call
+    pub fn take_samples(&mut self) -> Vec<(i16, i16)> {
+        std::mem::take(&mut self.samples)
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate.clamp(1, CLOCK);
+        // the capacitor charge lost in a sample, 0.999958 per T-cycle
+        self.charge_factor = 0.999958f32.powf(CLOCK as f32 / self.sample_rate as f32);
+    }
+
+    /// Leaves channel 0-3 out of the mix, the registers still reflect it
+    pub fn set_channel_muted(&mut self, channel: usize, muted: bool) {
+        if muted {
+            self.muted_channels |= 1 << channel;
+        } else {
+            self.muted_channels &= !(1 << channel);
+        }
+    }
+
+    pub fn is_channel_muted(&self, channel: usize) -> bool {
+        self.muted_channels & (1 << channel) > 0
+    }
+
+    fn power_off(&mut self) {
+        let square1 = mem::replace(&mut self.square1, Square::new(true));
+        let square2 = mem::replace(&mut self.square2, Square::new(false));
+        let wave = mem::replace(&mut self.wave, Wave::new());
+        let noise = mem::replace(&mut self.noise, Noise::new());
+        self.wave.ram = wave.ram;
+        // the length counters are not powered on DMG
+        if !self.cgb {
+            self.square1.length = square1.length.powered_off();
+            self.square2.length = square2.length.powered_off();
+            self.wave.length = wave.length.powered_off();
+            self.noise.length = noise.length.powered_off();
+        }
+        self.registers[..0x16].fill(0);
+        self.powered = false;
+    }
+
+    /// Channel 1-4 status bits of NR52
+    fn channels_enabled(&self) -> u8 {
+        self.square1.enabled as u8
+            | (self.square2.enabled as u8) << 1
+            | (self.wave.enabled as u8) << 2
+            | (self.noise.enabled as u8) << 3
+    }
+
+    pub fn new(cgb: bool) -> Self {
+        let mut apu = Apu {
+            square1: Square::new(true),
+            square2: Square::new(false),
+            wave: Wave::new(),
+            noise: Noise::new(),
+            registers: [0; 0x20],
+            powered: true,
+            frame_step: 0,
+            cgb,
+            muted_channels: 0,
+
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            sample_clock: 0,
+            mix_sum: (0, 0),
+            mixed: 0,
+            capacitors: (0.0, 0.0),
+            charge_factor: 1.0,
+            samples: Vec::new(),
+        };
+        apu.set_sample_rate(DEFAULT_SAMPLE_RATE);
+        for (i, value) in BOOT_REGISTERS.iter().enumerate() {
+            // NRx4 without the trigger bit
+            let value = if i % 5 == 4 { value & 0x7f } else { *value };
+            apu.write(0xff10 + i, value);
+        }
+        // the boot sound has faded out, but channel 1 is still on
+        apu.square1.enabled = true;
+        apu
+    }
+}
+
+impl MemoryAccessor for Apu {
+    fn get(&self, location: usize) -> u8 {
+        match location {
+            0xff26 => (self.powered as u8) << 7 | 0x70 | self.channels_enabled(),
+            0xff10..=0xff2f => self.registers[location - 0xff10] | READ_MASKS[location - 0xff10],
+            // while playing, the byte being played is read instead
+            0xff30..=0xff3f if self.wave.enabled => self.wave.current_byte(),
+            0xff30..=0xff3f => self.wave.ram[location - 0xff30],
+            // PCM12/PCM34, CGB only: digital outputs of the channels
+            0xff76 | 0xff77 if !self.cgb => 0xff,
+            0xff76 => self.square2.output().unwrap_or(0) << 4 | self.square1.output().unwrap_or(0),
+            0xff77 => self.noise.output().unwrap_or(0) << 4 | self.wave.output().unwrap_or(0),
+            _ => panic!("audio location read: {:#x}", location),
+        }
+    }
+
+    fn write(&mut self, location: usize, value: u8) {
+        trace!("Writting to audio: {:#x}: {:#x}", location, value);
+        match location {
+            0xff26 => {
+                if value & 0x80 == 0 && self.powered {
+                    self.power_off();
+                } else if value & 0x80 > 0 && !self.powered {
+                    self.powered = true;
+                    self.frame_step = 0;
+                }
+            }
+            0xff30..=0xff3f => self.wave.ram[location - 0xff30] = value,
+            // on DMG the length counters can still be loaded while off
+            0xff11 | 0xff16 | 0xff1b | 0xff20 if !self.powered && !self.cgb => match location {
+                0xff11 => self.square1.length.load(value & 0x3f),
+                0xff16 => self.square2.length.load(value & 0x3f),
+                0xff1b => self.wave.length.load(value),
+                _ => self.noise.length.load(value & 0x3f),
+            },
+            0xff10..=0xff2f if !self.powered => (),
+            0xff10..=0xff2f => {
+                self.registers[location - 0xff10] = value;
+                let register = (location - 0xff10) % 5;
+                match location {
+                    0xff10..=0xff14 => self.square1.write(register, value),
+                    0xff15..=0xff19 => self.square2.write(register, value),
+                    0xff1a..=0xff1e => self.wave.write(register, value),
+                    0xff1f..=0xff23 => self.noise.write(register, value),
+                    _ => (),
+                }
+            }
+            0xff76 | 0xff77 => (),
+            _ => panic!("audio location write: {:#x} - {:#x}", location, value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Apu, CLOCK};
+    use crate::gameboy::memory_bus::MemoryAccessor;
+
+    #[test]
+    fn read_masks() {
+        let mut apu = Apu::new(false);
+        apu.write(0xff11, 0x00);
+        assert_eq!(apu.get(0xff11), 0x3f);
+        apu.write(0xff13, 0x12);
+        assert_eq!(apu.get(0xff13), 0xff);
+        apu.write(0xff24, 0x35);
+        assert_eq!(apu.get(0xff24), 0x35);
+        assert_eq!(apu.get(0xff27), 0xff);
+        assert_eq!(apu.get(0xff26), 0xf1);
+    }
+
+    #[test]
+    fn power_off_clears_registers() {
+        let mut apu = Apu::new(false);
+        apu.write(0xff30, 0x12);
+        apu.write(0xff26, 0x00);
+        assert_eq!(apu.get(0xff26), 0x70);
+        assert_eq!(apu.get(0xff24), 0x00);
+        assert_eq!(apu.get(0xff12), 0x00);
+
+        // ignored while off, but for lengths on DMG and wave RAM
+        apu.write(0xff24, 0x77);
+        assert_eq!(apu.get(0xff24), 0x00);
+        apu.write(0xff20, 0x3f);
+        assert_eq!(apu.get(0xff30), 0x12);
+
+        apu.write(0xff26, 0x80);
+        apu.write(0xff21, 0xf0);
+        // length enabled, one step left
+        apu.write(0xff23, 0xc0);
+        assert_eq!(apu.get(0xff26), 0xf8);
+        apu.frame_sequencer_step();
+        assert_eq!(apu.get(0xff26), 0xf0);
+    }
+
+    #[test]
+    fn power_off_keeps_lengths_on_dmg() {
+        for cgb in [false, true] {
+            let mut apu = Apu::new(cgb);
+            // channel 2 with one length step left
+            apu.write(0xff16, 0x3f);
+            apu.write(0xff26, 0x00);
+            apu.write(0xff26, 0x80);
+            apu.write(0xff17, 0xf0);
+            apu.write(0xff19, 0xc0);
+            apu.frame_sequencer_step();
+            assert_eq!(apu.get(0xff26) & 0x02 == 0, !cgb);
+        }
+    }
+
+    #[test]
+    fn square_channel_plays() {
+        let mut apu = Apu::new(false);
+        apu.take_samples();
+        // channel 2 on both sides, full volume, 50% duty at about 1kHz
+        apu.write(0xff25, 0x22);
+        apu.write(0xff16, 0x80);
+        apu.write(0xff17, 0xf0);
+        apu.write(0xff18, 0x00);
+        apu.write(0xff19, 0x87);
+        assert_eq!(apu.get(0xff26) & 0x02, 0x02);
+
+        apu.step(CLOCK / 64);
+        let samples = apu.take_samples();
+        assert_eq!(samples.len(), 750);
+        let loud = samples.iter().filter(|(l, _)| l.abs() > 1000).count();
+        assert!(loud > 600, "{}", loud);
+        assert!(samples.iter().all(|(l, r)| l == r));
+    }
+
+    #[test]
+    fn muted_channel_is_left_out_of_the_mix() {
+        let mut apu = Apu::new(false);
+        apu.write(0xff25, 0x22);
+        apu.write(0xff17, 0xf0);
+        apu.write(0xff19, 0x87);
+        apu.set_channel_muted(1, true);
+        apu.step(CLOCK / 64);
+        assert!(apu.take_samples().iter().all(|(l, _)| l.abs() < 100));
+        // still playing
+        assert_eq!(apu.get(0xff26) & 0x02, 0x02);
+        assert_eq!(apu.get(0xff76) >> 4, 0x0f);
+    }
+
+    #[test]
+    fn sweep_overflow_disables_channel_1() {
+        let mut apu = Apu::new(false);
+        // period 1, increasing, shift 1
+        apu.write(0xff10, 0x11);
+        apu.write(0xff12, 0xf0);
+        apu.write(0xff13, 0x00);
+        apu.write(0xff14, 0x84);
+        assert_eq!(apu.get(0xff26) & 0x01, 0x01);
+        for _ in 0..8 {
+            apu.frame_sequencer_step();
+        }
+        assert_eq!(apu.get(0xff26) & 0x01, 0x00);
+    }
+
+    #[test]
+    fn wave_ram_reads_the_playing_byte() {
+        let mut apu = Apu::new(false);
+        for i in 0..16 {
+            apu.write(0xff30 + i, i as u8);
+        }
+        assert_eq!(apu.get(0xff35), 5);
+
+        apu.write(0xff1a, 0x80);
+        apu.write(0xff1e, 0x87);
+        // 2 T-cycles per sample at the highest frequency
+        apu.step(2 * 6);
+        assert_eq!(apu.get(0xff30), 3);
+    }
+}
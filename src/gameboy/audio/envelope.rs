@@ -0,0 +1,105 @@
+/// Length counter, disabling the channel when it runs out
+pub struct Length {
+    counter: u16,
+    /// 64, or 256 for the wave channel
+    max: u16,
+    /// NRx4 bit 6
+    pub enabled: bool,
+}
+
+impl Length {
+    /// NRx1, the counter runs for `max - value` steps
+    pub fn load(&mut self, value: u8) {
+        self.counter = self.max - value as u16;
+    }
+
+    pub fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max;
+        }
+    }
+
+    /// Clocked at 256Hz by the frame sequencer. Returns true when the
+    /// channel has to be disabled.
+    pub fn step(&mut self) -> bool {
+        if !self.enabled || self.counter == 0 {
+            return false;
+        }
+        self.counter -= 1;
+        self.counter == 0
+    }
+
+    /// What is left through an APU power off on DMG: the counter, but not
+    /// the enable bit of NRx4
+    pub fn powered_off(self) -> Self {
+        Length {
+            enabled: false,
+            ..self
+        }
+    }
+
+    pub fn new(max: u16) -> Self {
+        Length {
+            counter: 0,
+            max,
+            enabled: false,
+        }
+    }
+}
+
+/// Volume envelope of the square and noise channels
+pub struct Envelope {
+    /// NRx2
+    ///
+    /// 4-7 - Initial volume
+    ///
+    /// 3 - Direction: 0 = decrease, 1 = increase
+    ///
+    /// 0-2 - Period in 64Hz steps, 0 stops the envelope
+    register: u8,
+    pub volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    pub fn write(&mut self, value: u8) {
+        self.register = value;
+    }
+
+    /// The DAC is off when the initial volume is 0 and the envelope
+    /// decreases
+    pub fn dac_enabled(&self) -> bool {
+        self.register & 0xf8 != 0
+    }
+
+    pub fn trigger(&mut self) {
+        self.volume = self.register >> 4;
+        self.timer = self.register & 0x07;
+    }
+
+    /// Clocked at 64Hz by the frame sequencer
+    pub fn step(&mut self) {
+        let period = self.register & 0x07;
+        if period == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = period;
+        if self.register & 0x08 > 0 {
+            self.volume = (self.volume + 1).min(15);
+        } else {
+            self.volume = self.volume.saturating_sub(1);
+        }
+    }
+
+    pub fn new() -> Self {
+        Envelope {
+            register: 0,
+            volume: 0,
+            timer: 0,
+        }
+    }
+}
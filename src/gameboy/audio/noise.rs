@@ -0,0 +1,125 @@
+use super::envelope::{Envelope, Length};
+
+/// Channel 4, a linear feedback shift register clocked at a configurable rate
+pub struct Noise {
+    pub enabled: bool,
+    pub length: Length,
+    envelope: Envelope,
+    /// NR43
+    ///
+    /// 4-7 - Clock shift
+    ///
+    /// 3 - LFSR width: 0 = 15 bits, 1 = 7 bits
+    ///
+    /// 0-2 - Clock divider
+    register: u8,
+    lfsr: u16,
+    /// T-cycles until the next LFSR shift
+    timer: u32,
+}
+
+impl Noise {
+    fn period(&self) -> u32 {
+        let divider = match self.register & 0x07 {
+            0 => 8,
+            code => code as u32 * 16,
+        };
+        divider << (self.register >> 4)
+    }
+
+    /// NR41-NR44, NR40 does not exist
+    pub fn write(&mut self, register: usize, value: u8) {
+        match register {
+            0 => (),
+            1 => self.length.load(value & 0x3f),
+            2 => {
+                self.envelope.write(value);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.register = value,
+            _ => {
+                self.length.enabled = value & 0x40 > 0;
+                if value & 0x80 > 0 {
+                    self.enabled = self.envelope.dac_enabled();
+                    self.length.trigger();
+                    self.envelope.trigger();
+                    self.lfsr = 0x7fff;
+                    self.timer = self.period();
+                }
+            }
+        }
+    }
+
+    /// Advances by one T-cycle
+    pub fn tick(&mut self) {
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = self.period();
+
+        let bit = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+        self.lfsr = (self.lfsr >> 1) | (bit << 14);
+        if self.register & 0x08 > 0 {
+            self.lfsr = (self.lfsr & !(1 << 6)) | (bit << 6);
+        }
+    }
+
+    pub fn length_step(&mut self) {
+        if self.length.step() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn envelope_step(&mut self) {
+        self.envelope.step();
+    }
+
+    /// Volume (0-15) sent to the DAC, None when the DAC is off
+    pub fn output(&self) -> Option<u8> {
+        if !self.envelope.dac_enabled() {
+            return None;
+        }
+        Some(if self.enabled && self.lfsr & 1 == 0 {
+            self.envelope.volume
+        } else {
+            0
+        })
+    }
+
+    pub fn new() -> Self {
+        Noise {
+            enabled: false,
+            length: Length::new(64),
+            envelope: Envelope::new(),
+            register: 0,
+            lfsr: 0x7fff,
+            timer: 8,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Noise;
+
+    #[test]
+    fn short_lfsr_repeats_every_127_shifts() {
+        let mut noise = Noise::new();
+        noise.write(2, 0xf0);
+        // 7 bits, period 8 T-cycles
+        noise.write(3, 0x08);
+        noise.write(4, 0x80);
+
+        let outputs: Vec<Option<u8>> = (0..127 * 2 * 8)
+            .map(|_| {
+                noise.tick();
+                noise.output()
+            })
+            .collect();
+        assert!(outputs.contains(&Some(15)) && outputs.contains(&Some(0)));
+        assert_eq!(outputs[..127 * 8], outputs[127 * 8..]);
+    }
+}
@@ -0,0 +1,188 @@
+use super::envelope::{Envelope, Length};
+
+/// Waveforms of the 12.5%, 25%, 50% and 75% duty cycles
+const DUTY_PATTERNS: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+
+/// Frequency sweep of channel 1
+struct Sweep {
+    /// NR10
+    ///
+    /// 4-6 - Period in 128Hz steps, 0 stops the sweep
+    ///
+    /// 3 - Direction: 0 = increase, 1 = decrease
+    ///
+    /// 0-2 - Shift
+    register: u8,
+    /// Frequency the sweep works from, copied on trigger
+    shadow: u16,
+    timer: u8,
+    enabled: bool,
+}
+
+impl Sweep {
+    fn period(&self) -> u8 {
+        (self.register >> 4) & 0x07
+    }
+
+    fn shift(&self) -> u8 {
+        self.register & 0x07
+    }
+
+    /// Reloads the timer, a period of 0 counting as 8
+    fn reload(&mut self) {
+        self.timer = match self.period() {
+            0 => 8,
+            period => period,
+        };
+    }
+
+    /// Next frequency, above 2047 when it overflows
+    fn next_frequency(&self) -> u16 {
+        let delta = self.shadow >> self.shift();
+        if self.register & 0x08 > 0 {
+            self.shadow - delta
+        } else {
+            self.shadow + delta
+        }
+    }
+}
+
+/// Square wave channels 1 (with sweep) and 2
+pub struct Square {
+    pub enabled: bool,
+    sweep: Option<Sweep>,
+    pub length: Length,
+    envelope: Envelope,
+    /// NRx1 bits 6-7
+    duty: u8,
+    /// Position in the duty pattern
+    duty_step: u8,
+    /// 11 bits from NRx3 and NRx4
+    frequency: u16,
+    /// T-cycles until the next duty step
+    timer: u16,
+}
+
+impl Square {
+    /// NRx0-NRx4, NR20 does not exist
+    pub fn write(&mut self, register: usize, value: u8) {
+        match register {
+            0 => {
+                if let Some(sweep) = self.sweep.as_mut() {
+                    sweep.register = value;
+                }
+            }
+            1 => {
+                self.duty = value >> 6;
+                self.length.load(value & 0x3f);
+            }
+            2 => {
+                self.envelope.write(value);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.frequency = (self.frequency & 0x700) | value as u16,
+            _ => {
+                self.frequency = (self.frequency & 0xff) | ((value as u16 & 0x07) << 8);
+                self.length.enabled = value & 0x40 > 0;
+                if value & 0x80 > 0 {
+                    self.trigger();
+                }
+            }
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.timer = (2048 - self.frequency) * 4;
+        self.envelope.trigger();
+
+        if let Some(sweep) = self.sweep.as_mut() {
+            sweep.shadow = self.frequency;
+            sweep.reload();
+            sweep.enabled = sweep.period() != 0 || sweep.shift() != 0;
+            if sweep.shift() != 0 && sweep.next_frequency() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    /// Advances by one T-cycle
+    pub fn tick(&mut self) {
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = (2048 - self.frequency) * 4;
+            self.duty_step = (self.duty_step + 1) & 0x07;
+        }
+    }
+
+    pub fn length_step(&mut self) {
+        if self.length.step() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn envelope_step(&mut self) {
+        self.envelope.step();
+    }
+
+    /// Clocked at 128Hz by the frame sequencer, channel 1 only
+    pub fn sweep_step(&mut self) {
+        let Some(sweep) = self.sweep.as_mut() else {
+            return;
+        };
+        sweep.timer = sweep.timer.saturating_sub(1);
+        if sweep.timer > 0 {
+            return;
+        }
+        sweep.reload();
+        if !sweep.enabled || sweep.period() == 0 {
+            return;
+        }
+
+        let frequency = sweep.next_frequency();
+        if frequency > 2047 {
+            self.enabled = false;
+        } else if sweep.shift() != 0 {
+            sweep.shadow = frequency;
+            self.frequency = frequency;
+            // checked again with the new frequency, without writing it back
+            if sweep.next_frequency() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    /// Volume (0-15) sent to the DAC, None when the DAC is off
+    pub fn output(&self) -> Option<u8> {
+        if !self.envelope.dac_enabled() {
+            return None;
+        }
+        let high = DUTY_PATTERNS[self.duty as usize] >> (7 - self.duty_step) & 1 > 0;
+        Some(if self.enabled && high {
+            self.envelope.volume
+        } else {
+            0
+        })
+    }
+
+    pub fn new(sweep: bool) -> Self {
+        Square {
+            enabled: false,
+            sweep: sweep.then_some(Sweep {
+                register: 0,
+                shadow: 0,
+                timer: 0,
+                enabled: false,
+            }),
+            length: Length::new(64),
+            envelope: Envelope::new(),
+            duty: 0,
+            duty_step: 0,
+            frequency: 0,
+            timer: 8192,
+        }
+    }
+}
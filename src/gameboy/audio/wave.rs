@@ -0,0 +1,96 @@
+use super::envelope::Length;
+
+/// Channel 3, playing the 32 4-bit samples of wave RAM
+pub struct Wave {
+    pub enabled: bool,
+    /// NR30 bit 7
+    dac: bool,
+    pub length: Length,
+    /// NR32 bits 5-6: mute, 100%, 50% or 25%
+    volume: u8,
+    /// 11 bits from NR33 and NR34
+    frequency: u16,
+    /// T-cycles until the next sample
+    timer: u16,
+    /// Sample being played, high nibble of each byte first
+    position: u8,
+    /// FF30-FF3F
+    pub ram: [u8; 16],
+}
+
+impl Wave {
+    /// NR30-NR34
+    pub fn write(&mut self, register: usize, value: u8) {
+        match register {
+            0 => {
+                self.dac = value & 0x80 > 0;
+                if !self.dac {
+                    self.enabled = false;
+                }
+            }
+            1 => self.length.load(value),
+            2 => self.volume = (value >> 5) & 0x03,
+            3 => self.frequency = (self.frequency & 0x700) | value as u16,
+            _ => {
+                self.frequency = (self.frequency & 0xff) | ((value as u16 & 0x07) << 8);
+                self.length.enabled = value & 0x40 > 0;
+                if value & 0x80 > 0 {
+                    self.enabled = self.dac;
+                    self.length.trigger();
+                    self.timer = (2048 - self.frequency) * 2;
+                    self.position = 0;
+                }
+            }
+        }
+    }
+
+    /// Byte of wave RAM being played
+    pub fn current_byte(&self) -> u8 {
+        self.ram[self.position as usize / 2]
+    }
+
+    /// Advances by one T-cycle
+    pub fn tick(&mut self) {
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = (2048 - self.frequency) * 2;
+            self.position = (self.position + 1) & 0x1f;
+        }
+    }
+
+    pub fn length_step(&mut self) {
+        if self.length.step() {
+            self.enabled = false;
+        }
+    }
+
+    /// Volume (0-15) sent to the DAC, None when the DAC is off
+    pub fn output(&self) -> Option<u8> {
+        if !self.dac {
+            return None;
+        }
+        if !self.enabled || self.volume == 0 {
+            return Some(0);
+        }
+        let byte = self.current_byte();
+        let sample = if self.position & 1 == 0 {
+            byte >> 4
+        } else {
+            byte & 0x0f
+        };
+        Some(sample >> (self.volume - 1))
+    }
+
+    pub fn new() -> Self {
+        Wave {
+            enabled: false,
+            dac: false,
+            length: Length::new(256),
+            volume: 0,
+            frequency: 0,
+            timer: 4096,
+            position: 0,
+            ram: [0; 16],
+        }
+    }
+}
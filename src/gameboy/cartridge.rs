@@ -5,6 +5,7 @@ use std::{
     str,
 };
 
+mod gbs;
 mod mbc1;
 mod mbc3;
 mod nombc;
@@ -22,6 +23,11 @@ enum Type {
 
 pub(crate) trait Cartridge: MemoryAccessor {}
 
+/// Plays a GBS file, `rom` being the image built by `Gbs`
+pub(super) fn gbs(rom: Vec<u8>) -> Box<dyn Cartridge> {
+    Box::new(gbs::GbsCartridge::new(rom))
+}
+
 fn load_file(file_path: &path::Path) -> io::Result<Vec<u8>> {
     let mut f = File::open(file_path)?;
     let mut buffer = Vec::new();
@@ -0,0 +1,49 @@
+use super::Cartridge;
+use crate::gameboy::memory_bus::MemoryAccessor;
+
+/// ROM image of a GBS file with its driver, banked in 16KB like on MBC5,
+/// and 8KB of RAM
+pub struct GbsCartridge {
+    rom: Vec<u8>,
+    /// Bank mapped at 0x4000-0x7fff
+    rom_bank: usize,
+    ram: Vec<u8>,
+}
+
+impl Cartridge for GbsCartridge {}
+
+impl MemoryAccessor for GbsCartridge {
+    fn get(&self, location: usize) -> u8 {
+        match location {
+            0x0000..=0x3fff => self.rom[location],
+            0x4000..=0x7fff => self.rom[self.rom_bank * 0x4000 + location - 0x4000],
+            0xa000..=0xbfff => self.ram[location - 0xa000],
+            _ => panic!("Unknown location: {:#x}", location),
+        }
+    }
+
+    fn write(&mut self, location: usize, value: u8) {
+        match location {
+            0x2000..=0x3fff => {
+                let banks = self.rom.len() / 0x4000;
+                self.rom_bank = (value as usize % banks).max(1);
+            }
+            0xa000..=0xbfff => self.ram[location - 0xa000] = value,
+            // RAM enable and the other MBC registers
+            _ => (),
+        }
+    }
+}
+
+impl GbsCartridge {
+    /// `rom` is padded to a whole number of banks, at least 2
+    pub fn new(mut rom: Vec<u8>) -> Self {
+        let banks = rom.len().div_ceil(0x4000).max(2);
+        rom.resize(banks * 0x4000, 0xff);
+        GbsCartridge {
+            rom,
+            rom_bank: 1,
+            ram: vec![0; 0x2000],
+        }
+    }
+}
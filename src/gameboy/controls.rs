@@ -1,8 +1,88 @@
+use std::path::PathBuf;
+
 use log::trace;
-use minifb::Key;
+
+use super::graphics::DebugLayer;
 
 pub const REGISTER_LOCATION: usize = 0xff00;
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Button {
+    A,
+    B,
+    Select,
+    Start,
+    Right,
+    Left,
+    Up,
+    Down,
+}
+
+impl Button {
+    pub const ALL: [Button; 8] = [
+        Button::A,
+        Button::B,
+        Button::Select,
+        Button::Start,
+        Button::Right,
+        Button::Left,
+        Button::Up,
+        Button::Down,
+    ];
+
+    /// Case insensitive name, as in `Debug`
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|button| format!("{:?}", button).eq_ignore_ascii_case(name))
+    }
+
+    /// Bit in the pressed buttons: the d-pad in the low nibble and the
+    /// buttons in the high one, in the order of the joypad register
+    fn bit(self) -> u8 {
+        match self {
+            Button::Right => 0x01,
+            Button::Left => 0x02,
+            Button::Up => 0x04,
+            Button::Down => 0x08,
+            Button::A => 0x10,
+            Button::B => 0x20,
+            Button::Select => 0x40,
+            Button::Start => 0x80,
+        }
+    }
+}
+
+/// Changes to the emulator settings asked by the user
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Command {
+    /// Cycle through the DMG palette presets
+    NextPalette,
+    /// Start recording to the path, or stop the current recording
+    ToggleRecording(PathBuf),
+    /// Show or hide a layer, see `DebugLayers`
+    ToggleLayer(DebugLayer),
+    /// Tint each layer in its own color, or stop
+    ToggleHighlight,
+    /// Mute or unmute audio channel 0-3
+    ToggleChannel(usize),
+}
+
+/// Where the buttons come from, polled once per frame
+pub trait InputSource {
+    fn pressed_buttons(&mut self) -> Vec<Button>;
+
+    /// Commands asked since the last frame
+    fn commands(&mut self) -> Vec<Command> {
+        Vec::new()
+    }
+}
+
+/// SGB packets are 16 bytes, followed by a stop bit
+const PACKET_BITS: usize = 128;
+/// SGB command selecting the number of controllers
+const MLT_REQ: u8 = 0x11;
+
 pub struct Joypad {
     /// ff00
     ///
@@ -19,7 +99,21 @@ pub struct Joypad {
     /// 0 A / Right
     joypad: u8,
 
-    keys: Vec<minifb::Key>,
+    /// Buttons held down, see `Button::bit`
+    pressed: u8,
+
+    /// Super Game Boy, receiving commands through P14/P15
+    sgb: bool,
+    /// Bits received of the current packet, None when not transferring
+    packet_bits: Option<usize>,
+    packet: [u8; 16],
+    /// Packets of a multi-packet command
+    command: Vec<u8>,
+    packets_left: u8,
+    completed_command: Option<Vec<u8>>,
+    /// Controllers enabled by MLT_REQ, and the one currently read
+    players: u8,
+    player: u8,
 }
 
 impl Joypad {
@@ -28,33 +122,24 @@ impl Joypad {
             REGISTER_LOCATION => {
                 // If neither buttons nor d-pad is selected ($30 was written), then the low nibble
                 // reads $F (all buttons released).
-                if self.joypad & 0x30 == 0x30 || self.keys.is_empty() {
+                // with several SGB controllers, it reads the current player
+                if self.joypad & 0x30 == 0x30 {
+                    if self.players > 1 {
+                        return (self.joypad & 0xf0) | (0xf - self.player);
+                    }
+                    return self.joypad | 0xf;
+                }
+                if self.player != 0 {
                     return self.joypad | 0xf;
                 }
 
+                // 0 when pressed
                 let mut keys = 0xffu8;
-                let buttons = self.buttons_selected();
-                let dpad = self.dpad_selected();
-
-                if (buttons && self.keys.contains(&Key::Enter))
-                    || (dpad && self.keys.contains(&Key::Down))
-                {
-                    keys &= 0xf7; // 11110111
-                }
-                if (buttons && self.keys.contains(&Key::Backspace))
-                    || (dpad && self.keys.contains(&Key::Up))
-                {
-                    keys &= 0xfb; // 11111011
-                }
-                if (buttons && self.keys.contains(&Key::X))
-                    || (dpad && self.keys.contains(&Key::Left))
-                {
-                    keys &= 0xfd; // 11111101
+                if self.buttons_selected() {
+                    keys &= !(self.pressed >> 4);
                 }
-                if (buttons && self.keys.contains(&Key::Z))
-                    || (dpad && self.keys.contains(&Key::Right))
-                {
-                    keys &= 0xfe; // 11111110
+                if self.dpad_selected() {
+                    keys &= !(self.pressed & 0x0f);
                 }
                 self.joypad & keys
             }
@@ -67,13 +152,75 @@ impl Joypad {
         let value = (value & 0x30) | (self.joypad & 0x0f);
         trace!("updating joypad: {:#b}", value);
         match location {
-            REGISTER_LOCATION => self.joypad = value,
+            REGISTER_LOCATION => {
+                if self.sgb {
+                    self.sgb_write(value & 0x30);
+                }
+                self.joypad = value
+            }
             _ => {
                 panic!("controls location write: {:#x}", location)
             }
         }
     }
 
+    /// A packet starts with a reset pulse (P14 and P15 low), then every bit is
+    /// a pulse on P14 (0) or P15 (1), each followed by both lines high
+    fn sgb_write(&mut self, lines: u8) {
+        let previous = self.joypad & 0x30;
+        match (lines, self.packet_bits) {
+            (0x00, _) => {
+                self.packet_bits = Some(0);
+                self.packet = [0; 16];
+            }
+            (0x10 | 0x20, Some(bits)) if previous == 0x30 => {
+                if bits == PACKET_BITS {
+                    self.packet_bits = None;
+                    self.packet_received();
+                } else {
+                    if lines == 0x10 {
+                        self.packet[bits / 8] |= 1 << (bits % 8);
+                    }
+                    self.packet_bits = Some(bits + 1);
+                }
+            }
+            // P15 going high selects the next controller
+            (0x30, None) if previous & 0x20 == 0 && self.players > 1 => {
+                self.player = (self.player + 1) % self.players;
+            }
+            _ => (),
+        }
+    }
+
+    fn packet_received(&mut self) {
+        if self.packets_left == 0 {
+            self.packets_left = (self.packet[0] & 0x07).max(1);
+            self.command.clear();
+        }
+        self.command.extend_from_slice(&self.packet);
+        self.packets_left -= 1;
+        if self.packets_left > 0 {
+            return;
+        }
+
+        let command = std::mem::take(&mut self.command);
+        trace!("SGB command: {:x?}", command);
+        if command[0] >> 3 == MLT_REQ {
+            self.players = match command[1] & 0x03 {
+                1 => 2,
+                3 => 4,
+                _ => 1,
+            };
+            self.player = 0;
+        }
+        self.completed_command = Some(command);
+    }
+
+    /// The last SGB command fully received
+    pub fn take_sgb_command(&mut self) -> Option<Vec<u8>> {
+        self.completed_command.take()
+    }
+
     fn buttons_selected(&self) -> bool {
         // flipped semantics
         (self.joypad & (1 << 5)) == 0
@@ -83,16 +230,110 @@ impl Joypad {
         self.joypad & (1 << 4) == 0
     }
 
-    pub fn key_pressed(&mut self, pressed_keys: Option<Vec<minifb::Key>>) {
-        if let Some(keys) = pressed_keys {
-            self.keys = keys
-        }
+    pub fn set_pressed(&mut self, buttons: Vec<Button>) {
+        self.pressed = buttons
+            .iter()
+            .fold(0, |pressed, button| pressed | button.bit());
     }
 
-    pub fn new() -> Self {
+    pub fn new(sgb: bool) -> Self {
         Joypad {
             joypad: 0xcf,
-            keys: Vec::new(),
+            pressed: 0,
+
+            sgb,
+            packet_bits: None,
+            packet: [0; 16],
+            command: Vec::new(),
+            packets_left: 0,
+            completed_command: None,
+            players: 1,
+            player: 0,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Button, Joypad, REGISTER_LOCATION};
+
+    #[test]
+    fn reads_the_selected_buttons() {
+        let mut joypad = Joypad::new(false);
+        joypad.set_pressed(vec![Button::A, Button::Start, Button::Left]);
+        joypad.write(REGISTER_LOCATION, 0x10);
+        assert_eq!(joypad.get(REGISTER_LOCATION) & 0x0f, 0b0110);
+        joypad.write(REGISTER_LOCATION, 0x20);
+        assert_eq!(joypad.get(REGISTER_LOCATION) & 0x0f, 0b1101);
+        joypad.write(REGISTER_LOCATION, 0x30);
+        assert_eq!(joypad.get(REGISTER_LOCATION) & 0x0f, 0x0f);
+
+        joypad.set_pressed(vec![]);
+        joypad.write(REGISTER_LOCATION, 0x10);
+        assert_eq!(joypad.get(REGISTER_LOCATION) & 0x0f, 0x0f);
+        assert_eq!(Button::parse("select"), Some(Button::Select));
+    }
+
+    fn send_packet(joypad: &mut Joypad, packet: &[u8; 16]) {
+        joypad.write(REGISTER_LOCATION, 0x00);
+        joypad.write(REGISTER_LOCATION, 0x30);
+        for i in 0..128 {
+            let bit = packet[i / 8] >> (i % 8) & 1;
+            joypad.write(REGISTER_LOCATION, if bit == 1 { 0x10 } else { 0x20 });
+            joypad.write(REGISTER_LOCATION, 0x30);
+        }
+        // stop bit
+        joypad.write(REGISTER_LOCATION, 0x20);
+        joypad.write(REGISTER_LOCATION, 0x30);
+    }
+
+    #[test]
+    fn decodes_sgb_packets() {
+        let mut joypad = Joypad::new(true);
+        let mut packet = [0; 16];
+        packet[0] = 0x0b << 3 | 1;
+        packet[1] = 0xa5;
+        packet[15] = 0x80;
+        send_packet(&mut joypad, &packet);
+        assert_eq!(joypad.take_sgb_command(), Some(packet.to_vec()));
+        assert_eq!(joypad.take_sgb_command(), None);
+    }
+
+    #[test]
+    fn joins_multi_packet_commands() {
+        let mut joypad = Joypad::new(true);
+        let mut first = [0; 16];
+        first[0] = 0x04 << 3 | 2;
+        send_packet(&mut joypad, &first);
+        assert_eq!(joypad.take_sgb_command(), None);
+
+        send_packet(&mut joypad, &[1; 16]);
+        let command = joypad.take_sgb_command().unwrap();
+        assert_eq!(command.len(), 32);
+        assert_eq!(command[16], 1);
+    }
+
+    #[test]
+    fn mlt_req_cycles_players() {
+        let mut joypad = Joypad::new(true);
+        let mut packet = [0; 16];
+        packet[0] = 0x11 << 3 | 1;
+        packet[1] = 0x01;
+        send_packet(&mut joypad, &packet);
+
+        assert_eq!(joypad.get(REGISTER_LOCATION) & 0x0f, 0x0f);
+        joypad.write(REGISTER_LOCATION, 0x10);
+        joypad.write(REGISTER_LOCATION, 0x30);
+        assert_eq!(joypad.get(REGISTER_LOCATION) & 0x0f, 0x0e);
+        joypad.write(REGISTER_LOCATION, 0x10);
+        joypad.write(REGISTER_LOCATION, 0x30);
+        assert_eq!(joypad.get(REGISTER_LOCATION) & 0x0f, 0x0f);
+    }
+
+    #[test]
+    fn ignores_packets_on_dmg() {
+        let mut joypad = Joypad::new(false);
+        send_packet(&mut joypad, &[0x11 << 3 | 1; 16]);
+        assert_eq!(joypad.take_sgb_command(), None);
+    }
+}
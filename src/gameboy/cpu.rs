@@ -297,6 +297,8 @@ pub fn get_ticks(instruction: u8) -> u32 {
         0x27 => 4,
         // HALT
         0x76 => 4,
+        // STOP
+        0x10 => 4,
 
         0xcb => panic!("cb cycles not supported"),
 
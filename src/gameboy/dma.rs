@@ -0,0 +1,191 @@
+use log::{debug, trace};
+
+use super::memory_bus::MemoryAccessor;
+
+pub const REGISTER_LOCATION: usize = 0xff46;
+
+/// Number of bytes copied into OAM by a transfer
+const TRANSFER_LENGTH: usize = 0xa0;
+
+/// OAM DMA controller
+///
+/// Writing to FF46 copies `XX00-XX9F` into OAM, one byte per M-cycle. The
+/// first byte is copied on the second M-cycle after the write, and a transfer
+/// can be restarted at any time, in which case the old one keeps running until
+/// the new one starts.
+///
+/// While a transfer is running the CPU can only use HRAM and the I/O registers:
+/// OAM reads as 0xFF and any access on the bus the transfer is reading from
+/// sees the byte being copied instead.
+pub struct OamDma {
+    /// FF46
+    register: u8,
+
+    /// Source of the running transfer
+    source: usize,
+    /// Next byte to be copied by the running transfer
+    index: usize,
+    active: bool,
+
+    /// Written to FF46 during the last M-cycle
+    requested: Option<usize>,
+    /// Starting on the next M-cycle
+    pending: Option<usize>,
+
+    /// Last byte read by the transfer. This is what the CPU sees on conflicts.
+    last_byte: u8,
+}
+
+impl OamDma {
+    /// Advances the controller by one M-cycle, before the CPU access of that
+    /// cycle.
+    ///
+    /// Returns the source location and OAM offset of the byte that has to be
+    /// copied during this cycle, if any.
+    pub fn step(&mut self) -> Option<(usize, usize)> {
+        // the last byte stays on the bus for the cycle it is copied in
+        if self.active && self.index == TRANSFER_LENGTH {
+            trace!("OAM DMA completed");
+            self.active = false;
+        }
+
+        let starting = self.pending.take();
+        self.pending = self.requested.take();
+        if let Some(source) = starting {
+            debug!("Starting OAM DMA from {:#x}", source);
+            self.source = source;
+            self.index = 0;
+            self.active = true;
+        }
+
+        if !self.active {
+            return None;
+        }
+
+        let index = self.index;
+        self.index += 1;
+        Some((self.source + index, index))
+    }
+
+    pub fn transferred(&mut self, value: u8) {
+        self.last_byte = value;
+    }
+
+    /// Returns the value the CPU sees when accessing `location` while a
+    /// transfer is running, or None if the access is not affected.
+    pub fn conflict(&self, location: usize) -> Option<u8> {
+        if !self.active {
+            return None;
+        }
+
+        match location {
+            0xff00..=0xffff => None,
+            0xfe00..=0xfeff => Some(0xff),
+            _ if is_vram_bus(location) == is_vram_bus(self.source) => Some(self.last_byte),
+            _ => None,
+        }
+    }
+
+    pub fn new() -> Self {
+        OamDma {
+            register: 0xff,
+            source: 0,
+            index: 0,
+            active: false,
+            requested: None,
+            pending: None,
+            last_byte: 0xff,
+        }
+    }
+}
+
+/// The DMG has separate buses for VRAM and for everything else (ROM, WRAM and
+/// external RAM). A transfer only conflicts with accesses on its own bus.
+fn is_vram_bus(location: usize) -> bool {
+    (0x8000..=0x9fff).contains(&location)
+}
+
+impl MemoryAccessor for OamDma {
+    fn get(&self, location: usize) -> u8 {
+        match location {
+            REGISTER_LOCATION => self.register,
+            _ => panic!("dma location read: {:#x}", location),
+        }
+    }
+
+    fn write(&mut self, location: usize, value: u8) {
+        match location {
+            REGISTER_LOCATION => {
+                debug!("Requesting OAM DMA transfer from {:#x}", value);
+                self.register = value;
+                self.requested = Some((value as usize) << 8);
+            }
+            _ => panic!("dma location write: {:#x} - {:#x}", location, value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OamDma;
+    use crate::gameboy::memory_bus::MemoryAccessor;
+
+    fn start(dma: &mut OamDma, value: u8) {
+        dma.write(0xff46, value);
+    }
+
+    #[test]
+    fn transfer_starts_after_delay() {
+        let mut dma = OamDma::new();
+        start(&mut dma, 0xc0);
+
+        // the cycle after the write
+        assert_eq!(dma.step(), None);
+        assert_eq!(dma.conflict(0xfe00), None);
+
+        assert_eq!(dma.step(), Some((0xc000, 0)));
+        assert_eq!(dma.conflict(0xfe00), Some(0xff));
+    }
+
+    #[test]
+    fn transfer_takes_160_cycles() {
+        let mut dma = OamDma::new();
+        start(&mut dma, 0xc0);
+        dma.step();
+
+        for i in 0..0xa0 {
+            assert_eq!(dma.step(), Some((0xc000 + i, i)));
+        }
+        assert_eq!(dma.conflict(0xfe00), Some(0xff));
+        assert_eq!(dma.step(), None);
+        assert_eq!(dma.conflict(0xfe00), None);
+    }
+
+    #[test]
+    fn restart_keeps_old_transfer_during_delay() {
+        let mut dma = OamDma::new();
+        start(&mut dma, 0xc0);
+        dma.step();
+        dma.step();
+        dma.step();
+
+        start(&mut dma, 0xd0);
+        assert_eq!(dma.step(), Some((0xc002, 2)));
+        assert_eq!(dma.step(), Some((0xd000, 0)));
+    }
+
+    #[test]
+    fn conflicts_only_on_same_bus() {
+        let mut dma = OamDma::new();
+        start(&mut dma, 0xc0);
+        dma.step();
+        dma.step();
+        dma.transferred(0x42);
+
+        assert_eq!(dma.conflict(0x0150), Some(0x42));
+        assert_eq!(dma.conflict(0xc123), Some(0x42));
+        assert_eq!(dma.conflict(0x8000), None);
+        assert_eq!(dma.conflict(0xff80), None);
+        assert_eq!(dma.conflict(0xff46), None);
+    }
+}
@@ -0,0 +1,449 @@
+use std::{fs, path::Path};
+
+use log::info;
+
+use super::{
+    cartridge, interrupts, AudioSink, Button, Command, Frame, FrameSink, GameBoy, InputSource,
+    DEFAULT_SAMPLE_RATE,
+};
+
+const HEADER_SIZE: usize = 0x70;
+/// Where the driver starts, after the cartridge header
+const DRIVER: usize = 0x150;
+
+const TRACK_COLOR: u32 = 0xa0a0a0;
+const CURRENT_TRACK_COLOR: u32 = 0x000000;
+/// Squares of 4 pixels 5 apart after a 2 pixels margin, as many as fit in 160
+const TRACKS_PER_ROW: usize = 31;
+const WAVE_COLOR: u32 = 0x306850;
+
+/// A GBS file: the sound driver and music data of a game, with the addresses
+/// of its routines
+///
+/// The data is loaded at `load`, `init` is called once with the track number
+/// in A, then `play` at the rate given by the timer registers, or on every
+/// VBlank.
+pub struct Gbs {
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+    pub tracks: u8,
+    /// 0-based
+    pub first_track: u8,
+    load: u16,
+    init: u16,
+    play: u16,
+    stack: u16,
+    /// TMA and TAC, bit 2 of TAC picking the timer over VBlank and bit 7
+    /// the CGB double speed
+    tma: u8,
+    tac: u8,
+    data: Vec<u8>,
+}
+
+fn header_string(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .take_while(|&&c| c != 0)
+        .map(|&c| char::from(c))
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+impl Gbs {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let bytes = fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Self::parse(&bytes)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() <= HEADER_SIZE || &bytes[..3] != b"GBS" {
+            return Err("not a GBS file".to_string());
+        }
+        if bytes[3] != 1 {
+            return Err(format!("unsupported GBS version {}", bytes[3]));
+        }
+        let word = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+        let gbs = Gbs {
+            title: header_string(&bytes[0x10..0x30]),
+            author: header_string(&bytes[0x30..0x50]),
+            copyright: header_string(&bytes[0x50..0x70]),
+            tracks: bytes[4],
+            first_track: bytes[5].saturating_sub(1),
+            load: word(0x06),
+            init: word(0x08),
+            play: word(0x0a),
+            stack: word(0x0c),
+            tma: bytes[0x0e],
+            tac: bytes[0x0f],
+            data: bytes[HEADER_SIZE..].to_vec(),
+        };
+        if gbs.tracks == 0 {
+            return Err("no tracks".to_string());
+        }
+        // the driver lives below
+        if !(0x400..0x8000).contains(&gbs.load) {
+            return Err(format!("load address {:#06x} out of range", gbs.load));
+        }
+        Ok(gbs)
+    }
+
+    fn double_speed(&self) -> bool {
+        self.tac & 0x80 > 0
+    }
+
+    fn timer_driven(&self) -> bool {
+        self.tac & 0x04 > 0
+    }
+
+    /// Calls of the play routine per second
+    pub fn play_rate(&self) -> f64 {
+        if !self.timer_driven() {
+            return 4194304.0 / 70224.0;
+        }
+        let clock = [4096.0, 262144.0, 65536.0, 16384.0][self.tac as usize & 0x03];
+        let speed = if self.double_speed() { 2.0 } else { 1.0 };
+        clock * speed / (256 - self.tma as u32) as f64
+    }
+
+    /// The data at the load address, with the driver below: RST vectors
+    /// jumping to the load address, interrupt vectors calling the play
+    /// routine and the code setting up the timer and calling init
+    fn rom(&self, track: u8) -> Vec<u8> {
+        let load = self.load as usize;
+        let mut rom = vec![0; load + self.data.len()];
+        rom[load..].copy_from_slice(&self.data);
+
+        for vector in (0..0x40).step_by(8) {
+            let [low, high] = (self.load + vector as u16).to_le_bytes();
+            rom[vector..vector + 3].copy_from_slice(&[0xc3, low, high]);
+        }
+        for vector in (0x40..=0x60).step_by(8) {
+            // RETI
+            rom[vector] = 0xd9;
+        }
+        let [low, high] = self.play.to_le_bytes();
+        let vector = if self.timer_driven() { 0x50 } else { 0x40 };
+        // CALL play; RETI
+        rom[vector..vector + 4].copy_from_slice(&[0xcd, low, high, 0xd9]);
+
+        // NOP; JP driver
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0xc3, DRIVER as u8, (DRIVER >> 8) as u8]);
+        let title = self.title.as_bytes();
+        let length = title.len().min(15);
+        rom[0x134..0x134 + length].copy_from_slice(&title[..length]);
+        rom[0x143] = if self.double_speed() { 0x80 } else { 0x00 };
+
+        let [sp_low, sp_high] = self.stack.to_le_bytes();
+        // DI; LD SP,stack
+        let mut driver = vec![0xf3, 0x31, sp_low, sp_high];
+        if self.double_speed() {
+            // LD A,1; LDH (KEY1),A; STOP
+            driver.extend([0x3e, 0x01, 0xe0, 0x4d, 0x10, 0x00]);
+        }
+        let [init_low, init_high] = self.init.to_le_bytes();
+        let enabled = if self.timer_driven() {
+            interrupts::TIMER
+        } else {
+            interrupts::VBLANK
+        };
+        #[rustfmt::skip]
+        let start = [
+            0x3e, self.tma, 0xe0, 0x06, // LD A,tma; LDH (TMA),A
+            0x3e, self.tac & 0x07, 0xe0, 0x07, // LD A,tac; LDH (TAC),A
+            0x3e, track, 0xcd, init_low, init_high, // LD A,track; CALL init
+            0xaf, 0xe0, 0x0f, // XOR A; LDH (IF),A
+            0x3e, enabled, 0xe0, 0xff, // LD A,enabled; LDH (IE),A
+            0xfb, 0x76, 0x18, 0xfd, // EI; HALT; JR -3
+        ];
+        driver.extend(start);
+        rom[DRIVER..DRIVER + driver.len()].copy_from_slice(&driver);
+        rom
+    }
+}
+
+/// Plays the tracks of a GBS file on a console running its driver.
+///
+/// Right and Left switch to the next and previous track, A restarts the
+/// current one. The frames show the tracks, the current one in black, and
+/// the waveform of the last frame.
+pub struct GbsPlayer {
+    gbs: Gbs,
+    track: u8,
+    gb: GameBoy,
+    sample_rate: u32,
+    muted_channels: [bool; 4],
+    frame: Frame,
+    frame_sink: Option<Box<dyn FrameSink>>,
+    input: Option<Box<dyn InputSource>>,
+    audio_sink: Option<Box<dyn AudioSink>>,
+    /// Buttons held at the last frame, tracks change on presses
+    held: Vec<Button>,
+    /// Samples waiting for `take_samples` when there is no audio sink
+    samples: Vec<(i16, i16)>,
+}
+
+impl GbsPlayer {
+    /// Starts playing the first track
+    pub fn new(gbs: Gbs) -> Self {
+        info!(
+            "{} - {} ({}), {} tracks, played at {:.2}Hz",
+            gbs.title,
+            gbs.author,
+            gbs.copyright,
+            gbs.tracks,
+            gbs.play_rate()
+        );
+        let track = gbs.first_track.min(gbs.tracks - 1);
+        info!("Track {}/{}", track + 1, gbs.tracks);
+        let gb = GameBoy::with_cartridge(cartridge::gbs(gbs.rom(track)));
+        GbsPlayer {
+            gbs,
+            track,
+            gb,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            muted_channels: [false; 4],
+            frame: Frame::new(),
+            frame_sink: None,
+            input: None,
+            audio_sink: None,
+            held: Vec::new(),
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn gbs(&self) -> &Gbs {
+        &self.gbs
+    }
+
+    /// 0-based
+    pub fn track(&self) -> u8 {
+        self.track
+    }
+
+    /// Restarts the console on `track`, wrapping around the track count
+    pub fn play_track(&mut self, track: u8) {
+        self.track = track % self.gbs.tracks;
+        self.gb = GameBoy::with_cartridge(cartridge::gbs(self.gbs.rom(self.track)));
+        self.gb.set_sample_rate(self.sample_rate);
+        for (channel, muted) in self.muted_channels.iter().enumerate() {
+            self.gb.set_channel_muted(channel, *muted);
+        }
+        info!("Track {}/{}", self.track + 1, self.gbs.tracks);
+    }
+
+    /// Runs a frame of the console, about 1/60s of music
+    pub fn run_frame(&mut self) -> &Frame {
+        self.gb.run_frame();
+        let samples = self.gb.take_samples();
+        self.draw(&samples);
+
+        if let Some(sink) = self.audio_sink.as_mut() {
+            sink.play(&samples);
+        } else {
+            self.samples.extend(samples);
+            let max = self.sample_rate as usize;
+            if self.samples.len() > max {
+                self.samples.drain(..self.samples.len() - max);
+            }
+        }
+        if let Some(sink) = self.frame_sink.as_mut() {
+            sink.present(&self.frame);
+        }
+        self.poll_input();
+        &self.frame
+    }
+
+    fn poll_input(&mut self) {
+        let Some(input) = self.input.as_mut() else {
+            return;
+        };
+        let pressed = input.pressed_buttons();
+        let commands = input.commands();
+        let newly_pressed = |button| pressed.contains(&button) && !self.held.contains(&button);
+        let track = if newly_pressed(Button::Right) {
+            Some(self.track + 1)
+        } else if newly_pressed(Button::Left) {
+            Some(self.track.checked_sub(1).unwrap_or(self.gbs.tracks - 1))
+        } else if newly_pressed(Button::A) {
+            Some(self.track)
+        } else {
+            None
+        };
+        self.held = pressed;
+        if let Some(track) = track {
+            self.play_track(track);
+        }
+
+        for command in commands {
+            if let Command::ToggleChannel(channel) = command {
+                self.muted_channels[channel] = !self.muted_channels[channel];
+                info!(
+                    "Audio channel {} muted: {}",
+                    channel + 1,
+                    self.muted_channels[channel]
+                );
+                self.gb
+                    .set_channel_muted(channel, self.muted_channels[channel]);
+            }
+        }
+    }
+
+    /// A row of squares for the tracks, and the mix of `samples` below
+    fn draw(&mut self, samples: &[(i16, i16)]) {
+        let frame = &mut self.frame;
+        let width = frame.width;
+        frame.pixels.fill(0xffffff);
+        frame.number = self.gb.frame_count();
+
+        for track in 0..self.gbs.tracks as usize {
+            let (x, y) = (
+                2 + (track % TRACKS_PER_ROW) * 5,
+                2 + (track / TRACKS_PER_ROW) * 5,
+            );
+            let color = if track == self.track as usize {
+                CURRENT_TRACK_COLOR
+            } else {
+                TRACK_COLOR
+            };
+            for dy in 0..4 {
+                let start = (y + dy) * width + x;
+                if let Some(row) = frame.pixels.get_mut(start..start + 4) {
+                    row.fill(color);
+                }
+            }
+        }
+
+        if samples.is_empty() {
+            return;
+        }
+        let (center, amplitude) = (frame.height as i32 - 48, 40);
+        let mut previous = None;
+        for x in 0..width {
+            let (left, right) = samples[x * samples.len() / width];
+            let mix = (left as i32 + right as i32) / 2;
+            let y = center - mix * amplitude / i16::MAX as i32;
+            let (top, bottom) = match previous {
+                Some(previous) => (y.min(previous), y.max(previous)),
+                None => (y, y),
+            };
+            for y in top..=bottom {
+                frame.pixels[y as usize * width + x] = WAVE_COLOR;
+            }
+            previous = Some(y);
+        }
+    }
+
+    /// Stereo samples played since the last call, see `GameBoy::take_samples`
+    pub fn take_samples(&mut self) -> Vec<(i16, i16)> {
+        std::mem::take(&mut self.samples)
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.gb.set_sample_rate(sample_rate);
+        self.sample_rate = self.gb.sample_rate();
+        if let Some(sink) = self.audio_sink.as_mut() {
+            sink.set_sample_rate(self.sample_rate);
+        }
+    }
+
+    pub fn set_audio_sink(&mut self, mut sink: Box<dyn AudioSink>) {
+        sink.set_sample_rate(self.sample_rate);
+        self.audio_sink = Some(sink);
+    }
+
+    /// Shows the frames in and reads the buttons from the same frontend
+    pub fn set_frontend<F: FrameSink + InputSource + Clone + 'static>(&mut self, frontend: F) {
+        self.frame_sink = Some(Box::new(frontend.clone()));
+        self.input = Some(Box::new(frontend));
+    }
+
+    /// Plays forever, see `set_frontend`
+    pub fn start(&mut self) {
+        loop {
+            self.run_frame();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Gbs, GbsPlayer};
+
+    /// A GBS file with `code` at 0x400: init at 0x400, play at 0x410
+    fn gbs_file(tac: u8, code: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0; 0x70];
+        bytes[..4].copy_from_slice(b"GBS\x01");
+        bytes[4] = 3;
+        bytes[5] = 2;
+        bytes[6..14].copy_from_slice(&[0x00, 0x04, 0x00, 0x04, 0x10, 0x04, 0xfe, 0xff]);
+        bytes[0x0e] = 0xc0;
+        bytes[0x0f] = tac;
+        bytes[0x10..0x15].copy_from_slice(b"Tunes");
+        bytes.extend_from_slice(code);
+        bytes
+    }
+
+    #[test]
+    fn parses_the_header() {
+        let gbs = Gbs::parse(&gbs_file(0x04, &[0xc9])).unwrap();
+        assert_eq!(gbs.title, "Tunes");
+        assert_eq!((gbs.tracks, gbs.first_track), (3, 1));
+        assert_eq!(
+            (gbs.load, gbs.init, gbs.play, gbs.stack),
+            (0x400, 0x400, 0x410, 0xfffe)
+        );
+        // 4096Hz / 64
+        assert_eq!(gbs.play_rate(), 64.0);
+
+        let vblank = Gbs::parse(&gbs_file(0x00, &[0xc9])).unwrap();
+        assert!((vblank.play_rate() - 59.73).abs() < 0.01);
+
+        assert!(Gbs::parse(b"GBR\x01").is_err());
+        let mut low = gbs_file(0x00, &[0xc9]);
+        low[7] = 0x03;
+        assert!(Gbs::parse(&low).is_err());
+    }
+
+    #[test]
+    fn driver_calls_init_and_play() {
+        let gbs = Gbs::parse(&gbs_file(0x04, &[0xc9])).unwrap();
+        let rom = gbs.rom(2);
+        // RST 38 jumps to the load address + 0x38
+        assert_eq!(rom[0x38..0x3b], [0xc3, 0x38, 0x04]);
+        // the timer interrupt calls play
+        assert_eq!(rom[0x50..0x54], [0xcd, 0x10, 0x04, 0xd9]);
+        assert_eq!(rom[0x40], 0xd9);
+        assert_eq!(rom[0x100..0x104], [0x00, 0xc3, 0x50, 0x01]);
+        assert_eq!(rom[0x400], 0xc9);
+    }
+
+    #[test]
+    fn plays_at_the_timer_rate() {
+        // init: LD ($C001),A; RET
+        let mut code = vec![0xea, 0x01, 0xc0, 0xc9];
+        code.resize(0x10, 0);
+        // play: LD HL,$C000; INC (HL); RET
+        code.extend([0x21, 0x00, 0xc0, 0x34, 0xc9]);
+        let mut player = GbsPlayer::new(Gbs::parse(&gbs_file(0x04, &code)).unwrap());
+        assert_eq!(player.track(), 1);
+
+        for _ in 0..60 {
+            player.run_frame();
+        }
+        // 64Hz for a bit less than a second, the first frame being shorter
+        let calls = player.gb.bus_read(0xc000);
+        assert!((60..=65).contains(&calls), "{}", calls);
+        assert_eq!(player.gb.bus_read(0xc001), 1);
+
+        player.play_track(5);
+        player.run_frame();
+        assert_eq!(player.track(), 2);
+        assert_eq!(player.gb.bus_read(0xc001), 2);
+    }
+}
@@ -1,234 +1,593 @@
+mod color_palette;
+mod debug_layers;
+mod dmg_palette;
 pub(crate) mod engine;
+mod fetcher;
+mod post_process;
 mod processor;
+mod sgb;
 mod tile;
-mod window;
+mod vram_view;
+
+use std::collections::VecDeque;
 
 use super::memory_bus::MemoryAccessor;
 use crate::gameboy::interrupts;
-pub use engine::Buffer;
+use color_palette::ColorPalettes;
+pub use debug_layers::{DebugLayer, DebugLayers};
+pub use dmg_palette::DmgPalettes;
+use dmg_palette::{Layer, PRESETS};
+use engine::Pixel;
+pub use engine::{Buffer, Frame};
+use fetcher::{BgPixel, Fetcher, FetcherStep, ObjectPixel};
 use log::{debug, info, trace};
+pub use post_process::{Image, PostProcessing, FILTER_PRESETS};
 pub use processor::Mode;
 pub use processor::Processor;
+use sgb::{Sgb, SGB_HEIGHT, SGB_WIDTH};
 pub use tile::Tile;
-use window::{FakeScreen, Screen};
+pub use vram_view::{TilePalette, VramView};
+
+pub const WIDTH: usize = 160;
+pub const HEIGHT: usize = 144;
+
+/// Receives the frames as they are completed, e.g. to show them in a window
+pub trait FrameSink {
+    fn present(&mut self, frame: &Frame);
+}
+
+/// Dots (T-cycles) per scanline
+const LINE_DOTS: u32 = 456;
+/// Length of the OAM scan (mode 2)
+const OAM_SCAN_DOTS: u32 = 80;
+/// The first tile fetched on every line is thrown away
+const FIRST_FETCH_DOTS: u8 = 6;
+/// Dots needed to fetch a sprite row once the background fetcher is ready
+const SPRITE_FETCH_DOTS: u8 = 6;
+/// The first line after the LCD is turned on is shorter
+const LCD_ON_SKIPPED_DOTS: u32 = 4;
+/// OAM is scanned one row (8 bytes, 2 objects) every 4 dots
+const OAM_ROW_DOTS: u32 = 4;
+/// Size of a VRAM bank's tile data and tile maps
+const TILE_DATA_SIZE: usize = 0x1800;
+const TILE_MAPS_SIZE: usize = 0x800;
+/// Dots of a whole frame, VBlank included
+const FRAME_DOTS: u32 = LINE_DOTS * 154;
+/// LY reads 153 only for the first dots of the last line, then 0
+const LAST_LINE_LY_DOTS: u32 = 4;
 
 pub struct Display {
     engine: Buffer,
-    window: Box<dyn window::DrawingWindow>,
+    /// The last completed frame
+    frame: Frame,
+    frame_completed: bool,
     processor: Processor,
 
+    /// Both VRAM banks, bank 1 only being used on CGB
     tile_data: Vec<u8>,
+    /// Tile maps in bank 0, BG map attributes in bank 1
     tile_maps: Vec<u8>,
     pub oam: Vec<u8>,
 
+    cgb: bool,
+    /// FF4F (VBK), CGB only
+    vram_bank: usize,
+    /// FF6C (OPRI), CGB only. Bit 0 cleared: objects are prioritised by OAM
+    /// index instead of X coordinate.
+    object_priority: u8,
+    palettes: ColorPalettes,
+    /// Super Game Boy colorization and border
+    sgb: Option<Sgb>,
+    /// Position in `PRESETS` of the DMG palette, None for a custom one
+    palette_preset: Option<usize>,
+    layers: DebugLayers,
+
+    /// Dots since the start of the current line
     dots: u32,
     gpu_mode: Mode,
     interrupt: u8,
+
+    // Pixel transfer (mode 3)
+    fetcher: Fetcher,
+    bg_fifo: VecDeque<BgPixel>,
+    obj_fifo: VecDeque<ObjectPixel>,
+    /// Next pixel to be drawn on the line
+    lx: u8,
+    /// Pixels still to be thrown away (SCX fine scroll)
+    discard: u8,
+    /// Dots left in the dummy fetch at the start of the line
+    first_fetch: u8,
+    /// Objects on the current line, taken out once fetched
+    line_sprites: Vec<(u8, Tile)>,
+    /// Object being fetched (with its OAM index) and dots spent on it
+    sprite_fetch: Option<((u8, Tile), u8)>,
+    /// WY matched LY at some point during this frame
+    window_y_triggered: bool,
+    window_on_line: bool,
+
+    lcd_on: bool,
+    /// STOP mode, the PPU is halted and the screen blank
+    stopped: bool,
+    /// The first frame after the LCD is turned on is not shown
+    skip_frame: bool,
+    /// Dots since the last frame while the LCD is off
+    off_dots: u32,
+    /// Mode 0 started since the last call to `take_hblank_started`
+    hblank_started: bool,
 }
 
 impl Display {
-    pub fn gpu_step(&mut self, dots: u32) -> (u8, Option<Vec<minifb::Key>>) {
+    pub fn gpu_step(&mut self, dots: u32) -> u8 {
         self.interrupt = 0;
-        if !self.processor.lcd_enabled() {
+        if self.processor.lcd_enabled() != self.lcd_on {
+            if self.lcd_on {
+                self.turn_off();
+            } else {
+                self.turn_on();
+            }
+        }
+        if !self.lcd_on || self.stopped {
             trace!("LCD disabled!");
-            self.dots = 0;
-            self.processor.ly = 0;
-            self.set_gpu_mode(Mode::Two);
-            return (self.interrupt, None);
+            // frames keep coming, showing a blank screen
+            self.off_dots += dots;
+            if self.off_dots >= FRAME_DOTS {
+                self.off_dots -= FRAME_DOTS;
+                self.end_frame(true);
+            }
+            return self.interrupt;
+        }
+
+        for _ in 0..dots {
+            self.dot();
+        }
+        if self.processor.take_stat_interrupt() {
+            self.interrupt |= interrupts::STAT;
         }
-        self.dots += dots;
+        self.interrupt
+    }
 
-        let mut pressed_keys = None;
+    /// Advances the PPU by a single dot
+    fn dot(&mut self) {
+        self.dots += 1;
 
         match self.gpu_mode {
             Mode::Two => {
-                let _line = self.processor.ly;
-                if self.dots >= 80 {
-                    // scan pixels TODO ideally I should follow the ticks, not do it at once
-                    self.dots -= 80;
+                if self.dots == OAM_SCAN_DOTS {
                     self.set_gpu_mode(Mode::Three);
                 }
             }
-            Mode::One => {
-                if self.dots >= 456 {
-                    self.processor.ly += 1;
-                    self.dots -= 456;
-                    if self.processor.should_trigger_lyc_stat_interrupt() {
-                        self.interrupt |= interrupts::STAT;
-                        println!(
-                            "todo: check and enable interrupt - lyc - One {}-{}",
-                            self.processor.lyc, self.processor.ly
-                        )
-                    }
-
-                    if self.processor.ly > 153 {
-                        self.processor.ly = 0;
-                        self.set_gpu_mode(Mode::Two);
-                    }
+            Mode::Three => {
+                self.pixel_transfer_dot();
+                if self.lx == WIDTH as u8 {
+                    self.set_gpu_mode(Mode::Zero);
                 }
             }
             Mode::Zero => {
-                if self.dots >= 204 {
-                    self.dots -= 204;
-
+                if self.dots == LINE_DOTS {
+                    self.dots = 0;
                     self.processor.ly += 1;
-                    if self.processor.should_trigger_lyc_stat_interrupt() {
-                        self.interrupt |= interrupts::STAT;
-                        println!(
-                            "todo: check and enable interrupt - lyc - Zero {}-{}",
-                            self.processor.lyc, self.processor.ly
-                        );
-                    }
 
-                    if self.processor.ly == 144 {
+                    if self.processor.ly == HEIGHT as u8 {
                         self.interrupt |= interrupts::VBLANK;
-
-                        self.window.refresh_buffer(&self.engine.screen);
-
-                        let keys = self.window.get_pressed_keys();
-                        pressed_keys = Some(keys);
-
                         self.set_gpu_mode(Mode::One);
+
+                        let shown = !std::mem::take(&mut self.skip_frame);
+                        self.end_frame(shown);
                     } else {
                         self.set_gpu_mode(Mode::Two);
                     }
                 }
             }
-            Mode::Three => {
-                let line = self.processor.ly;
-
-                if self.dots >= 172 {
-                    self.engine.wipe_line(line);
-                    self.draw_background();
-                    self.draw_sprites(line);
+            Mode::One => {
+                if self.processor.ly == 153 && self.dots == LAST_LINE_LY_DOTS {
+                    self.processor.ly = 0;
+                }
+                if self.dots == LINE_DOTS {
+                    self.dots = 0;
 
-                    self.set_gpu_mode(Mode::Zero);
-                    self.dots -= 172;
+                    // LY already went back to 0 during line 153
+                    if self.processor.ly == 0 {
+                        self.set_gpu_mode(Mode::Two);
+                    } else {
+                        self.processor.ly += 1;
+                    }
                 }
             }
         }
-        (self.interrupt, pressed_keys)
+
+        // the mode 2 source also fires when VBlank starts
+        let vblank_start = self.gpu_mode == Mode::One && self.processor.ly == HEIGHT as u8;
+        self.processor
+            .update_stat_line(vblank_start && self.dots == 0);
+    }
+
+    fn turn_off(&mut self) {
+        info!("LCD turned off at LY {}", self.processor.ly);
+        self.lcd_on = false;
+        self.dots = 0;
+        self.processor.ly = 0;
+        self.gpu_mode = Mode::Zero;
+        self.processor.lcd_status &= !3;
+        self.processor.update_stat_line(false);
+
+        self.off_dots = 0;
+        self.engine.wipe_screen();
+    }
+
+    pub fn set_stopped(&mut self, stopped: bool) {
+        if stopped {
+            self.off_dots = 0;
+            self.engine.wipe_screen();
+        }
+        self.stopped = stopped;
+    }
+
+    /// Counts a new frame and, if `shown`, copies it from the screen buffer
+    fn end_frame(&mut self, shown: bool) {
+        self.frame.number += 1;
+        self.frame_completed = true;
+        if shown {
+            self.update_frame();
+        }
+    }
+
+    /// Composes the frame, inside the border with a SGB
+    fn update_frame(&mut self) {
+        let frame = &mut self.frame;
+        frame.shades.copy_from_slice(&self.engine.shades);
+        match self.sgb.as_mut() {
+            Some(sgb) => {
+                frame.pixels.clear();
+                frame
+                    .pixels
+                    .extend_from_slice(sgb.frame_completed(&self.engine.shades));
+                (frame.width, frame.height) = (SGB_WIDTH, SGB_HEIGHT);
+            }
+            None => frame.pixels.copy_from_slice(&self.engine.screen),
+        }
+    }
+
+    /// The first line starts without an OAM scan: STAT reports mode 0 and
+    /// OAM stays accessible until mode 3
+    fn turn_on(&mut self) {
+        info!("LCD turned on");
+        self.lcd_on = true;
+        self.skip_frame = true;
+        self.dots = LCD_ON_SKIPPED_DOTS;
+        self.gpu_mode = Mode::Two;
+        self.window_y_triggered = self.processor.wy == 0;
+        self.processor.win_y_counter = 0;
+        self.processor.update_stat_line(false);
+    }
+
+    /// Mode as seen by the CPU
+    fn stat_mode(&self) -> u8 {
+        self.processor.lcd_status & 0x03
+    }
+
+    fn vram_locked(&self) -> bool {
+        self.stat_mode() == Mode::Three as u8
+    }
+
+    fn oam_locked(&self) -> bool {
+        self.stat_mode() == Mode::Two as u8 || self.stat_mode() == Mode::Three as u8
     }
 
-    fn draw_sprites(&mut self, line: u8) {
-        if !self.processor.is_object_enabled() {
-            trace!("objects are disabled :sadge:");
+    /// OAM corruption bug, triggered by 16-bit increments and decrements of a
+    /// register pointing to OAM while it is being scanned. The row being read
+    /// is mixed with the preceding one.
+    pub fn corrupt_oam(&mut self) {
+        if self.stat_mode() != Mode::Two as u8 {
+            return;
+        }
+        let row = (self.dots / OAM_ROW_DOTS) as usize;
+        if row == 0 || row >= 20 {
             return;
         }
 
+        let word = |oam: &[u8], index: usize| u16::from_le_bytes([oam[index], oam[index + 1]]);
+        let current = row * 8;
+        let previous = current - 8;
+        let a = word(&self.oam, current);
+        let b = word(&self.oam, previous);
+        let c = word(&self.oam, previous + 4);
+
+        let first = ((a ^ c) & (b ^ c)) ^ c;
+        self.oam[current..current + 2].copy_from_slice(&first.to_le_bytes());
+        self.oam
+            .copy_within(previous + 2..previous + 8, current + 2);
+    }
+
+    /// Selects the first 10 objects on the current line, in OAM order
+    fn scan_oam(&mut self) {
+        self.line_sprites.clear();
+        let line = self.processor.ly;
         let double_size = self.processor.is_object_double_size();
 
-        let mut object_counter = 0;
-        let mut previous_x_coordinate = 255;
         for i in 0..40 {
             let tile = self.get_oam_object(i);
-
             if tile.object_in_scanline(line, double_size) {
-                object_counter += 1;
                 debug!("{}: found object {:?}", line, tile);
-                if object_counter > 10 {
-                    trace!("too many sprites on the line. Is it a bug?");
+                self.line_sprites.push((i as u8, tile));
+                if self.line_sprites.len() == 10 {
                     break;
                 }
+            }
+        }
+    }
 
-                // If same X coordinate, the previous has priority
-                if tile.x == previous_x_coordinate {
-                    info!("same x, previous has priority");
-                    // todo!("this is wrong.. only if opaque!")
-                    // continue;
-                }
-                previous_x_coordinate = tile.x;
+    fn start_pixel_transfer(&mut self) {
+        self.scan_oam();
+        self.fetcher = Fetcher::new(false);
+        self.bg_fifo.clear();
+        self.obj_fifo.clear();
+        self.lx = 0;
+        self.discard = self.processor.scx % 8;
+        self.first_fetch = FIRST_FETCH_DOTS;
+        self.sprite_fetch = None;
+        self.window_on_line = false;
+    }
 
-                if tile.x == 0 || tile.x >= 168 {
-                    debug!("sprite's x is outside of bounds. ignoring");
-                    continue;
-                }
+    fn pixel_transfer_dot(&mut self) {
+        if self.first_fetch > 0 {
+            self.first_fetch -= 1;
+            return;
+        }
 
-                let index = if double_size {
-                    if line + 16 - tile.y < 8 {
-                        tile.tile_index & 0xfe
-                    } else {
-                        tile.tile_index | 0x01
-                    }
-                } else {
-                    tile.tile_index
-                };
+        // The background fetcher has to be ready to push before the object
+        // row is fetched. Nothing is drawn in the meantime.
+        if let Some((sprite, dots)) = self.sprite_fetch.take() {
+            if self.fetcher.step != FetcherStep::Push {
+                self.fetcher_dot();
+                self.sprite_fetch = Some((sprite, dots));
+            } else if dots + 1 < SPRITE_FETCH_DOTS {
+                self.sprite_fetch = Some((sprite, dots + 1));
+            } else {
+                self.fetch_sprite(sprite);
+            }
+            return;
+        }
 
-                // if not double size or the top tile for double
-                // let y_pos = if !double_size || line + 16 - tile.y < 8 {
-                //     16 + line as usize - tile.y as usize
-                // } else {
-                //     16 + line as usize - (tile.y + 8) as usize
-                // };
-                let y_pos = 16 + line as usize - tile.y as usize;
-                let final_y_pos = if !tile.is_y_flipped() {
-                    y_pos % 8
-                } else {
-                    // TODO flipped - double is probably broken
-                    7 - (y_pos % 8)
-                };
+        if self.discard == 0 && self.processor.is_object_enabled() {
+            // lowest X first, then lowest OAM index (the scan keeps OAM order)
+            let lx = self.lx;
+            let next = self
+                .line_sprites
+                .iter()
+                .enumerate()
+                .filter(|(_, (_, s))| s.x <= lx + 8)
+                .min_by_key(|(_, (_, s))| s.x)
+                .map(|(index, _)| index);
+            if let Some(index) = next {
+                let sprite = self.line_sprites.remove(index);
+                self.sprite_fetch = Some((sprite, 0));
+                self.pixel_transfer_dot();
+                return;
+            }
+        }
 
-                debug!("line: {} tile.y: {}", line, tile.y);
-                let tile_data = self.get_tile_data(0x8000, index, final_y_pos);
+        if !self.fetcher.window && self.window_starts() {
+            self.window_on_line = true;
+            self.bg_fifo.clear();
+            self.fetcher = Fetcher::new(true);
+            // the window starts left of the screen, its first pixels are hidden
+            self.discard = (7 - self.processor.wx.min(7)).saturating_sub(self.lx);
+        }
 
-                let palette = if (tile.flags & (1 << 4)) > 0 {
-                    self.processor.obp1
-                } else {
-                    self.processor.obp0
-                };
-                self.engine.draw_tile(tile, line, tile_data, palette);
-            }
+        self.fetcher_dot();
+
+        let Some(bg) = self.bg_fifo.pop_front() else {
+            return;
+        };
+        if self.discard > 0 {
+            self.discard -= 1;
+            return;
         }
+        let object = self.obj_fifo.pop_front();
+        self.draw_pixel(bg, object);
+        self.lx += 1;
     }
 
-    fn draw_background(&mut self) {
-        let line = self.processor.ly;
-        if !self.processor.is_bg_window_enabled() {
-            trace!("bg/window is disabled. must draw white :sadge:");
-            // todo we also wipe_line one up. probably uneccessary
-            self.engine.wipe_line(line);
-            return;
+    fn window_starts(&self) -> bool {
+        self.window_y_triggered
+            && self.processor.is_window_enabled()
+            && self.processor.wx <= (WIDTH + 7) as u8
+            && self.lx + 7 >= self.processor.wx
+    }
+
+    fn fetcher_dot(&mut self) {
+        match self.fetcher.step {
+            FetcherStep::Tile => {
+                if self.fetcher.tick() {
+                    let tile_map = self.processor.get_tile_map(self.fetcher.window);
+                    let (column, row) = if self.fetcher.window {
+                        (self.fetcher.x, self.processor.win_y_counter / 8)
+                    } else {
+                        let y = self.processor.scy.wrapping_add(self.processor.ly);
+                        ((self.processor.scx / 8 + self.fetcher.x) & 0x1f, y / 8)
+                    };
+                    let offset = tile_map - 0x9800 + row as usize * 32 + column as usize;
+                    self.fetcher.tile_id = self.tile_maps[offset];
+                    if self.cgb {
+                        self.fetcher.attributes = self.tile_maps[TILE_MAPS_SIZE + offset];
+                    }
+                    self.fetcher.step = FetcherStep::DataLow;
+                }
+            }
+            FetcherStep::DataLow => {
+                if self.fetcher.tick() {
+                    self.fetcher.low = self.fetch_bg_data().0;
+                    self.fetcher.step = FetcherStep::DataHigh;
+                }
+            }
+            FetcherStep::DataHigh => {
+                if self.fetcher.tick() {
+                    self.fetcher.high = self.fetch_bg_data().1;
+                    self.fetcher.step = FetcherStep::Push;
+                }
+            }
+            FetcherStep::Push => {
+                if self.bg_fifo.is_empty() {
+                    let attributes = self.fetcher.attributes;
+                    for x in 0..8 {
+                        let pixel_x = if attributes & (1 << 5) > 0 { 7 - x } else { x };
+                        self.bg_fifo.push_back(BgPixel {
+                            color: fetcher::color_index(
+                                self.fetcher.low,
+                                self.fetcher.high,
+                                pixel_x,
+                            ),
+                            palette: attributes & 0x07,
+                            priority: attributes & (1 << 7) > 0,
+                        });
+                    }
+                    self.fetcher.x = (self.fetcher.x + 1) & 0x1f;
+                    self.fetcher.step = FetcherStep::Tile;
+                }
+            }
         }
+    }
 
-        let wx = self.processor.wx;
-        let wy = self.processor.wy;
-        let in_window =
-            self.processor.is_window_enabled() && line >= wy && wx <= (window::WIDTH + 7) as u8;
+    fn fetch_bg_data(&self) -> (u8, u8) {
+        let row = if self.fetcher.window {
+            self.processor.win_y_counter % 8
+        } else {
+            self.processor.scy.wrapping_add(self.processor.ly) % 8
+        };
+        let attributes = self.fetcher.attributes;
+        let row = if attributes & (1 << 6) > 0 {
+            7 - row
+        } else {
+            row
+        };
+        let bank = ((attributes >> 3) & 1) as usize;
+        let baseline = self.processor.get_tile_data_baseline();
+        self.get_tile_data(bank, baseline, self.fetcher.tile_id, row as usize)
+    }
 
-        for x in 0..160u8 {
-            let in_window = in_window && x + 7 >= wx;
+    /// Fetches a row of the object and mixes it into the object FIFO. Pixels
+    /// already in the FIFO are only replaced if they are transparent, or on
+    /// CGB if the new object comes first in OAM.
+    fn fetch_sprite(&mut self, (oam_index, tile): (u8, Tile)) {
+        let double_size = self.processor.is_object_double_size();
+        let (index, row) = tile.tile_row(self.processor.ly, double_size);
+        let bank = if self.cgb { tile.bank() } else { 0 };
+        let (low, high) = self.get_tile_data(bank, 0x8000, index, row);
+        let oam_priority = self.cgb && self.object_priority & 0x01 == 0;
 
-            let y_pos = if in_window {
-                self.processor.win_y_counter
-            } else {
-                self.processor.scy.wrapping_add(line)
+        // objects partially left of the screen
+        let hidden = (self.lx + 8).saturating_sub(tile.x);
+        for x in hidden..8 {
+            let pixel_x = if tile.is_x_flipped() { 7 - x } else { x };
+            let pixel = ObjectPixel {
+                color: if self.layers.is_object_shown(oam_index) {
+                    fetcher::color_index(low, high, pixel_x)
+                } else {
+                    0
+                },
+                palette: if self.cgb {
+                    tile.cgb_palette()
+                } else {
+                    tile.dmg_palette()
+                },
+                behind_bg: !tile.has_priority(),
+                oam_index,
             };
 
-            // which of the 8 vertical pixels of the current
-            // tile is the scanline on?
-            let tile_row = y_pos / 8;
+            let position = (x - hidden) as usize;
+            match self.obj_fifo.get_mut(position) {
+                Some(existing) if existing.color == 0 => *existing = pixel,
+                Some(existing)
+                    if oam_priority && pixel.color != 0 && oam_index < existing.oam_index =>
+                {
+                    *existing = pixel
+                }
+                Some(_) => (),
+                None => self.obj_fifo.push_back(pixel),
+            }
+        }
+    }
 
-            let tile_map = self.processor.get_tile_map(in_window);
+    fn draw_pixel(&mut self, bg: BgPixel, object: Option<ObjectPixel>) {
+        // the window started on this line if the fetcher is on it
+        let bg_layer = if self.fetcher.window {
+            DebugLayer::Window
+        } else {
+            DebugLayer::Background
+        };
+        let bg = if self.layers.is_shown(bg_layer) {
+            bg
+        } else {
+            BgPixel {
+                color: 0,
+                priority: false,
+                ..bg
+            }
+        };
+        if self.cgb {
+            return self.draw_cgb_pixel(bg, bg_layer, object);
+        }
 
-            // translate the current x pos to window space if necessary
-            let x_pos = if in_window {
-                x + 7 - wx
-            } else {
-                x.wrapping_add(self.processor.scx)
-            };
+        let bg_enabled = self.processor.is_bg_window_enabled();
+        self.engine
+            .set_bg_index(self.lx, if bg_enabled { bg.color } else { 0 }, false);
 
-            let tile_col = x_pos / 8;
+        let (pixel, drawn) = match object {
+            Some(object)
+                if object.color != 0
+                    && self.processor.is_object_enabled()
+                    && !(object.behind_bg && self.engine.bg_index(self.lx) != 0) =>
+            {
+                let (palette, layer) = if object.palette == 1 {
+                    (self.processor.obp1, Layer::Object1)
+                } else {
+                    (self.processor.obp0, Layer::Object0)
+                };
+                let pixel = Pixel::Dmg {
+                    color: object.color,
+                    palette,
+                    layer,
+                };
+                (pixel, DebugLayer::Objects)
+            }
+            _ if !bg_enabled => (Pixel::blank(), bg_layer),
+            _ => {
+                let pixel = Pixel::Dmg {
+                    color: bg.color,
+                    palette: self.processor.bgp,
+                    layer: Layer::Background,
+                };
+                (pixel, bg_layer)
+            }
+        };
+        self.engine.set_pixel(self.lx, pixel, drawn);
+    }
 
-            let tile_id = self.get(tile_map + tile_row as usize * 32 + tile_col as usize);
-            let tile_data_baseline = self.processor.get_tile_data_baseline();
-            let tile_data = self.get_tile_data(tile_data_baseline, tile_id, y_pos as usize % 8);
+    /// On CGB, LCDC.0 cleared gives objects priority over everything, the
+    /// background being still drawn
+    fn draw_cgb_pixel(&mut self, bg: BgPixel, bg_layer: DebugLayer, object: Option<ObjectPixel>) {
+        self.engine.set_bg_index(self.lx, bg.color, bg.priority);
+        let bg_on_top = self.processor.is_bg_window_enabled()
+            && self.engine.bg_index(self.lx) != 0
+            && self.engine.bg_priority(self.lx);
 
-            let palette = self.processor.bgp;
-            self.engine.draw_bg_tile(x_pos, x, line, tile_data, palette);
-        }
-        if in_window {
-            self.processor.win_y_counter += 1;
-        }
+        let (color, drawn) = match object {
+            Some(object)
+                if object.color != 0
+                    && self.processor.is_object_enabled()
+                    && !bg_on_top
+                    && !(object.behind_bg
+                        && self.processor.is_bg_window_enabled()
+                        && bg.color != 0) =>
+            {
+                (
+                    self.palettes.object(object.palette, object.color),
+                    DebugLayer::Objects,
+                )
+            }
+            _ => (self.palettes.background(bg.palette, bg.color), bg_layer),
+        };
+        self.engine.set_pixel(self.lx, Pixel::Cgb(color), drawn);
     }
 
     fn set_gpu_mode(&mut self, mode: Mode) {
@@ -236,14 +595,25 @@ impl Display {
         self.processor.lcd_status &= !3; // wipe 2 first digits
         self.processor.lcd_status |= mode as u8;
 
-        if self.processor.should_trigger_mode_stat_interrupt(mode) {
-            self.interrupt |= interrupts::STAT;
-            println!("todo: check and enable interrupt - mode");
-        }
-
-        if mode == Mode::Two && self.processor.wy == self.processor.ly {
-            // reset window counter
-            self.processor.win_y_counter = 0
+        match mode {
+            Mode::Two => {
+                if self.processor.ly == 0 {
+                    self.window_y_triggered = false;
+                    self.processor.win_y_counter = 0;
+                }
+                if self.processor.wy == self.processor.ly {
+                    self.window_y_triggered = true;
+                }
+            }
+            Mode::Three => self.start_pixel_transfer(),
+            Mode::Zero => {
+                self.hblank_started = true;
+                self.engine.finish_line(self.processor.ly);
+                if self.window_on_line {
+                    self.processor.win_y_counter += 1;
+                }
+            }
+            Mode::One => (),
         }
     }
 
@@ -255,35 +625,112 @@ impl Display {
         Tile::new(y, x, tile_index, flags)
     }
 
-    pub fn get_tile_data(&self, baseline: usize, id: u8, row: usize) -> (u8, u8) {
+    pub fn get_tile_data(&self, bank: usize, baseline: usize, id: u8, row: usize) -> (u8, u8) {
         let baseline = if baseline == 0x8800 {
             baseline - 0x8000 + (id as i8 as i16 + 128) as usize * 16
         } else {
             baseline - 0x8000 + id as usize * 16
-        };
+        } + bank * TILE_DATA_SIZE;
         // let id = id as usize;
         let a = self.tile_data[baseline + row * 2];
         let b = self.tile_data[baseline + row * 2 + 1];
         (a, b)
     }
 
-    pub fn start_window(&mut self) {
-        self.window = Box::new(Screen::new())
+    /// HDMA copies a block at the start of every HBlank
+    pub fn take_hblank_started(&mut self) -> bool {
+        std::mem::take(&mut self.hblank_started)
+    }
+
+    pub fn screen(&self) -> &[u32] {
+        &self.engine.screen
+    }
+
+    pub fn frame(&self) -> &Frame {
+        &self.frame
+    }
+
+    /// A frame was completed since the last call
+    pub fn take_frame_completed(&mut self) -> bool {
+        std::mem::take(&mut self.frame_completed)
+    }
+
+    /// Colors of the DMG shades, used from the next line drawn
+    pub fn set_dmg_palettes(&mut self, palettes: DmgPalettes) {
+        self.engine.palettes = palettes;
+        self.palette_preset = None;
+    }
+
+    pub fn next_palette_preset(&mut self) {
+        let preset = self.palette_preset.map_or(0, |i| (i + 1) % PRESETS.len());
+        let (name, palettes) = PRESETS[preset];
+        info!("DMG palette: {}", name);
+        self.engine.palettes = palettes;
+        self.palette_preset = Some(preset);
+    }
+
+    pub fn debug_layers(&self) -> DebugLayers {
+        self.layers
+    }
+
+    /// Layers shown from the next pixel drawn
+    pub fn set_debug_layers(&mut self, layers: DebugLayers) {
+        self.layers = layers;
+        self.engine.highlight = layers.highlight;
+    }
+
+    pub fn enable_sgb(&mut self) {
+        self.sgb = Some(Sgb::new());
+        self.frame.pixels = vec![0xffffff; SGB_WIDTH * SGB_HEIGHT];
+        (self.frame.width, self.frame.height) = (SGB_WIDTH, SGB_HEIGHT);
+    }
+
+    /// A command sent by the game through the joypad register
+    pub fn sgb_command(&mut self, command: &[u8]) {
+        if let Some(sgb) = self.sgb.as_mut() {
+            sgb.command(command);
+        }
     }
 
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(cgb: bool) -> Self {
         Display {
             engine: Buffer::new(),
-            window: Box::new(FakeScreen {}), //TODO ugly.. setting a FakeScreen for sake of tests..
-            processor: Processor::new(),
+            frame: Frame::new(),
+            frame_completed: false,
+            processor: Processor::new(cgb),
 
-            tile_data: vec![0; 0x97FF - 0x8000 + 1],
-            tile_maps: vec![0; 0x9FFF - 0x9800 + 1],
+            tile_data: vec![0; 2 * TILE_DATA_SIZE],
+            tile_maps: vec![0; 2 * TILE_MAPS_SIZE],
             oam: vec![0; 0xFE9F - 0xFE00 + 1],
 
+            cgb,
+            vram_bank: 0,
+            object_priority: 0,
+            palettes: ColorPalettes::new(),
+            sgb: None,
+            palette_preset: Some(0),
+            layers: DebugLayers::default(),
+
             dots: 0,
             gpu_mode: Mode::Two,
             interrupt: 0,
+
+            fetcher: Fetcher::new(false),
+            bg_fifo: VecDeque::with_capacity(16),
+            obj_fifo: VecDeque::with_capacity(8),
+            lx: 0,
+            discard: 0,
+            first_fetch: 0,
+            line_sprites: Vec::with_capacity(10),
+            sprite_fetch: None,
+            window_y_triggered: false,
+            window_on_line: false,
+
+            lcd_on: true,
+            stopped: false,
+            skip_frame: false,
+            off_dots: 0,
+            hblank_started: false,
         }
     }
 }
@@ -291,17 +738,32 @@ impl Display {
 impl MemoryAccessor for Display {
     fn get(&self, location: usize) -> u8 {
         match location {
-            0x8000..=0x97FF => self.tile_data[location - 0x8000],
-            0x9800..=0x9FFF => self.tile_maps[location - 0x9800],
+            0x8000..=0x9FFF if self.vram_locked() => 0xff,
+            0xFE00..=0xFE9F if self.oam_locked() => 0xff,
+            0x8000..=0x97FF => self.tile_data[self.vram_bank * TILE_DATA_SIZE + location - 0x8000],
+            0x9800..=0x9FFF => self.tile_maps[self.vram_bank * TILE_MAPS_SIZE + location - 0x9800],
             0xff40..=0xff4b => self.processor.get(location),
             0xFE00..=0xFE9F => self.oam[location - 0xFE00],
 
+            // CGB registers
+            _ if !self.cgb => 0xff,
+            0xff4f => 0xfe | self.vram_bank as u8,
+            0xff69 | 0xff6b if self.vram_locked() => 0xff,
+            0xff68..=0xff6b => self.palettes.get(location),
+            0xff6c => 0xfe | self.object_priority,
+
             _ => panic!("Unknown location: {:#x}", location),
         }
     }
 
     fn write(&mut self, location: usize, value: u8) {
         match location {
+            0x8000..=0x9FFF if self.vram_locked() => {
+                debug!("VRAM write during mode 3 dropped: {:#x}", location)
+            }
+            0xfe00..=0xfe9f if self.oam_locked() => {
+                debug!("OAM write during mode {} dropped", self.stat_mode())
+            }
             0xfe00..=0xfe9f => {
                 self.oam[location - 0xfe00] = value;
             }
@@ -315,13 +777,23 @@ impl MemoryAccessor for Display {
                         location, value, value
                     );
                 }
-                self.tile_data[location - 0x8000] = value
+                self.tile_data[self.vram_bank * TILE_DATA_SIZE + location - 0x8000] = value
             }
 
             0x9800..=0x9FFF => {
                 debug!("Writing to Tile Map");
-                self.tile_maps[location - 0x9800] = value
+                self.tile_maps[self.vram_bank * TILE_MAPS_SIZE + location - 0x9800] = value
+            }
+
+            // CGB registers
+            _ if !self.cgb => (),
+            0xff4f => self.vram_bank = (value & 0x01) as usize,
+            0xff69 | 0xff6b if self.vram_locked() => {
+                debug!("Palette write during mode 3 dropped: {:#x}", location)
             }
+            0xff68..=0xff6b => self.palettes.write(location, value),
+            0xff6c => self.object_priority = value & 0x01,
+
             _ => {
                 panic!(
                     "Memory write to graphics {:#x} value: {:#x}",
@@ -331,3 +803,319 @@ impl MemoryAccessor for Display {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{DebugLayer, DebugLayers, Display, Mode};
+    use crate::gameboy::interrupts;
+    use crate::gameboy::memory_bus::MemoryAccessor;
+
+    /// Dots spent in mode 3 on the next line
+    fn mode_three_length(display: &mut Display) -> u32 {
+        while display.gpu_mode != Mode::Two {
+            display.gpu_step(1);
+        }
+        while display.gpu_mode != Mode::Three {
+            display.gpu_step(1);
+        }
+        let mut dots = 0;
+        while display.gpu_mode == Mode::Three {
+            display.gpu_step(1);
+            dots += 1;
+        }
+        dots
+    }
+
+    #[test]
+    fn mode_three_takes_172_dots() {
+        let mut display = Display::new(false);
+        assert_eq!(mode_three_length(&mut display), 172);
+    }
+
+    #[test]
+    fn fine_scroll_extends_mode_three() {
+        let mut display = Display::new(false);
+        display.write(0xff43, 3);
+        assert_eq!(mode_three_length(&mut display), 175);
+    }
+
+    #[test]
+    fn sprites_extend_mode_three() {
+        let mut display = Display::new(false);
+        display.write(0xff40, 0x93);
+        // visible on line 1, aligned with the background tiles
+        display.oam[0] = 16;
+        display.oam[1] = 16;
+        display.gpu_step(456);
+        assert!(mode_three_length(&mut display) > 172);
+    }
+
+    #[test]
+    fn draws_background_through_palette() {
+        let mut display = Display::new(false);
+        // tile 0, first row: color 3 for the leftmost pixel
+        display.write(0x8000, 0x80);
+        display.write(0x8001, 0x80);
+        display.write(0xff47, 0xe4);
+        display.gpu_step(456);
+        assert_eq!(display.engine.screen[0], 0x000000);
+        assert_eq!(display.engine.screen[1], 0xffffff);
+    }
+
+    fn object(display: &mut Display, index: usize, x: u8, tile: u8) {
+        display.oam[index * 4] = 16;
+        display.oam[index * 4 + 1] = x;
+        display.oam[index * 4 + 2] = tile;
+    }
+
+    /// Tile 1: color 1 on the leftmost pixel, tile 2: color 2 on all but it,
+    /// tile 3: color 1
+    fn objects_display() -> Display {
+        let mut display = Display::new(false);
+        display.write(0xff40, 0x93);
+        display.write(0xff48, 0xe4);
+        display.write(0x8010, 0x80);
+        display.write(0x8021, 0x7f);
+        display.write(0x8030, 0xff);
+        display
+    }
+
+    #[test]
+    fn same_x_lowest_oam_index_wins() {
+        let mut display = objects_display();
+        object(&mut display, 0, 8, 1);
+        object(&mut display, 1, 8, 2);
+        display.gpu_step(456);
+
+        let line = &display.engine.screen[..160];
+        assert_eq!(line[0], 0xa9a9a9);
+        // transparent pixels of the first object show the second one
+        assert_eq!(line[1], 0x545454);
+    }
+
+    fn hidden(mut display: Display, change: impl Fn(&mut DebugLayers)) -> Vec<u32> {
+        let mut layers = display.debug_layers();
+        change(&mut layers);
+        display.set_debug_layers(layers);
+        display.gpu_step(456);
+        display.engine.screen[..160].to_vec()
+    }
+
+    #[test]
+    fn hidden_objects_show_the_next_one() {
+        let display = || {
+            let mut display = objects_display();
+            object(&mut display, 0, 8, 1);
+            object(&mut display, 1, 8, 2);
+            display
+        };
+        let line = hidden(display(), |layers| layers.set_object_hidden(1, true));
+        assert_eq!(line[0], 0xa9a9a9);
+        assert_eq!(line[1], 0xffffff);
+
+        let line = hidden(display(), |layers| layers.set_object_hidden(0, true));
+        assert_eq!(line[0], 0xffffff);
+        assert_eq!(line[1], 0x545454);
+
+        let line = hidden(display(), |layers| layers.toggle(DebugLayer::Objects));
+        assert!(line.iter().all(|&color| color == 0xffffff));
+    }
+
+    #[test]
+    fn hidden_background_and_highlight() {
+        let display = || {
+            let mut display = Display::new(false);
+            // color 0 white, color 1 black
+            display.write(0xff47, 0x0c);
+            display.write(0x8000, 0xff);
+            display
+        };
+        let line = hidden(display(), |layers| layers.toggle(DebugLayer::Background));
+        assert_eq!(line[0], 0xffffff);
+
+        // black tinted red
+        let line = hidden(display(), |layers| layers.highlight = true);
+        assert_eq!(line[0], 0x7f0000);
+    }
+
+    #[test]
+    fn lowest_x_wins() {
+        let mut display = objects_display();
+        // both partially offscreen, fetched at the start of the line
+        object(&mut display, 0, 7, 2);
+        object(&mut display, 1, 6, 3);
+        display.gpu_step(456);
+
+        let line = &display.engine.screen[..160];
+        assert_eq!(line[0], 0xa9a9a9);
+        assert_eq!(line[5], 0xa9a9a9);
+        assert_eq!(line[6], 0x545454);
+    }
+
+    #[test]
+    fn behind_bg_uses_color_index() {
+        let mut display = objects_display();
+        // BG color 0 is black, color 1 dark gray
+        display.write(0xff47, 0x0b);
+        display.write(0x8000, 0x0f);
+        object(&mut display, 0, 8, 3);
+        display.oam[3] = 0x80;
+        display.gpu_step(456);
+
+        let line = &display.engine.screen[..160];
+        assert_eq!(line[0], 0xa9a9a9);
+        assert_eq!(line[4], 0x545454);
+    }
+
+    /// Runs `dots` dots one at a time, counting the STAT interrupts
+    fn stat_interrupts(display: &mut Display, dots: u32) -> usize {
+        (0..dots)
+            .filter(|_| display.gpu_step(1) & interrupts::STAT > 0)
+            .count()
+    }
+
+    #[test]
+    fn stat_sources_block_each_other() {
+        let mut display = Display::new(false);
+        // HBlank and LY=LYC on line 1
+        display.write(0xff45, 1);
+        display.write(0xff41, 0x48);
+        assert_eq!(stat_interrupts(&mut display, 456), 1);
+        // LY=LYC rises while HBlank is still high
+        assert_eq!(stat_interrupts(&mut display, 1), 0);
+        assert_eq!(stat_interrupts(&mut display, 455), 0);
+    }
+
+    #[test]
+    fn lyc_write_matching_ly_fires() {
+        let mut display = Display::new(false);
+        display.gpu_step(456 * 3);
+        display.write(0xff41, 0x40);
+        display.write(0xff45, 3);
+        assert_eq!(stat_interrupts(&mut display, 1), 1);
+    }
+
+    #[test]
+    fn ly_reads_0_during_line_153() {
+        let mut display = Display::new(false);
+        display.gpu_step(456 * 153 + 1);
+        assert_eq!(display.get(0xff44), 153);
+        display.gpu_step(4);
+        assert_eq!(display.get(0xff44), 0);
+
+        // LYC=0 matches early, and only fires once
+        display.write(0xff45, 0);
+        display.write(0xff41, 0x40);
+        display.gpu_step(1);
+        assert_eq!(stat_interrupts(&mut display, 456), 0);
+    }
+
+    #[test]
+    fn stat_write_fires_in_vblank() {
+        let mut display = Display::new(false);
+        display.write(0xff45, 0xff);
+        display.gpu_step(456 * 145);
+        display.write(0xff41, 0);
+        assert_eq!(stat_interrupts(&mut display, 1), 1);
+    }
+
+    #[test]
+    fn vram_and_oam_locked_by_mode() {
+        let mut display = Display::new(false);
+        display.oam[0] = 0x42;
+        display.tile_data[0] = 0x24;
+
+        display.gpu_step(1);
+        assert_eq!(display.get(0xfe00), 0xff);
+        assert_eq!(display.get(0x8000), 0x24);
+        display.write(0xfe00, 0);
+        assert_eq!(display.oam[0], 0x42);
+
+        display.gpu_step(80);
+        assert_eq!(display.get(0x8000), 0xff);
+        display.write(0x8000, 0);
+        assert_eq!(display.tile_data[0], 0x24);
+
+        display.gpu_step(200);
+        assert_eq!(display.get(0xfe00), 0x42);
+        assert_eq!(display.get(0x8000), 0x24);
+    }
+
+    #[test]
+    fn lcd_on_starts_without_oam_scan() {
+        let mut display = Display::new(false);
+        display.gpu_step(456 * 10);
+        display.write(0xff40, 0x11);
+        display.gpu_step(1);
+        assert_eq!(display.get(0xff44), 0);
+        assert_eq!(display.get(0xff41) & 0x03, 0);
+
+        display.write(0xff40, 0x91);
+        display.gpu_step(1);
+        assert_eq!(display.get(0xff41) & 0x03, 0);
+        assert_eq!(display.get(0xfe00), 0);
+        display.gpu_step(80);
+        assert_eq!(display.get(0xff41) & 0x03, 3);
+
+        // the first line is shorter, the next one has an OAM scan
+        display.gpu_step(456 - 81 - 4);
+        assert_eq!(display.get(0xff44), 1);
+        assert_eq!(display.get(0xff41) & 0x03, 2);
+    }
+
+    #[test]
+    fn inc_dec_corrupts_oam_row() {
+        let mut display = Display::new(false);
+        for (i, byte) in display.oam.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        // row 2 is being scanned
+        display.gpu_step(9);
+        display.corrupt_oam();
+
+        let (a, b, c) = (0x1110u16, 0x0908u16, 0x0d0cu16);
+        let first = ((a ^ c) & (b ^ c)) ^ c;
+        assert_eq!(display.oam[16..18], first.to_le_bytes());
+        assert_eq!(display.oam[18..24], [10, 11, 12, 13, 14, 15]);
+        assert_eq!(display.oam[24], 24);
+    }
+
+    /// Sets color `index` of a CGB palette through BCPS/BCPD or OCPS/OCPD
+    fn cgb_color(display: &mut Display, register: usize, palette: u8, index: u8, color: u16) {
+        display.write(register, palette * 8 + index * 2);
+        display.write(register + 1, color as u8);
+        display.write(register, palette * 8 + index * 2 + 1);
+        display.write(register + 1, (color >> 8) as u8);
+    }
+
+    #[test]
+    fn cgb_bg_attributes() {
+        let mut display = Display::new(true);
+        // palette 2, X flip, tile data in bank 1
+        display.write(0xff4f, 1);
+        display.write(0x9800, 0x2a);
+        display.write(0x8000, 0x80);
+        display.write(0xff4f, 0);
+        cgb_color(&mut display, 0xff68, 2, 1, 0x001f);
+        display.gpu_step(456);
+
+        assert_eq!(display.engine.screen[7], 0xff0000);
+        assert_eq!(display.engine.screen[0], 0xffffff);
+    }
+
+    #[test]
+    fn cgb_objects_prioritised_by_oam_index() {
+        let mut display = Display::new(true);
+        display.write(0xff40, 0x93);
+        display.write(0x8030, 0xff);
+        cgb_color(&mut display, 0xff6a, 0, 1, 0x001f);
+        cgb_color(&mut display, 0xff6a, 1, 1, 0x7c00);
+        object(&mut display, 0, 10, 3);
+        object(&mut display, 1, 8, 3);
+        display.oam[7] = 0x01;
+        display.gpu_step(456);
+
+        assert_eq!(display.engine.screen[0], 0x0000ff);
+        assert_eq!(display.engine.screen[2], 0xff0000);
+    }
+}
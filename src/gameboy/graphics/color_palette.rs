@@ -0,0 +1,102 @@
+use log::trace;
+
+use crate::gameboy::memory_bus::MemoryAccessor;
+
+/// Palette memory, 8 palettes of 4 colors, 2 bytes per color
+const PALETTE_RAM_SIZE: usize = 64;
+
+/// CGB color palettes
+///
+/// BCPS/OCPS (FF68/FF6A) select a byte of the BG/OBJ palette memory, bit 7
+/// enabling the auto increment after each write to BCPD/OCPD (FF69/FF6B).
+pub struct ColorPalettes {
+    background: [u8; PALETTE_RAM_SIZE],
+    objects: [u8; PALETTE_RAM_SIZE],
+    /// FF68
+    background_index: u8,
+    /// FF6A
+    objects_index: u8,
+}
+
+fn color(ram: &[u8], palette: u8, color: u8) -> u16 {
+    let index = palette as usize * 8 + color as usize * 2;
+    u16::from_le_bytes([ram[index], ram[index + 1]]) & 0x7fff
+}
+
+fn write_data(ram: &mut [u8], index: &mut u8, value: u8) {
+    ram[(*index & 0x3f) as usize] = value;
+    if *index & 0x80 > 0 {
+        *index = 0x80 | (index.wrapping_add(1) & 0x3f);
+    }
+}
+
+impl ColorPalettes {
+    pub fn background(&self, palette: u8, index: u8) -> u16 {
+        color(&self.background, palette, index)
+    }
+
+    pub fn object(&self, palette: u8, index: u8) -> u16 {
+        color(&self.objects, palette, index)
+    }
+
+    pub fn new() -> Self {
+        ColorPalettes {
+            // the boot ROM sets every background color to white
+            background: [0xff; PALETTE_RAM_SIZE],
+            objects: [0; PALETTE_RAM_SIZE],
+            background_index: 0,
+            objects_index: 0,
+        }
+    }
+}
+
+impl MemoryAccessor for ColorPalettes {
+    fn get(&self, location: usize) -> u8 {
+        match location {
+            0xff68 => self.background_index | 0x40,
+            0xff69 => self.background[(self.background_index & 0x3f) as usize],
+            0xff6a => self.objects_index | 0x40,
+            0xff6b => self.objects[(self.objects_index & 0x3f) as usize],
+            _ => panic!("color palette location read: {:#x}", location),
+        }
+    }
+
+    fn write(&mut self, location: usize, value: u8) {
+        trace!("Writting to color palettes: {:#x}: {:#x}", location, value);
+        match location {
+            0xff68 => self.background_index = value & 0xbf,
+            0xff69 => write_data(&mut self.background, &mut self.background_index, value),
+            0xff6a => self.objects_index = value & 0xbf,
+            0xff6b => write_data(&mut self.objects, &mut self.objects_index, value),
+            _ => panic!("color palette location write: {:#x}", location),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ColorPalettes;
+    use crate::gameboy::memory_bus::MemoryAccessor;
+
+    #[test]
+    fn auto_increments_index() {
+        let mut palettes = ColorPalettes::new();
+        // palette 1, color 1
+        palettes.write(0xff6a, 0x80 | 0x0a);
+        palettes.write(0xff6b, 0x1f);
+        palettes.write(0xff6b, 0x7c);
+
+        assert_eq!(palettes.get(0xff6a), 0xc0 | 0x0c);
+        assert_eq!(palettes.object(1, 1), 0x7c1f);
+        assert_eq!(palettes.object(1, 0), 0);
+    }
+
+    #[test]
+    fn index_wraps_around() {
+        let mut palettes = ColorPalettes::new();
+        palettes.write(0xff68, 0xbf);
+        palettes.write(0xff69, 0x12);
+        assert_eq!(palettes.get(0xff68), 0xc0);
+        assert_eq!(palettes.background(7, 3) & 0xff00, 0x1200);
+    }
+}
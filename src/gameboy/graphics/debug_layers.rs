@@ -0,0 +1,76 @@
+/// A layer of the picture, as drawn by the PPU
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DebugLayer {
+    Background,
+    Window,
+    Objects,
+}
+
+impl DebugLayer {
+    /// Color the layer is tinted with when highlighting
+    pub fn tint(&self) -> u32 {
+        match self {
+            DebugLayer::Background => 0xff0000,
+            DebugLayer::Window => 0x00ff00,
+            DebugLayer::Objects => 0x0000ff,
+        }
+    }
+}
+
+/// Debug overrides hiding layers whatever the game writes to LCDC.
+///
+/// Hidden layers are drawn as transparent (color 0) without changing the PPU
+/// timings, so a hidden window still covers the background.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DebugLayers {
+    pub background: bool,
+    pub window: bool,
+    pub objects: bool,
+    /// Bit n set hides the object n in OAM
+    pub hidden_objects: u64,
+    /// Tints each layer in its own color, see `DebugLayer::tint`
+    pub highlight: bool,
+}
+
+impl DebugLayers {
+    pub fn toggle(&mut self, layer: DebugLayer) {
+        let shown = match layer {
+            DebugLayer::Background => &mut self.background,
+            DebugLayer::Window => &mut self.window,
+            DebugLayer::Objects => &mut self.objects,
+        };
+        *shown = !*shown;
+    }
+
+    pub fn is_shown(&self, layer: DebugLayer) -> bool {
+        match layer {
+            DebugLayer::Background => self.background,
+            DebugLayer::Window => self.window,
+            DebugLayer::Objects => self.objects,
+        }
+    }
+
+    pub fn set_object_hidden(&mut self, oam_index: usize, hidden: bool) {
+        if hidden {
+            self.hidden_objects |= 1 << oam_index;
+        } else {
+            self.hidden_objects &= !(1 << oam_index);
+        }
+    }
+
+    pub fn is_object_shown(&self, oam_index: u8) -> bool {
+        self.objects && self.hidden_objects & (1 << oam_index) == 0
+    }
+}
+
+impl Default for DebugLayers {
+    fn default() -> Self {
+        DebugLayers {
+            background: true,
+            window: true,
+            objects: true,
+            hidden_objects: 0,
+            highlight: false,
+        }
+    }
+}
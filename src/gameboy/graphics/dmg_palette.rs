@@ -0,0 +1,301 @@
+use std::{fs, path::Path};
+
+use super::engine::rgb555_to_rgb;
+
+/// Which DMG palette register a pixel went through
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Layer {
+    Background,
+    Object0,
+    Object1,
+}
+
+/// Colors (0RGB) of the 4 DMG shades, from white to black, for the
+/// background/window and each object palette
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DmgPalettes {
+    pub background: [u32; 4],
+    pub object0: [u32; 4],
+    pub object1: [u32; 4],
+}
+
+const GRAY: [u32; 4] = [0xffffff, 0xa9a9a9, 0x545454, 0x000000];
+const DMG_GREEN: [u32; 4] = [0x9bbc0f, 0x8bac0f, 0x306230, 0x0f380f];
+const POCKET: [u32; 4] = [0xc4cfa1, 0x8b956d, 0x4d533c, 0x1f1f1f];
+/// Blue/orange, distinguishable with red-green color blindness
+const BLUE_ORANGE: [u32; 4] = [0xffffff, 0xfdb863, 0x5e3c99, 0x000000];
+/// Yellow/blue, distinguishable with most color vision deficiencies
+const YELLOW_BLUE: [u32; 4] = [0xfde725, 0x7c7b78, 0x00326f, 0x000000];
+
+/// GBC boot ROM palettes, chosen with a button combination at boot or by
+/// the title checksum of Nintendo games
+const GBC_BROWN: [u32; 4] = [0xffffff, 0xffad63, 0x843100, 0x000000];
+const GBC_RED: [u32; 4] = [0xffffff, 0xff8484, 0x943a3a, 0x000000];
+const GBC_DARK_BROWN: [u32; 4] = [0xffe6c5, 0xce9c84, 0x846b29, 0x5a3108];
+const GBC_BLUE: [u32; 4] = [0xffffff, 0x65a49b, 0x0000fe, 0x000000];
+const GBC_DARK_BLUE: [u32; 4] = [0xffffff, 0x8c8cde, 0x52528c, 0x000000];
+const GBC_GRAY: [u32; 4] = [0xffffff, 0xa5a5a5, 0x525252, 0x000000];
+const GBC_PASTEL: [u32; 4] = [0xffffa5, 0xff9494, 0x9494ff, 0x000000];
+const GBC_ORANGE: [u32; 4] = [0xffffff, 0xffff00, 0xff0000, 0x000000];
+const GBC_YELLOW: [u32; 4] = [0xffffff, 0xffff00, 0x7b4a00, 0x000000];
+const GBC_GREEN: [u32; 4] = [0xffffff, 0x52ff00, 0xff4200, 0x000000];
+const GBC_DARK_GREEN: [u32; 4] = [0xffffff, 0x7bff31, 0x0063c5, 0x000000];
+const GBC_LEAF: [u32; 4] = [0xffffff, 0x7bff31, 0x008400, 0x000000];
+const GBC_INVERTED: [u32; 4] = [0x000000, 0x008484, 0xffde00, 0xffffff];
+
+/// Palettes that can be selected by name or cycled through at runtime
+pub(crate) const PRESETS: &[(&str, DmgPalettes)] = &[
+    ("gray", DmgPalettes::single(GRAY)),
+    ("green", DmgPalettes::single(DMG_GREEN)),
+    ("pocket", DmgPalettes::single(POCKET)),
+    ("blue-orange", DmgPalettes::single(BLUE_ORANGE)),
+    ("yellow-blue", DmgPalettes::single(YELLOW_BLUE)),
+    ("gbc-brown", DmgPalettes::single(GBC_BROWN)),
+    ("gbc-red", DmgPalettes::single(GBC_RED)),
+    ("gbc-dark-brown", DmgPalettes::single(GBC_DARK_BROWN)),
+    ("gbc-blue", DmgPalettes::new(GBC_BLUE, GBC_RED, GBC_BLUE)),
+    (
+        "gbc-dark-blue",
+        DmgPalettes::new(GBC_DARK_BLUE, GBC_RED, GBC_BROWN),
+    ),
+    ("gbc-gray", DmgPalettes::single(GBC_GRAY)),
+    ("gbc-pastel", DmgPalettes::single(GBC_PASTEL)),
+    ("gbc-orange", DmgPalettes::single(GBC_ORANGE)),
+    (
+        "gbc-yellow",
+        DmgPalettes::new(GBC_YELLOW, GBC_BLUE, GBC_LEAF),
+    ),
+    ("gbc-green", DmgPalettes::single(GBC_GREEN)),
+    (
+        "gbc-dark-green",
+        DmgPalettes::new(GBC_DARK_GREEN, GBC_RED, GBC_RED),
+    ),
+    ("gbc-inverted", DmgPalettes::single(GBC_INVERTED)),
+];
+
+/// Colors (RGB555) of the GBC boot ROM palettes, 4 per palette
+#[rustfmt::skip]
+const GBC_BOOT_COLORS: [u16; 120] = [
+    0x7fff, 0x32bf, 0x00d0, 0x0000, 0x639f, 0x4279, 0x15b0, 0x04cb,
+    0x7fff, 0x6e31, 0x454a, 0x0000, 0x7fff, 0x1bef, 0x0200, 0x0000,
+    0x7fff, 0x421f, 0x1cf2, 0x0000, 0x7fff, 0x5294, 0x294a, 0x0000,
+    0x7fff, 0x03ff, 0x012f, 0x0000, 0x7fff, 0x03ef, 0x01d6, 0x0000,
+    0x7fff, 0x42b5, 0x3dc8, 0x0000, 0x7e74, 0x03ff, 0x0180, 0x0000,
+    0x67ff, 0x77ac, 0x1a13, 0x2d6b, 0x7ed6, 0x4bff, 0x2175, 0x0000,
+    0x53ff, 0x4a5f, 0x7e52, 0x0000, 0x4fff, 0x7ed2, 0x3a4c, 0x1ce0,
+    0x03ed, 0x7fff, 0x255f, 0x0000, 0x036a, 0x021f, 0x03ff, 0x7fff,
+    0x7fff, 0x01df, 0x0112, 0x0000, 0x231f, 0x035f, 0x00f2, 0x0009,
+    0x7fff, 0x03ea, 0x011f, 0x0000, 0x299f, 0x001a, 0x000c, 0x0000,
+    0x7fff, 0x027f, 0x001f, 0x0000, 0x7fff, 0x03e0, 0x0206, 0x0120,
+    0x7fff, 0x7eeb, 0x001f, 0x7c00, 0x7fff, 0x3fff, 0x7e00, 0x001f,
+    0x7fff, 0x03ff, 0x001f, 0x0000, 0x03ff, 0x001f, 0x000c, 0x0000,
+    0x7fff, 0x033f, 0x0193, 0x0000, 0x0000, 0x4200, 0x037f, 0x7fff,
+    0x7fff, 0x7e8c, 0x7c00, 0x0000, 0x7fff, 0x1bef, 0x6180, 0x0000,
+];
+
+/// Object 0, object 1 and background palettes of the GBC boot ROM
+/// combinations, as the index of their first color in `GBC_BOOT_COLORS`. A
+/// few start in the middle of a palette, as in the boot ROM.
+#[rustfmt::skip]
+const GBC_BOOT_COMBINATIONS: [(u8, u8, u8); 51] = [
+    (16, 16, 116), (72, 72, 72), (80, 80, 80), (96, 96, 96), (36, 36, 36), (0, 0, 0),
+    (108, 108, 108), (20, 20, 20), (48, 48, 48), (104, 104, 104), (64, 32, 32), (16, 112, 112),
+    (16, 8, 8), (12, 16, 16), (16, 116, 116), (112, 16, 112), (8, 68, 8), (64, 64, 32),
+    (16, 16, 28), (16, 16, 72), (16, 16, 80), (76, 76, 36), (15, 15, 44), (68, 68, 8), (16, 16, 8),
+    (16, 16, 12), (112, 112, 0), (12, 12, 0), (0, 0, 4), (72, 88, 72), (80, 88, 80), (96, 88, 96),
+    (64, 88, 32), (68, 16, 52), (111, 0, 56), (111, 16, 60), (76, 88, 36), (64, 112, 40),
+    (16, 92, 112), (68, 88, 8), (16, 0, 8), (16, 112, 12), (112, 12, 0), (12, 112, 16),
+    (84, 112, 16), (12, 112, 0), (100, 12, 112), (0, 112, 32), (16, 12, 112), (112, 12, 24),
+    (16, 112, 116),
+];
+
+/// Title checksums of the games given their own palette combination by the
+/// GBC boot ROM. Some checksums are shared, the 4th letter of the title tells
+/// them apart.
+#[rustfmt::skip]
+const GBC_TITLE_PALETTES: [(u8, Option<u8>, u8); 94] = [
+    (0x00, None, 0), (0x88, None, 4), (0x16, None, 5), (0x36, None, 35), (0xd1, None, 34),
+    (0xdb, None, 3), (0xf2, None, 31), (0x3c, None, 15), (0x8c, None, 10), (0x92, None, 5),
+    (0x3d, None, 19), (0x5c, None, 36), (0x58, None, 7), (0xc9, None, 37), (0x3e, None, 30),
+    (0x70, None, 44), (0x1d, None, 21), (0x59, None, 32), (0x69, None, 31), (0x19, None, 20),
+    (0x35, None, 5), (0xa8, None, 33), (0x14, None, 13), (0xaa, None, 14), (0x75, None, 5),
+    (0x95, None, 29), (0x99, None, 5), (0x34, None, 18), (0x6f, None, 9), (0x15, None, 3),
+    (0xff, None, 2), (0x97, None, 26), (0x4b, None, 25), (0x90, None, 25), (0x17, None, 41),
+    (0x10, None, 42), (0x39, None, 26), (0xf7, None, 45), (0xf6, None, 42), (0xa2, None, 45),
+    (0x49, None, 36), (0x4e, None, 38), (0x43, None, 26), (0x68, None, 42), (0xe0, None, 30),
+    (0x8b, None, 41), (0xf0, None, 34), (0xce, None, 34), (0x0c, None, 5), (0x29, None, 42),
+    (0xe8, None, 6), (0xb7, None, 5), (0x86, None, 33), (0x9a, None, 25), (0x52, None, 42),
+    (0x01, None, 42), (0x9d, None, 40), (0x71, None, 2), (0x9c, None, 16), (0xbd, None, 25),
+    (0x5d, None, 42), (0x6d, None, 42), (0x67, None, 5), (0x3f, None, 0), (0x6b, None, 39),
+    (0xb3, Some(b'B'), 36), (0x46, Some(b'E'), 22), (0x28, Some(b'F'), 25), (0xa5, Some(b'A'), 6),
+    (0xc6, Some(b'A'), 32), (0xd3, Some(b'R'), 12), (0x27, Some(b'B'), 36), (0x61, Some(b'E'), 11),
+    (0x18, Some(b'K'), 39), (0x66, Some(b'E'), 18), (0x6a, Some(b'K'), 39), (0xbf, Some(b' '), 24),
+    (0x0d, Some(b'R'), 31), (0xf4, Some(b'-'), 50), (0xb3, Some(b'U'), 17), (0x46, Some(b'R'), 46),
+    (0x28, Some(b'A'), 6), (0xa5, Some(b'R'), 27), (0xc6, Some(b' '), 0), (0xd3, Some(b'I'), 47),
+    (0x27, Some(b'N'), 41), (0x61, Some(b'A'), 41), (0x18, Some(b'I'), 0), (0x66, Some(b'L'), 0),
+    (0x6a, Some(b'I'), 19), (0xbf, Some(b'C'), 34), (0x0d, Some(b'E'), 23), (0xf4, Some(b' '), 18),
+    (0xb3, Some(b'R'), 29),
+];
+
+impl DmgPalettes {
+    pub const fn new(background: [u32; 4], object0: [u32; 4], object1: [u32; 4]) -> Self {
+        DmgPalettes {
+            background,
+            object0,
+            object1,
+        }
+    }
+
+    /// The same colors for every layer
+    pub const fn single(colors: [u32; 4]) -> Self {
+        DmgPalettes::new(colors, colors, colors)
+    }
+
+    pub fn preset(name: &str) -> Option<Self> {
+        PRESETS
+            .iter()
+            .find(|(preset, _)| *preset == name)
+            .map(|(_, palettes)| *palettes)
+    }
+
+    pub fn preset_names() -> impl Iterator<Item = &'static str> {
+        PRESETS.iter().map(|(name, _)| *name)
+    }
+
+    /// The palette the GBC boot ROM picks for a DMG game: Nintendo games are
+    /// looked up by the checksum of their title, the others are dark green
+    pub fn gbc_boot(title: &[u8], nintendo: bool) -> Self {
+        let checksum = title.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        let fourth = title.get(3).copied();
+        let entry = GBC_TITLE_PALETTES
+            .iter()
+            .find(|(sum, letter, _)| *sum == checksum && (letter.is_none() || *letter == fourth));
+        let combination = match entry {
+            Some(&(_, _, combination)) if nintendo => combination,
+            _ => 0,
+        };
+
+        let (object0, object1, background) = GBC_BOOT_COMBINATIONS[combination as usize];
+        DmgPalettes::new(
+            gbc_boot_palette(background),
+            gbc_boot_palette(object0),
+            gbc_boot_palette(object1),
+        )
+    }
+
+    /// Reads a palette file: one line per layer (`bg`, `obp0`, `obp1`) with 4
+    /// hex colors, from white to black. A line without a layer sets all of
+    /// them, `#` starts a comment.
+    ///
+    /// ```text
+    /// bg   = e0f8d0 88c070 346856 081820
+    /// obp0 = ffffff ff8484 943a3a 000000
+    /// ```
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Self::parse(&text)
+    }
+
+    fn parse(text: &str) -> Result<Self, String> {
+        let mut palettes = DmgPalettes::single(GRAY);
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (layer, colors) = match line.split_once('=') {
+                Some((layer, colors)) => (Some(layer.trim()), colors),
+                None => (None, line),
+            };
+            let colors: Vec<u32> = colors
+                .split_whitespace()
+                .map(|color| u32::from_str_radix(color, 16))
+                .collect::<Result<_, _>>()
+                .map_err(|e| format!("line {}: {}", number + 1, e))?;
+            let colors: [u32; 4] = colors
+                .try_into()
+                .map_err(|_| format!("line {}: expected 4 colors", number + 1))?;
+
+            match layer {
+                None => palettes = DmgPalettes::single(colors),
+                Some("bg") => palettes.background = colors,
+                Some("obp0") => palettes.object0 = colors,
+                Some("obp1") => palettes.object1 = colors,
+                Some(layer) => return Err(format!("line {}: unknown layer {}", number + 1, layer)),
+            }
+        }
+        Ok(palettes)
+    }
+
+    pub fn color(&self, layer: Layer, shade: u8) -> u32 {
+        let colors = match layer {
+            Layer::Background => &self.background,
+            Layer::Object0 => &self.object0,
+            Layer::Object1 => &self.object1,
+        };
+        colors[shade as usize & 0x03]
+    }
+}
+
+/// The 4 colors from `first` in `GBC_BOOT_COLORS`, converted to 0RGB
+fn gbc_boot_palette(first: u8) -> [u32; 4] {
+    let mut colors = [0; 4];
+    for (i, color) in colors.iter_mut().enumerate() {
+        *color = rgb555_to_rgb(GBC_BOOT_COLORS[first as usize + i]);
+    }
+    colors
+}
+
+impl Default for DmgPalettes {
+    fn default() -> Self {
+        DmgPalettes::single(GRAY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DmgPalettes, Layer, GBC_LEAF};
+
+    /// The red and dark green presets, as expanded from 15 bits
+    const BOOT_RED: [u32; 4] = [0xffffff, 0xff8484, 0x943939, 0x000000];
+    const BOOT_DARK_GREEN: [u32; 4] = [0xffffff, 0x7bff31, 0x0063c6, 0x000000];
+
+    #[test]
+    fn parses_palette_files() {
+        let palettes = DmgPalettes::parse(
+            "# comment\n\
+             ffffff aaaaaa 555555 000000\n\
+             obp1 = ff0000 00ff00 0000ff 000000 # red\n",
+        )
+        .unwrap();
+        assert_eq!(palettes.color(Layer::Background, 1), 0xaaaaaa);
+        assert_eq!(palettes.color(Layer::Object0, 2), 0x555555);
+        assert_eq!(palettes.color(Layer::Object1, 0), 0xff0000);
+
+        assert!(DmgPalettes::parse("ffffff 000000").is_err());
+        assert!(DmgPalettes::parse("obp2 = 0 0 0 0").is_err());
+    }
+
+    #[test]
+    fn gbc_boot_palette_by_title() {
+        let red = DmgPalettes::gbc_boot(b"POKEMON RED", true);
+        assert_eq!(red.background, BOOT_RED);
+        assert_eq!(red.object0, GBC_LEAF);
+        let other = DmgPalettes::gbc_boot(b"POKEMON RED", false);
+        assert_eq!(other.background, BOOT_DARK_GREEN);
+    }
+
+    #[test]
+    fn gbc_boot_palette_by_fourth_letter() {
+        // same checksum, told apart by the 4th letter
+        let blue = DmgPalettes::gbc_boot(b"POKEMON BLUE", true);
+        assert_eq!(blue.background, [0xffffff, 0x63a5ff, 0x0000ff, 0x000000]);
+        assert_eq!(blue.object0, BOOT_RED);
+        let vegas = DmgPalettes::gbc_boot(b"VEGAS STAKES", true);
+        assert_eq!(vegas.background, GBC_LEAF);
+        // the checksum but none of the letters
+        let unknown = DmgPalettes::gbc_boot(b"POKMEON BLUE", true);
+        assert_eq!(unknown.background, BOOT_DARK_GREEN);
+    }
+}
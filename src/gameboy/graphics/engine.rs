@@ -1,80 +1,126 @@
-use super::window::{HEIGHT, WIDTH};
-use log::trace;
+use super::debug_layers::DebugLayer;
+use super::dmg_palette::{DmgPalettes, Layer};
+use super::{HEIGHT, WIDTH};
+
+/// A pixel of the current line, before the palette is applied
+#[derive(Clone, Copy, Debug)]
+pub enum Pixel {
+    Dmg {
+        /// Color index, 0-3
+        color: u8,
+        /// Value of the palette register when the pixel was output
+        palette: u8,
+        layer: Layer,
+    },
+    /// 15-bit color from the CGB palette memory
+    Cgb(u16),
+}
 
-const WHITE: u32 = 0xffffff;
-const LIGHT_GRAY: u32 = 0xa9a9a9;
-const DARK_GRAY: u32 = 0x545454;
-const BLACK: u32 = 0x000000;
+impl Pixel {
+    /// BG and window disabled (LCDC.0): white whatever BGP is
+    pub fn blank() -> Self {
+        Pixel::Dmg {
+            color: 0,
+            palette: 0,
+            layer: Layer::Background,
+        }
+    }
+}
 
-pub struct Buffer {
-    pub screen: Vec<u32>,
+/// A completed frame
+#[derive(Clone)]
+pub struct Frame {
+    /// 0RGB pixels, 256x224 with the SGB border, 160x144 otherwise
+    pub pixels: Vec<u32>,
+    pub width: usize,
+    pub height: usize,
+    /// DMG shade (0 white - 3 black) of each of the 160x144 LCD pixels, 0 in
+    /// CGB mode
+    pub shades: Vec<u8>,
+    /// Frames completed since power on, the LCD being off included
+    pub number: u64,
 }
-impl Buffer {
-    pub fn wipe_line(&mut self, line: u8) {
-        for p in 0..WIDTH {
-            self.screen[line as usize * WIDTH + p] = 0xffffff;
+
+impl Frame {
+    pub(crate) fn new() -> Self {
+        Frame {
+            pixels: vec![0xffffff; WIDTH * HEIGHT],
+            width: WIDTH,
+            height: HEIGHT,
+            shades: vec![0; WIDTH * HEIGHT],
+            number: 0,
         }
     }
+}
 
-    pub fn _wipe_screen(&mut self) {
+pub struct Buffer {
+    pub screen: Vec<u32>,
+    /// DMG shade (0 white - 3 black) of each pixel, after the palette
+    pub shades: Vec<u8>,
+    /// Raw BG/window color index of each pixel of the current line, deciding
+    /// whether objects behind the background are visible
+    bg_line: [u8; WIDTH],
+    /// CGB BG-to-OAM priority attribute of each pixel of the current line
+    bg_priority: [bool; WIDTH],
+    line: [Pixel; WIDTH],
+    /// Layer each pixel of the current line comes from
+    line_layers: [DebugLayer; WIDTH],
+    /// Colors of the DMG shades
+    pub palettes: DmgPalettes,
+    /// Tint the pixels by layer, see `DebugLayers::highlight`
+    pub highlight: bool,
+}
+impl Buffer {
+    pub fn wipe_screen(&mut self) {
+        let white = self.palettes.color(Layer::Background, 0);
         for elem in self.screen.iter_mut() {
-            *elem = 0xffffff;
+            *elem = white;
         }
+        self.shades.fill(0);
     }
 
-    pub fn draw_bg_tile(&mut self, x_pos: u8, x: u8, y: u8, tile_data: (u8, u8), palette: u8) {
-        let pixel = 7 - (x_pos % 8);
-        // let x = x + 7 - pixel;
-        let lsb = tile_data.0 & (1 << pixel) > 0;
-        let msb = tile_data.1 & (1 << pixel) > 0;
-
-        let color_code = (msb as u8) << 1 | lsb as u8;
-
-        let color_code = use_palette(palette, color_code);
-        let color = get_color(color_code);
-        if y as usize >= HEIGHT || x as usize >= WIDTH {
-            return;
-        }
-
-        self.screen[y as usize * WIDTH + x as usize] = color
+    pub fn set_bg_index(&mut self, x: u8, color: u8, priority: bool) {
+        self.bg_line[x as usize] = color;
+        self.bg_priority[x as usize] = priority;
     }
 
-    pub fn draw_tile(&mut self, tile: super::Tile, y: u8, tile_data: (u8, u8), palette: u8) {
-        let skip = if tile.x < 8 { 8 - tile.x } else { 0 };
-
-        let range: Box<dyn Iterator<Item = u8>> = if tile.is_x_flipped() {
-            // panic!("ASD");
-            Box::new(0..(8 - skip))
-        } else {
-            Box::new((0..(8 - skip)).rev())
-        };
-
-        for (i, pixel) in range.enumerate() {
-            // println!("{} + {} - 8 - skip {} - px {}", tile.x, i, skip, pixel);
-            let x = tile.x + (i as u8 + skip) - 8;
-            let lsb = tile_data.0 & (1 << pixel) > 0;
-            let msb = tile_data.1 & (1 << pixel) > 0;
-
-            let color_code = (msb as u8) << 1 | lsb as u8;
-            if color_code == 0 {
-                trace!("skiping transparent for sprite");
-                continue;
-            }
+    pub fn bg_index(&self, x: u8) -> u8 {
+        self.bg_line[x as usize]
+    }
 
-            let pixel_to_draw = y as usize * WIDTH + x as usize;
-            if !tile.has_priority() && (self.screen[pixel_to_draw] != WHITE) {
-                trace!("skiping not object priority");
-                continue;
-            }
+    pub fn bg_priority(&self, x: u8) -> bool {
+        self.bg_priority[x as usize]
+    }
 
-            let color_code = use_palette(palette, color_code);
-            let color = get_color(color_code);
-            // trace!("({}, {}) Color: {:#x} All: {:#x}",x,y,color,y as usize * WIDTH + x as usize);
-            if y as usize >= HEIGHT || x as usize >= WIDTH {
-                continue;
-            }
+    pub fn set_pixel(&mut self, x: u8, pixel: Pixel, layer: DebugLayer) {
+        self.line[x as usize] = pixel;
+        self.line_layers[x as usize] = layer;
+    }
 
-            self.screen[pixel_to_draw] = color
+    /// Applies the palettes to the current line and copies it to the screen
+    pub fn finish_line(&mut self, y: u8) {
+        if y as usize >= HEIGHT {
+            return;
+        }
+        let start = y as usize * WIDTH;
+        for (x, pixel) in self.line.iter().enumerate() {
+            let color = match *pixel {
+                Pixel::Dmg {
+                    color,
+                    palette,
+                    layer,
+                } => {
+                    let shade = use_palette(palette, color);
+                    self.shades[start + x] = shade;
+                    self.palettes.color(layer, shade)
+                }
+                Pixel::Cgb(color) => rgb555_to_rgb(color),
+            };
+            self.screen[start + x] = if self.highlight {
+                blend(color, self.line_layers[x].tint())
+            } else {
+                color
+            };
         }
     }
 
@@ -82,20 +128,30 @@ impl Buffer {
         let screen_buffer: Vec<u32> = vec![0; WIDTH * HEIGHT];
         Buffer {
             screen: screen_buffer,
+            shades: vec![0; WIDTH * HEIGHT],
+            bg_line: [0; WIDTH],
+            bg_priority: [false; WIDTH],
+            line: [Pixel::blank(); WIDTH],
+            line_layers: [DebugLayer::Background; WIDTH],
+            palettes: DmgPalettes::default(),
+            highlight: false,
         }
     }
 }
 
-fn get_color(color_code: u8) -> u32 {
-    if color_code == 0 {
-        WHITE // white
-    } else if color_code == 1 {
-        LIGHT_GRAY // light gray
-    } else if color_code == 2 {
-        DARK_GRAY // dark gray
-    } else {
-        BLACK // black
-    }
+/// Average of two 0RGB colors
+pub fn blend(a: u32, b: u32) -> u32 {
+    // average of each channel without carrying between them
+    ((a ^ b) & 0xfefefe) / 2 + (a & b)
+}
+
+/// Expands a CGB 15-bit color (5 bits per channel, red first) to 0RGB
+pub fn rgb555_to_rgb(color: u16) -> u32 {
+    let channel = |shift: u16| {
+        let value = ((color >> shift) & 0x1f) as u32;
+        (value << 3) | (value >> 2)
+    };
+    channel(0) << 16 | channel(5) << 8 | channel(10)
 }
 
 pub fn use_palette(palette: u8, id: u8) -> u8 {
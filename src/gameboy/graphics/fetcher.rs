@@ -0,0 +1,84 @@
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FetcherStep {
+    /// Read the tile id from the tile map
+    Tile,
+    /// Read the low byte of the tile row
+    DataLow,
+    /// Read the high byte of the tile row
+    DataHigh,
+    /// Wait for the FIFO to be empty and push the 8 pixels
+    Push,
+}
+
+/// Background/window pixel fetcher.
+///
+/// Every step but `Push` takes two dots. Registers (SCX, SCY, LCDC) are read
+/// when the step runs, so mid-scanline writes affect the following tiles.
+pub struct Fetcher {
+    pub step: FetcherStep,
+    /// Dots spent in the current step
+    pub dots: u8,
+    /// Tile column being fetched, relative to the start of the line/window
+    pub x: u8,
+    pub window: bool,
+
+    pub tile_id: u8,
+    /// CGB BG map attributes of the tile, always 0 on DMG
+    pub attributes: u8,
+    pub low: u8,
+    pub high: u8,
+}
+
+impl Fetcher {
+    /// Returns true if the current step is done and the fetcher can run it
+    pub fn tick(&mut self) -> bool {
+        self.dots += 1;
+        if self.dots < 2 {
+            return false;
+        }
+        self.dots = 0;
+        true
+    }
+
+    pub fn new(window: bool) -> Self {
+        Fetcher {
+            step: FetcherStep::Tile,
+            dots: 0,
+            x: 0,
+            window,
+            tile_id: 0,
+            attributes: 0,
+            low: 0,
+            high: 0,
+        }
+    }
+}
+
+/// A pixel waiting in the background FIFO
+#[derive(Clone, Copy, Debug)]
+pub struct BgPixel {
+    pub color: u8,
+    /// CGB palette number
+    pub palette: u8,
+    /// CGB BG-to-OAM priority: colors 1-3 are drawn over objects
+    pub priority: bool,
+}
+
+/// A pixel waiting in the object FIFO
+#[derive(Clone, Copy, Debug)]
+pub struct ObjectPixel {
+    /// Color index, 0 is transparent
+    pub color: u8,
+    /// OBP0/OBP1 on DMG, color palette 0-7 on CGB
+    pub palette: u8,
+    /// BG and window colors 1-3 are drawn over it
+    pub behind_bg: bool,
+    /// Position in OAM, deciding the priority between objects on CGB
+    pub oam_index: u8,
+}
+
+/// Color index of pixel `x` (0 is the leftmost) of a tile row
+pub fn color_index(low: u8, high: u8, x: u8) -> u8 {
+    let bit = 7 - x;
+    ((high >> bit) & 1) << 1 | ((low >> bit) & 1)
+}
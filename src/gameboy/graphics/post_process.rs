@@ -0,0 +1,486 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter},
+    path::Path,
+};
+
+use super::engine::blend;
+
+/// A frame in 0RGB
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Image {
+    pub pixels: Vec<u32>,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Image {
+    /// Nearest neighbor scaling
+    pub fn scale(&self, factor: usize) -> Result<Image, String> {
+        if factor == 0 {
+            return Err("Scale factor must be at least 1".to_string());
+        }
+        Ok(Scale(factor).apply(self.clone()))
+    }
+
+    pub fn filter(&self, post_processing: &mut PostProcessing) -> Image {
+        post_processing
+            .process(&self.pixels, self.width, self.height)
+            .clone()
+    }
+
+    pub fn save_png(&self, path: &Path) -> io::Result<()> {
+        let file = File::create(path)?;
+        let mut encoder =
+            png::Encoder::new(BufWriter::new(file), self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        let data: Vec<u8> = self
+            .pixels
+            .iter()
+            .flat_map(|&color| [(color >> 16) as u8, (color >> 8) as u8, color as u8])
+            .collect();
+        encoder
+            .write_header()
+            .and_then(|mut writer| writer.write_image_data(&data))
+            .map_err(io::Error::other)
+    }
+
+    fn pixel(&self, x: isize, y: isize) -> u32 {
+        let x = x.clamp(0, self.width as isize - 1) as usize;
+        let y = y.clamp(0, self.height as isize - 1) as usize;
+        self.pixels[y * self.width + x]
+    }
+}
+
+/// A step of the post-processing chain
+pub trait Filter {
+    fn apply(&mut self, image: Image) -> Image;
+    /// How many times bigger the output is
+    fn factor(&self) -> usize {
+        1
+    }
+}
+
+/// Chains given to the frontend hotkey, cycled in this order
+pub const FILTER_PRESETS: &[&str] = &[
+    "",
+    "scale3",
+    "scale2x",
+    "scale3x",
+    "xbr2x",
+    "grid3",
+    "blend,grid3",
+    "color,scale3",
+];
+
+/// Filters applied in order to every frame before it is shown
+pub struct PostProcessing {
+    filters: Vec<Box<dyn Filter>>,
+    output: Image,
+}
+
+impl PostProcessing {
+    /// Parses a comma separated chain, e.g. `blend,scale2x,grid2`:
+    ///
+    /// - `scale<N>`: nearest neighbor integer scaling
+    /// - `scale2x`, `scale3x`: EPX pixel-art scalers
+    /// - `xbr2x`: edge-directed pixel-art scaler
+    /// - `grid<N>`: scaling with darkened lines between the LCD dots
+    /// - `blend`: mixes each frame with the previous one, like the DMG LCD
+    /// - `color`: CGB LCD color correction
+    pub fn parse(chain: &str) -> Result<Self, String> {
+        let filters = chain
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| -> Result<Box<dyn Filter>, String> {
+                let factor = |prefix: &str| {
+                    name.strip_prefix(prefix)
+                        .and_then(|n| n.parse::<usize>().ok())
+                        .filter(|n| (1..=8).contains(n))
+                };
+                Ok(match name {
+                    "scale2x" => Box::new(Epx::new(2)),
+                    "scale3x" => Box::new(Epx::new(3)),
+                    "xbr2x" => Box::new(Xbr2x),
+                    "blend" => Box::new(FrameBlending::new()),
+                    "color" => Box::new(ColorCorrection),
+                    _ => {
+                        if let Some(n) = factor("scale") {
+                            Box::new(Scale(n))
+                        } else if let Some(n) = factor("grid").filter(|n| *n > 1) {
+                            Box::new(LcdGrid(n))
+                        } else {
+                            return Err(format!("Unknown filter {}", name));
+                        }
+                    }
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(PostProcessing {
+            filters,
+            output: Image {
+                pixels: Vec::new(),
+                width: 0,
+                height: 0,
+            },
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    pub fn output_size(&self, width: usize, height: usize) -> (usize, usize) {
+        let factor: usize = self.filters.iter().map(|filter| filter.factor()).product();
+        (width * factor, height * factor)
+    }
+
+    pub fn process(&mut self, screen: &[u32], width: usize, height: usize) -> &Image {
+        let image = Image {
+            pixels: screen.to_vec(),
+            width,
+            height,
+        };
+        self.output = self
+            .filters
+            .iter_mut()
+            .fold(image, |image, filter| filter.apply(image));
+        &self.output
+    }
+}
+
+impl Default for PostProcessing {
+    fn default() -> Self {
+        PostProcessing::parse("").unwrap()
+    }
+}
+
+/// Builds a `factor` times bigger image, `block` giving the pixels of each
+/// source pixel from left to right, top to bottom
+fn upscale(image: &Image, factor: usize, block: impl Fn(isize, isize, &mut [u32])) -> Image {
+    let width = image.width * factor;
+    let mut pixels = vec![0; width * image.height * factor];
+    let mut out = vec![0; factor * factor];
+    for y in 0..image.height {
+        for x in 0..image.width {
+            block(x as isize, y as isize, &mut out);
+            for (row, line) in out.chunks(factor).enumerate() {
+                let start = (y * factor + row) * width + x * factor;
+                pixels[start..start + factor].copy_from_slice(line);
+            }
+        }
+    }
+    Image {
+        pixels,
+        width,
+        height: image.height * factor,
+    }
+}
+
+struct Scale(usize);
+
+impl Filter for Scale {
+    fn apply(&mut self, image: Image) -> Image {
+        upscale(&image, self.0, |x, y, out| out.fill(image.pixel(x, y)))
+    }
+
+    fn factor(&self) -> usize {
+        self.0
+    }
+}
+
+/// EPX / AdvMAME Scale2x and Scale3x
+struct Epx {
+    factor: usize,
+}
+
+impl Epx {
+    fn new(factor: usize) -> Self {
+        Epx { factor }
+    }
+}
+
+impl Filter for Epx {
+    fn apply(&mut self, image: Image) -> Image {
+        let factor = self.factor;
+        upscale(&image, factor, |x, y, out| {
+            let p = |dx, dy| image.pixel(x + dx, y + dy);
+            let (a, b, c) = (p(-1, -1), p(0, -1), p(1, -1));
+            let (d, e, f) = (p(-1, 0), p(0, 0), p(1, 0));
+            let (g, h, i) = (p(-1, 1), p(0, 1), p(1, 1));
+
+            out.fill(e);
+            if b == h || d == f {
+                return;
+            }
+            if factor == 2 {
+                if d == b {
+                    out[0] = d;
+                }
+                if b == f {
+                    out[1] = f;
+                }
+                if d == h {
+                    out[2] = d;
+                }
+                if h == f {
+                    out[3] = f;
+                }
+            } else {
+                if d == b {
+                    out[0] = d;
+                }
+                if (d == b && e != c) || (b == f && e != a) {
+                    out[1] = b;
+                }
+                if b == f {
+                    out[2] = f;
+                }
+                if (d == b && e != g) || (d == h && e != a) {
+                    out[3] = d;
+                }
+                if (b == f && e != i) || (h == f && e != c) {
+                    out[5] = f;
+                }
+                if d == h {
+                    out[6] = d;
+                }
+                if (d == h && e != i) || (h == f && e != g) {
+                    out[7] = h;
+                }
+                if h == f {
+                    out[8] = f;
+                }
+            }
+        })
+    }
+
+    fn factor(&self) -> usize {
+        self.factor
+    }
+}
+
+/// Perceptual distance between two colors, in YUV
+fn distance(a: u32, b: u32) -> u32 {
+    let channel = |color: u32, shift: u32| ((color >> shift) & 0xff) as i32;
+    let (r, g, b) = (
+        channel(a, 16) - channel(b, 16),
+        channel(a, 8) - channel(b, 8),
+        channel(a, 0) - channel(b, 0),
+    );
+    let y = (r * 299 + g * 587 + b * 114) / 1000;
+    let u = (-r * 169 - g * 331 + b * 500) / 1000;
+    let v = (r * 500 - g * 419 - b * 81) / 1000;
+    (48 * y.abs() + 7 * u.abs() + 6 * v.abs()) as u32
+}
+
+/// Level 1 xBR: each corner of a pixel is blended with its neighbor when an
+/// edge goes through it
+struct Xbr2x;
+
+impl Filter for Xbr2x {
+    fn apply(&mut self, image: Image) -> Image {
+        upscale(&image, 2, |x, y, out| {
+            let e = image.pixel(x, y);
+            out.fill(e);
+            // mirroring the neighborhood turns the bottom right corner rule
+            // into the other ones
+            for (corner, (sx, sy)) in [(1, 1), (-1, 1), (1, -1), (-1, -1)].iter().enumerate() {
+                let p = |dx: isize, dy: isize| image.pixel(x + dx * sx, y + dy * sy);
+                let (b, c, d, f) = (p(0, -1), p(1, -1), p(-1, 0), p(1, 0));
+                let (g, h, i) = (p(-1, 1), p(0, 1), p(1, 1));
+                let (f4, h5, i4, i5) = (p(2, 0), p(0, 2), p(2, 1), p(1, 2));
+
+                let edge = distance(e, c)
+                    + distance(e, g)
+                    + distance(i, f4)
+                    + distance(i, h5)
+                    + 4 * distance(h, f);
+                let across = distance(h, d)
+                    + distance(h, i5)
+                    + distance(f, i4)
+                    + distance(f, b)
+                    + 4 * distance(e, i);
+                if edge < across && e != f && e != h {
+                    let neighbor = if distance(e, f) <= distance(e, h) {
+                        f
+                    } else {
+                        h
+                    };
+                    let index = 3 - corner;
+                    out[index] = blend(e, neighbor);
+                }
+            }
+        })
+    }
+
+    fn factor(&self) -> usize {
+        2
+    }
+}
+
+/// Every source pixel becomes a dot, with darker lines between them
+struct LcdGrid(usize);
+
+impl Filter for LcdGrid {
+    fn apply(&mut self, image: Image) -> Image {
+        let factor = self.0;
+        upscale(&image, factor, |x, y, out| {
+            let color = image.pixel(x, y);
+            // 3/4 of the brightness
+            let line = blend(color, blend(color, 0));
+            for (i, pixel) in out.iter_mut().enumerate() {
+                let edge = i % factor == factor - 1 || i / factor == factor - 1;
+                *pixel = if edge { line } else { color };
+            }
+        })
+    }
+
+    fn factor(&self) -> usize {
+        self.0
+    }
+}
+
+/// The DMG LCD is slow to change, mixing each frame with the previous one
+/// shows sprites flickering every other frame as transparent
+struct FrameBlending {
+    previous: Vec<u32>,
+}
+
+impl FrameBlending {
+    fn new() -> Self {
+        FrameBlending {
+            previous: Vec::new(),
+        }
+    }
+}
+
+impl Filter for FrameBlending {
+    fn apply(&mut self, image: Image) -> Image {
+        let pixels = if self.previous.len() == image.pixels.len() {
+            image
+                .pixels
+                .iter()
+                .zip(&self.previous)
+                .map(|(current, previous)| blend(*current, *previous))
+                .collect()
+        } else {
+            image.pixels.clone()
+        };
+        self.previous = image.pixels;
+        Image { pixels, ..image }
+    }
+}
+
+/// CGB LCD colors are washed out compared to the raw RGB555 values
+struct ColorCorrection;
+
+impl Filter for ColorCorrection {
+    fn apply(&mut self, mut image: Image) -> Image {
+        for pixel in image.pixels.iter_mut() {
+            let channel = |shift: u32| (*pixel >> (shift + 3)) & 0x1f;
+            let (r, g, b) = (channel(16), channel(8), channel(0));
+            let red = ((r * 13 + g * 2 + b) >> 1).min(0xff);
+            let green = ((g * 3 + b) << 1).min(0xff);
+            let blue = ((r * 3 + g * 2 + b * 11) >> 1).min(0xff);
+            *pixel = red << 16 | green << 8 | blue;
+        }
+        image
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Image, PostProcessing, FILTER_PRESETS};
+    use std::fs::File;
+
+    const W: u32 = 0xffffff;
+    const B: u32 = 0x000000;
+
+    #[test]
+    fn parses_chains() {
+        for preset in FILTER_PRESETS {
+            assert!(PostProcessing::parse(preset).is_ok(), "{}", preset);
+        }
+        assert!(PostProcessing::parse("scale0").is_err());
+        assert!(PostProcessing::parse("grid1").is_err());
+        assert!(PostProcessing::parse("sharpen").is_err());
+    }
+
+    #[test]
+    fn chains_scalers() {
+        let mut chain = PostProcessing::parse("scale2,scale3").unwrap();
+        assert_eq!(chain.output_size(2, 1), (12, 6));
+        let image = chain.process(&[W, B], 2, 1);
+        assert_eq!((image.width, image.height), (12, 6));
+        assert_eq!(image.pixels[5], W);
+        assert_eq!(image.pixels[6], B);
+    }
+
+    #[test]
+    fn scale2x_rounds_diagonals() {
+        let mut chain = PostProcessing::parse("scale2x").unwrap();
+        // a black diagonal: the white pixels next to it get a black corner
+        #[rustfmt::skip]
+        let screen = [
+            B, W, W,
+            W, B, W,
+            W, W, B,
+        ];
+        let image = chain.process(&screen, 3, 3);
+        // top right pixel of the center row's first pixel
+        assert_eq!(image.pixels[2 * 6 + 1], B);
+        assert_eq!(image.pixels[2 * 6], W);
+    }
+
+    #[test]
+    fn blends_with_previous_frame() {
+        let mut chain = PostProcessing::parse("blend").unwrap();
+        assert_eq!(chain.process(&[W], 1, 1).pixels, [W]);
+        assert_eq!(chain.process(&[B], 1, 1).pixels, [0x7f7f7f]);
+    }
+
+    #[test]
+    fn rejects_a_zero_scale() {
+        let image = Image {
+            pixels: vec![W],
+            width: 1,
+            height: 1,
+        };
+        assert!(image.scale(0).is_err());
+        assert_eq!(image.scale(1).unwrap(), image);
+    }
+
+    #[test]
+    fn saves_png() {
+        let image = Image {
+            pixels: vec![0xff0000, 0x00ff00],
+            width: 2,
+            height: 1,
+        }
+        .scale(2)
+        .unwrap();
+        let path = std::env::temp_dir().join(format!("rs-boy-png-{}.png", std::process::id()));
+        image.save_png(&path).unwrap();
+
+        let decoder = png::Decoder::new(File::open(&path).unwrap());
+        let mut reader = decoder.read_info().unwrap();
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buffer).unwrap();
+        assert_eq!((info.width, info.height), (4, 2));
+        assert_eq!(buffer[..9], [0xff, 0, 0, 0xff, 0, 0, 0, 0xff, 0]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn grid_darkens_dot_edges() {
+        let mut chain = PostProcessing::parse("grid2").unwrap();
+        let image = chain.process(&[W], 1, 1);
+        assert_eq!(image.pixels[0], W);
+        assert_ne!(image.pixels[3], W);
+    }
+}
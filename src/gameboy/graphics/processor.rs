@@ -75,6 +75,14 @@ pub struct Processor {
 
     //Helpers
     pub win_y_counter: u8,
+    /// Internal STAT interrupt line, the OR of all the enabled sources.
+    /// The interrupt is only requested on its rising edge, so a source going
+    /// high while another one is already high is blocked.
+    stat_line: bool,
+    stat_interrupt: bool,
+    /// CGB mode, LCDC.0 is the BG/window master priority and there is no
+    /// STAT write bug
+    pub cgb: bool,
 }
 
 impl MemoryAccessor for Processor {
@@ -84,7 +92,7 @@ impl MemoryAccessor for Processor {
             0xff40 => self.lcd_control,
             0xff41 => {
                 let compare = (self.ly == self.lyc) as u8;
-                self.lcd_status | (compare << 2)
+                0x80 | self.lcd_status | (compare << 2)
             }
             0xff42 => self.scy,
             0xff43 => self.scx,
@@ -111,15 +119,28 @@ impl MemoryAccessor for Processor {
                 }
                 self.lcd_control = value;
             }
-            0xff41 => self.lcd_status = value,
+            0xff41 => {
+                // DMG bug: all the sources are enabled for one cycle before
+                // the value is written, firing in HBlank, VBlank or on LY=LYC
+                let mode = self.lcd_status & 0x03;
+                if !self.cgb
+                    && self.lcd_enabled()
+                    && !self.stat_line
+                    && (mode == Mode::Zero as u8 || mode == Mode::One as u8 || self.ly == self.lyc)
+                {
+                    self.stat_interrupt = true;
+                    self.stat_line = true;
+                }
+
+                self.lcd_status = (self.lcd_status & 0x03) | (value & 0x78);
+                self.update_stat_line(false);
+            }
             0xff42 => self.scy = value,
             0xff43 => self.scx = value,
             0xff45 => {
-                if value == self.ly {
-                    todo!("Do I need to trigger STAT interrupt?");
-                }
                 trace!("LYC: {}", value);
-                self.lyc = value
+                self.lyc = value;
+                self.update_stat_line(false);
             }
             0xff47 => self.bgp = value,
             0xff48 => self.obp0 = value,
@@ -190,29 +211,37 @@ impl Processor {
         tilemap
     }
 
-    pub fn should_trigger_lyc_stat_interrupt(&self) -> bool {
-        self.lcd_status & (1 << 6) > 0 && self.ly == self.lyc
+    fn stat_signal(&self, vblank_start: bool) -> bool {
+        let mode = self.lcd_status & 0x03;
+        let enabled = |bit: u8| self.lcd_status & (1 << bit) > 0;
+
+        (enabled(6) && self.ly == self.lyc)
+            || (enabled(5) && (mode == Mode::Two as u8 || vblank_start))
+            || (enabled(4) && mode == Mode::One as u8)
+            || (enabled(3) && mode == Mode::Zero as u8)
     }
 
-    pub fn should_trigger_mode_stat_interrupt(&self, mode: Mode) -> bool {
-        if self.lcd_status & (1 << 5) > 0 && mode == Mode::Two {
-            return true;
-        }
-        if self.lcd_status & (1 << 4) > 0 && mode == Mode::One {
-            return true;
-        }
-        if self.lcd_status & (1 << 3) > 0 && mode == Mode::Zero {
-            return true;
+    /// Re-evaluates the STAT line, requesting the interrupt on a rising edge.
+    /// `vblank_start` is set on the first dot of line 144, where the mode 2
+    /// source fires as well.
+    pub fn update_stat_line(&mut self, vblank_start: bool) {
+        let signal = self.lcd_enabled() && self.stat_signal(vblank_start);
+        if signal && !self.stat_line {
+            trace!("STAT interrupt, LY {} LYC {}", self.ly, self.lyc);
+            self.stat_interrupt = true;
         }
+        self.stat_line = signal;
+    }
 
-        false
+    pub fn take_stat_interrupt(&mut self) -> bool {
+        std::mem::take(&mut self.stat_interrupt)
     }
 
-    pub fn new() -> Self {
+    pub fn new(cgb: bool) -> Self {
         Processor {
             // scanline: 0,
             lcd_control: 0x91,
-            lcd_status: 0x86, // I start with mode 2 instead of 1 (since ly = 0)
+            lcd_status: 0x02, // I start with mode 2 instead of 1 (since ly = 0)
             scy: 0,
             scx: 0,
             ly: 0,
@@ -224,6 +253,9 @@ impl Processor {
             obp1: 0xff,
 
             win_y_counter: 0,
+            stat_line: false,
+            stat_interrupt: false,
+            cgb,
         }
     }
 }
@@ -0,0 +1,475 @@
+use log::{debug, info};
+
+use super::engine::rgb555_to_rgb;
+use super::{HEIGHT, WIDTH};
+
+pub const SGB_WIDTH: usize = 256;
+pub const SGB_HEIGHT: usize = 224;
+/// Position of the Game Boy screen inside the border
+const SCREEN_X: usize = 48;
+const SCREEN_Y: usize = 40;
+
+/// The screen is split in 20x18 cells of 8x8 pixels, each using one of the 4
+/// palettes
+const CELLS_X: usize = WIDTH / 8;
+const CELLS_Y: usize = HEIGHT / 8;
+
+/// Bytes copied from the screen by a VRAM transfer
+const TRANSFER_SIZE: usize = 0x1000;
+/// Attribute files sent by ATTR_TRN, 2 bits per cell
+const ATTRIBUTE_FILES: usize = 45;
+const ATTRIBUTE_FILE_SIZE: usize = CELLS_X * CELLS_Y / 4;
+/// Border tiles, 4 bits per pixel
+const BORDER_TILES: usize = 256;
+const BORDER_TILE_SIZE: usize = 32;
+
+/// Default palette 0, before the game sends any
+const DEFAULT_PALETTE: [u16; 4] = [0x67bf, 0x265b, 0x10b5, 0x2866];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Transfer {
+    /// PAL_TRN: 512 system palettes
+    Palettes,
+    /// CHR_TRN: half of the border tiles
+    BorderTiles(usize),
+    /// PCT_TRN: border tile map and palettes
+    BorderMap,
+    /// ATTR_TRN: 45 attribute files
+    AttributeFiles,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Mask {
+    None,
+    /// Keep showing the last frame
+    Freeze,
+    Black,
+    /// Fill with color 0
+    Color0,
+}
+
+/// Super Game Boy
+///
+/// Commands are sent by the game through the joypad register, see
+/// `controls::Joypad`. The SGB colorizes the Game Boy screen with 4 palettes
+/// assigned to 8x8 cells, and surrounds it with a 256x224 border.
+pub struct Sgb {
+    palettes: [[u16; 4]; 4],
+    system_palettes: Vec<u16>,
+    /// Palette of each cell
+    attributes: [u8; CELLS_X * CELLS_Y],
+    attribute_files: Vec<u8>,
+    mask: Mask,
+
+    border_tiles: Vec<u8>,
+    /// 32x28 entries: tile (bits 0-7), palette (10-12), X flip (14), Y flip (15)
+    border_map: Vec<u16>,
+    /// Palettes 4-7, 16 colors each
+    border_palettes: Vec<u16>,
+
+    /// Transfer waiting for a frame to be drawn, and frames left to wait
+    transfer: Option<(Transfer, u8)>,
+    frame: Vec<u32>,
+}
+
+impl Sgb {
+    /// Runs a command made of one or more 16 bytes packets
+    pub fn command(&mut self, data: &[u8]) {
+        let command = data[0] >> 3;
+        debug!("SGB command {:#x}", command);
+        match command {
+            0x00 => self.set_palettes(data, 0, 1),
+            0x01 => self.set_palettes(data, 2, 3),
+            0x02 => self.set_palettes(data, 0, 3),
+            0x03 => self.set_palettes(data, 1, 2),
+            0x04 => self.attribute_block(data),
+            0x05 => self.attribute_line(data),
+            0x06 => self.attribute_divide(data),
+            0x07 => self.attribute_characters(data),
+            0x0a => self.set_system_palettes(data),
+            0x0b => self.start_transfer(Transfer::Palettes),
+            0x13 => self.start_transfer(Transfer::BorderTiles((data[1] & 1) as usize)),
+            0x14 => self.start_transfer(Transfer::BorderMap),
+            0x15 => self.start_transfer(Transfer::AttributeFiles),
+            0x16 => {
+                self.apply_attribute_file(data[1] & 0x3f);
+                if data[1] & 0x40 > 0 {
+                    self.mask = Mask::None;
+                }
+            }
+            0x17 => {
+                self.mask = match data[1] & 0x03 {
+                    0 => Mask::None,
+                    1 => Mask::Freeze,
+                    2 => Mask::Black,
+                    _ => Mask::Color0,
+                };
+                debug!("SGB mask: {:?}", self.mask);
+            }
+            _ => debug!("Ignoring SGB command {:#x}", command),
+        }
+    }
+
+    /// PAL01 etc: color 0 is shared by all the palettes
+    fn set_palettes(&mut self, data: &[u8], first: usize, second: usize) {
+        let color = |index: usize| u16::from_le_bytes([data[1 + index * 2], data[2 + index * 2]]);
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color(0);
+        }
+        for i in 1..4 {
+            self.palettes[first][i] = color(i);
+            self.palettes[second][i] = color(i + 3);
+        }
+    }
+
+    fn set_system_palettes(&mut self, data: &[u8]) {
+        for (palette, bytes) in self.palettes.iter_mut().zip(data[1..9].chunks(2)) {
+            let index = u16::from_le_bytes([bytes[0], bytes[1]]) as usize & 0x1ff;
+            palette.copy_from_slice(&self.system_palettes[index * 4..index * 4 + 4]);
+        }
+        let color0 = self.palettes[0][0];
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color0;
+        }
+
+        if data[9] & 0x80 > 0 {
+            self.apply_attribute_file(data[9] & 0x3f);
+        }
+        if data[9] & 0x40 > 0 {
+            self.mask = Mask::None;
+        }
+    }
+
+    fn set_cell(&mut self, x: usize, y: usize, palette: u8) {
+        if x < CELLS_X && y < CELLS_Y {
+            self.attributes[y * CELLS_X + x] = palette & 0x03;
+        }
+    }
+
+    /// ATTR_BLK: palettes inside, on the border and outside of rectangles
+    fn attribute_block(&mut self, data: &[u8]) {
+        let count = data[1] as usize;
+        for set in data[2..].chunks_exact(6).take(count) {
+            let control = set[0] & 0x07;
+            let inside = set[1] & 0x03;
+            let mut border = (set[1] >> 2) & 0x03;
+            let outside = (set[1] >> 4) & 0x03;
+            let (x1, y1, x2, y2) = (
+                set[2] as usize,
+                set[3] as usize,
+                set[4] as usize,
+                set[5] as usize,
+            );
+
+            // changing only one side also changes the border line
+            let mut change_border = control & 0x02 > 0;
+            if control == 0x01 {
+                change_border = true;
+                border = inside;
+            } else if control == 0x04 {
+                change_border = true;
+                border = outside;
+            }
+
+            for y in 0..CELLS_Y {
+                for x in 0..CELLS_X {
+                    let within = x >= x1 && x <= x2 && y >= y1 && y <= y2;
+                    let on_border = within && (x == x1 || x == x2 || y == y1 || y == y2);
+                    if on_border {
+                        if change_border {
+                            self.set_cell(x, y, border);
+                        }
+                    } else if within {
+                        if control & 0x01 > 0 {
+                            self.set_cell(x, y, inside);
+                        }
+                    } else if control & 0x04 > 0 {
+                        self.set_cell(x, y, outside);
+                    }
+                }
+            }
+        }
+    }
+
+    /// ATTR_LIN: whole rows or columns
+    fn attribute_line(&mut self, data: &[u8]) {
+        let count = data[1] as usize;
+        for &line in data[2..].iter().take(count) {
+            let index = (line & 0x1f) as usize;
+            let palette = (line >> 5) & 0x03;
+            if line & 0x80 > 0 {
+                for x in 0..CELLS_X {
+                    self.set_cell(x, index, palette);
+                }
+            } else {
+                for y in 0..CELLS_Y {
+                    self.set_cell(index, y, palette);
+                }
+            }
+        }
+    }
+
+    /// ATTR_DIV: splits the screen in two by a line
+    fn attribute_divide(&mut self, data: &[u8]) {
+        let after = data[1] & 0x03;
+        let before = (data[1] >> 2) & 0x03;
+        let on_line = (data[1] >> 4) & 0x03;
+        let horizontal = data[1] & 0x40 > 0;
+        let split = data[2] as usize;
+
+        for y in 0..CELLS_Y {
+            for x in 0..CELLS_X {
+                let position = if horizontal { y } else { x };
+                let palette = match position.cmp(&split) {
+                    std::cmp::Ordering::Less => before,
+                    std::cmp::Ordering::Equal => on_line,
+                    std::cmp::Ordering::Greater => after,
+                };
+                self.set_cell(x, y, palette);
+            }
+        }
+    }
+
+    /// ATTR_CHR: cell by cell, 4 cells per byte
+    fn attribute_characters(&mut self, data: &[u8]) {
+        let (mut x, mut y) = (data[1] as usize, data[2] as usize);
+        let count = u16::from_le_bytes([data[3], data[4]]) as usize;
+        let vertical = data[5] & 0x01 > 0;
+
+        for i in 0..count.min(CELLS_X * CELLS_Y) {
+            let Some(byte) = data.get(6 + i / 4) else {
+                break;
+            };
+            let palette = byte >> (6 - (i % 4) * 2);
+            self.set_cell(x, y, palette);
+
+            if vertical {
+                y += 1;
+                if y == CELLS_Y {
+                    y = 0;
+                    x += 1;
+                }
+            } else {
+                x += 1;
+                if x == CELLS_X {
+                    x = 0;
+                    y += 1;
+                }
+            }
+        }
+    }
+
+    fn apply_attribute_file(&mut self, file: u8) {
+        let file = file as usize;
+        if file >= ATTRIBUTE_FILES {
+            return;
+        }
+        let start = file * ATTRIBUTE_FILE_SIZE;
+        for (cell, attribute) in self.attributes.iter_mut().enumerate() {
+            let byte = self.attribute_files[start + cell / 4];
+            *attribute = (byte >> (6 - (cell % 4) * 2)) & 0x03;
+        }
+    }
+
+    /// The data is read from the screen once the game had the time to draw it
+    fn start_transfer(&mut self, transfer: Transfer) {
+        debug!("SGB transfer {:?}", transfer);
+        self.transfer = Some((transfer, 1));
+    }
+
+    /// Bytes drawn on screen as tiles 0-255, 20 per row, with BGP = 0xE4
+    fn screen_data(shades: &[u8]) -> Vec<u8> {
+        let mut data = Vec::with_capacity(TRANSFER_SIZE);
+        for tile in 0..TRANSFER_SIZE / 16 {
+            let (tile_x, tile_y) = (tile % CELLS_X * 8, tile / CELLS_X * 8);
+            for row in 0..8 {
+                let (mut low, mut high) = (0, 0);
+                for x in 0..8 {
+                    let shade = shades[(tile_y + row) * WIDTH + tile_x + x];
+                    low |= (shade & 1) << (7 - x);
+                    high |= (shade >> 1) << (7 - x);
+                }
+                data.push(low);
+                data.push(high);
+            }
+        }
+        data
+    }
+
+    fn run_transfer(&mut self, transfer: Transfer, shades: &[u8]) {
+        let data = Self::screen_data(shades);
+        let words = || data.chunks(2).map(|w| u16::from_le_bytes([w[0], w[1]]));
+        match transfer {
+            Transfer::Palettes => {
+                self.system_palettes = words().collect();
+            }
+            Transfer::BorderTiles(half) => {
+                let start = half * TRANSFER_SIZE;
+                self.border_tiles[start..start + TRANSFER_SIZE].copy_from_slice(&data);
+            }
+            Transfer::BorderMap => {
+                self.border_map = words().take(32 * 28).collect();
+                self.border_palettes = words().skip(0x400).take(64).collect();
+            }
+            Transfer::AttributeFiles => {
+                self.attribute_files
+                    .copy_from_slice(&data[..ATTRIBUTE_FILES * ATTRIBUTE_FILE_SIZE]);
+            }
+        }
+        info!("SGB transfer {:?} completed", transfer);
+    }
+
+    /// Color of a border pixel, None if transparent
+    fn border_pixel(&self, x: usize, y: usize) -> Option<u16> {
+        let entry = self.border_map[(y / 8) * 32 + x / 8];
+        let tile = (entry & 0xff) as usize;
+        let palette = ((entry >> 10) & 0x07) as usize;
+        let column = if entry & (1 << 14) > 0 {
+            x % 8
+        } else {
+            7 - x % 8
+        };
+        let row = if entry & (1 << 15) > 0 {
+            7 - y % 8
+        } else {
+            y % 8
+        };
+
+        let data = &self.border_tiles[tile * BORDER_TILE_SIZE..(tile + 1) * BORDER_TILE_SIZE];
+        let color = (0..4).fold(0, |color, plane| {
+            let byte = data[(plane / 2) * 16 + row * 2 + plane % 2];
+            color | ((byte >> column) & 1) << plane
+        }) as usize;
+
+        if color == 0 {
+            return None;
+        }
+        // palettes 4-7 are the ones sent by PCT_TRN
+        let index = palette.saturating_sub(4) * 16 + color;
+        Some(self.border_palettes[index])
+    }
+
+    /// Builds the 256x224 output from the shades of the frame that was just
+    /// drawn, running any pending transfer
+    pub fn frame_completed(&mut self, shades: &[u8]) -> &[u32] {
+        if let Some((transfer, frames)) = self.transfer {
+            if frames == 0 {
+                self.transfer = None;
+                self.run_transfer(transfer, shades);
+            } else {
+                self.transfer = Some((transfer, frames - 1));
+            }
+        }
+
+        let backdrop = self.palettes[0][0];
+        for y in 0..SGB_HEIGHT {
+            for x in 0..SGB_WIDTH {
+                let in_screen = (SCREEN_X..SCREEN_X + WIDTH).contains(&x)
+                    && (SCREEN_Y..SCREEN_Y + HEIGHT).contains(&y);
+                let color = match self.border_pixel(x, y) {
+                    Some(color) => color,
+                    None if !in_screen => backdrop,
+                    None => {
+                        let (screen_x, screen_y) = (x - SCREEN_X, y - SCREEN_Y);
+                        match self.mask {
+                            Mask::Freeze => continue,
+                            Mask::Black => 0,
+                            Mask::Color0 => backdrop,
+                            Mask::None => {
+                                let cell = (screen_y / 8) * CELLS_X + screen_x / 8;
+                                let palette = self.attributes[cell] as usize;
+                                let shade = shades[screen_y * WIDTH + screen_x] as usize;
+                                self.palettes[palette][shade]
+                            }
+                        }
+                    }
+                };
+                self.frame[y * SGB_WIDTH + x] = rgb555_to_rgb(color);
+            }
+        }
+        &self.frame
+    }
+
+    pub fn new() -> Self {
+        Sgb {
+            palettes: [DEFAULT_PALETTE; 4],
+            system_palettes: vec![0; TRANSFER_SIZE / 2],
+            attributes: [0; CELLS_X * CELLS_Y],
+            attribute_files: vec![0; ATTRIBUTE_FILES * ATTRIBUTE_FILE_SIZE],
+            mask: Mask::None,
+
+            border_tiles: vec![0; BORDER_TILES * BORDER_TILE_SIZE],
+            border_map: vec![0; 32 * 28],
+            border_palettes: vec![0; 64],
+
+            transfer: None,
+            frame: vec![0; SGB_WIDTH * SGB_HEIGHT],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Sgb, CELLS_X};
+
+    fn packet(command: u8, data: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0; 16];
+        packet[0] = command << 3 | 1;
+        packet[1..1 + data.len()].copy_from_slice(data);
+        packet
+    }
+
+    #[test]
+    fn pal01_shares_color_0() {
+        let mut sgb = Sgb::new();
+        sgb.command(&packet(
+            0x00,
+            &[0x1f, 0, 1, 0, 2, 0, 3, 0, 4, 0, 5, 0, 6, 0],
+        ));
+        assert_eq!(sgb.palettes[0], [0x1f, 1, 2, 3]);
+        assert_eq!(sgb.palettes[1], [0x1f, 4, 5, 6]);
+        assert_eq!(sgb.palettes[3][0], 0x1f);
+    }
+
+    #[test]
+    fn attr_blk_inside_only_changes_border() {
+        let mut sgb = Sgb::new();
+        sgb.command(&packet(0x04, &[1, 0x01, 0x02, 1, 1, 3, 3]));
+        assert_eq!(sgb.attributes[CELLS_X + 1], 2);
+        assert_eq!(sgb.attributes[2 * CELLS_X + 2], 2);
+        assert_eq!(sgb.attributes[0], 0);
+        assert_eq!(sgb.attributes[4 * CELLS_X + 4], 0);
+    }
+
+    #[test]
+    fn attr_div_splits_screen() {
+        let mut sgb = Sgb::new();
+        // vertical line at X = 5: left 1, line 2, right 3
+        sgb.command(&packet(0x06, &[0x27, 5]));
+        assert_eq!(sgb.attributes[4], 1);
+        assert_eq!(sgb.attributes[5], 2);
+        assert_eq!(sgb.attributes[CELLS_X + 6], 3);
+    }
+
+    #[test]
+    fn attr_chr_goes_left_to_right() {
+        let mut sgb = Sgb::new();
+        sgb.command(&packet(0x07, &[19, 0, 3, 0, 0, 0b1110_0100]));
+        assert_eq!(sgb.attributes[19], 3);
+        assert_eq!(sgb.attributes[CELLS_X], 2);
+        assert_eq!(sgb.attributes[CELLS_X + 1], 1);
+    }
+
+    #[test]
+    fn transfers_palettes_from_screen() {
+        let mut sgb = Sgb::new();
+        sgb.command(&packet(0x0b, &[]));
+        // first row of the first tile: shades 3 2 1 0 0 0 0 0
+        let mut shades = vec![0; 160 * 144];
+        shades[..4].copy_from_slice(&[3, 2, 1, 0]);
+        sgb.frame_completed(&shades);
+        assert_eq!(sgb.system_palettes[0], 0);
+        sgb.frame_completed(&shades);
+        assert_eq!(sgb.system_palettes[0], 0b1100_0000_1010_0000);
+    }
+}
@@ -7,6 +7,8 @@ pub struct Tile {
     /// 6 - Y flip: 0 = Normal, 1 = Entire OBJ is vertically mirrored
     /// 5 - X flip: 0 = Normal, 1 = Entire OBJ is horizontally mirrored
     /// 4- DMG palette [Non CGB Mode only]: 0 = OBP0, 1 = OBP1
+    /// 3 - Tile VRAM bank [CGB Mode only]
+    /// 0-2 - Palette [CGB Mode only]
     pub flags: u8,
 }
 
@@ -23,6 +25,22 @@ impl Tile {
         false
     }
 
+    /// Tile and row within it to draw on `scanline`. In 8x16 mode the top
+    /// tile has the index with bit 0 cleared, and flipping swaps both tiles.
+    pub fn tile_row(&self, scanline: u8, double_size: bool) -> (u8, usize) {
+        let height = if double_size { 16 } else { 8 };
+        let mut row = (scanline as usize + 16 - self.y as usize) % height;
+        if self.is_y_flipped() {
+            row = height - 1 - row;
+        }
+
+        if double_size {
+            (self.tile_index & 0xfe | (row / 8) as u8, row % 8)
+        } else {
+            (self.tile_index, row)
+        }
+    }
+
     pub fn is_x_flipped(&self) -> bool {
         self.flags & 1 << 5 > 0
     }
@@ -31,6 +49,18 @@ impl Tile {
         self.flags & 1 << 6 > 0
     }
 
+    pub fn dmg_palette(&self) -> u8 {
+        (self.flags >> 4) & 1
+    }
+
+    pub fn cgb_palette(&self) -> u8 {
+        self.flags & 0x07
+    }
+
+    pub fn bank(&self) -> usize {
+        ((self.flags >> 3) & 1) as usize
+    }
+
     pub fn has_priority(&self) -> bool {
         self.flags & 1 << 7 == 0
     }
@@ -95,4 +125,16 @@ mod tests {
         }
         assert!(!t.object_in_scanline(144 - 16 + 16, false));
     }
+
+    #[test]
+    fn tile_row_double_flipped() {
+        let t = Tile::new(16, 7, 5, 0);
+        assert_eq!(t.tile_row(0, true), (4, 0));
+        assert_eq!(t.tile_row(9, true), (4 | 1, 1));
+
+        let t = Tile::new(16, 7, 5, 1 << 6);
+        assert_eq!(t.tile_row(0, true), (5, 7));
+        assert_eq!(t.tile_row(15, true), (4, 0));
+        assert_eq!(t.tile_row(3, false), (5, 4));
+    }
 }
@@ -0,0 +1,370 @@
+use super::dmg_palette::Layer;
+use super::engine::{rgb555_to_rgb, use_palette};
+use super::{Display, Image, Tile, TILE_DATA_SIZE, TILE_MAPS_SIZE};
+
+/// Tiles in a VRAM bank
+const TILES: usize = 384;
+/// Tiles per row of the tile data view
+const TILES_PER_ROW: usize = 16;
+/// Size of a tile map in pixels
+const MAP_SIZE: usize = 256;
+/// Space between the views of two banks or maps
+const GAP: usize = 8;
+/// Cells of the OAM view, an 8x16 object with a margin around it
+const OAM_CELL_WIDTH: usize = 16;
+const OAM_CELL_HEIGHT: usize = 24;
+const OAM_PER_ROW: usize = 8;
+const SWATCH_SIZE: usize = 16;
+
+const BACKDROP: u32 = 0x202020;
+const VIEWPORT_COLOR: u32 = 0xff0000;
+const WINDOW_COLOR: u32 = 0x0080ff;
+
+/// Palette used to show tiles. On DMG the background palettes are all BGP
+/// and the object ones OBP0/OBP1, on CGB they are the 8 palettes of each.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TilePalette {
+    /// Color indexes as shades of gray, whatever the palettes
+    Raw,
+    Background(u8),
+    Object(u8),
+}
+
+impl TilePalette {
+    /// Raw, then the 8 background palettes, then the 8 object ones
+    pub fn next(self) -> Self {
+        match self {
+            TilePalette::Raw => TilePalette::Background(0),
+            TilePalette::Background(7) => TilePalette::Object(0),
+            TilePalette::Background(n) => TilePalette::Background(n + 1),
+            TilePalette::Object(7) => TilePalette::Raw,
+            TilePalette::Object(n) => TilePalette::Object(n + 1),
+        }
+    }
+}
+
+/// What the VRAM debug views show
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VramView {
+    /// The 384 tiles of each bank in a grid
+    Tiles(TilePalette),
+    /// Both tile maps side by side, with the SCX/SCY viewport (red) and the
+    /// window area (blue) drawn over the maps they use
+    TileMaps,
+    /// The 40 objects in OAM order, 8 per row
+    Oam,
+    /// BGP/OBP0/OBP1 on DMG, the 8 background and 8 object palettes on CGB
+    Palettes,
+}
+
+impl VramView {
+    /// `tiles`, `maps`, `oam` or `palettes`
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "tiles" => Some(VramView::Tiles(TilePalette::Raw)),
+            "maps" => Some(VramView::TileMaps),
+            "oam" => Some(VramView::Oam),
+            "palettes" => Some(VramView::Palettes),
+            _ => None,
+        }
+    }
+
+    pub fn next(self) -> Self {
+        match self {
+            VramView::Tiles(_) => VramView::TileMaps,
+            VramView::TileMaps => VramView::Oam,
+            VramView::Oam => VramView::Palettes,
+            VramView::Palettes => VramView::Tiles(TilePalette::Raw),
+        }
+    }
+}
+
+fn blank_image(width: usize, height: usize) -> Image {
+    Image {
+        pixels: vec![BACKDROP; width * height],
+        width,
+        height,
+    }
+}
+
+impl Image {
+    fn set(&mut self, x: usize, y: usize, color: u32) {
+        self.pixels[y * self.width + x] = color;
+    }
+
+    /// Outline of a rectangle, wrapping around in the `size` wide square area
+    /// starting at `left`
+    fn wrapping_rect(
+        &mut self,
+        area: (usize, usize),
+        (x, y): (usize, usize),
+        (w, h): (usize, usize),
+        color: u32,
+    ) {
+        let (left, size) = area;
+        for dx in 0..w {
+            self.set(left + (x + dx) % size, y % size, color);
+            self.set(left + (x + dx) % size, (y + h - 1) % size, color);
+        }
+        for dy in 0..h {
+            self.set(left + x % size, (y + dy) % size, color);
+            self.set(left + (x + w - 1) % size, (y + dy) % size, color);
+        }
+    }
+}
+
+impl Display {
+    /// Renders a debug view of the VRAM, OAM or palettes
+    pub fn vram_view(&self, view: VramView) -> Image {
+        match view {
+            VramView::Tiles(palette) => self.tiles_image(palette),
+            VramView::TileMaps => self.tile_maps_image(),
+            VramView::Oam => self.oam_image(),
+            VramView::Palettes => self.palettes_image(),
+        }
+    }
+
+    /// The 40 entries of OAM
+    pub fn oam_entries(&self) -> Vec<Tile> {
+        (0..40).map(|object| self.get_oam_object(object)).collect()
+    }
+
+    fn debug_color(&self, palette: TilePalette, index: u8) -> u32 {
+        let palettes = &self.engine.palettes;
+        match palette {
+            TilePalette::Raw => palettes.color(Layer::Background, index),
+            TilePalette::Background(n) if self.cgb => {
+                rgb555_to_rgb(self.palettes.background(n & 0x07, index))
+            }
+            TilePalette::Object(n) if self.cgb => {
+                rgb555_to_rgb(self.palettes.object(n & 0x07, index))
+            }
+            TilePalette::Background(_) => {
+                palettes.color(Layer::Background, use_palette(self.processor.bgp, index))
+            }
+            TilePalette::Object(n) if n & 1 == 0 => {
+                palettes.color(Layer::Object0, use_palette(self.processor.obp0, index))
+            }
+            TilePalette::Object(_) => {
+                palettes.color(Layer::Object1, use_palette(self.processor.obp1, index))
+            }
+        }
+    }
+
+    /// Color index of a pixel of a tile, `tile` counting from 8000
+    fn tile_pixel(&self, bank: usize, tile: usize, x: usize, y: usize) -> u8 {
+        let offset = bank * TILE_DATA_SIZE + tile * 16 + y * 2;
+        let (low, high) = (self.tile_data[offset], self.tile_data[offset + 1]);
+        let bit = 7 - x;
+        (((high >> bit) & 1) << 1) | ((low >> bit) & 1)
+    }
+
+    fn tiles_image(&self, palette: TilePalette) -> Image {
+        let banks = if self.cgb { 2 } else { 1 };
+        let bank_width = TILES_PER_ROW * 8;
+        let mut image = blank_image(
+            banks * bank_width + (banks - 1) * GAP,
+            TILES / TILES_PER_ROW * 8,
+        );
+        for bank in 0..banks {
+            for tile in 0..TILES {
+                let left = bank * (bank_width + GAP) + tile % TILES_PER_ROW * 8;
+                let top = tile / TILES_PER_ROW * 8;
+                for y in 0..8 {
+                    for x in 0..8 {
+                        let index = self.tile_pixel(bank, tile, x, y);
+                        image.set(left + x, top + y, self.debug_color(palette, index));
+                    }
+                }
+            }
+        }
+        image
+    }
+
+    fn tile_maps_image(&self) -> Image {
+        let mut image = blank_image(2 * MAP_SIZE + GAP, MAP_SIZE);
+        let baseline = self.processor.get_tile_data_baseline();
+        for map in 0..2 {
+            let left = map * (MAP_SIZE + GAP);
+            for entry in 0..32 * 32 {
+                let offset = map * 0x400 + entry;
+                let id = self.tile_maps[offset];
+                let attributes = if self.cgb {
+                    self.tile_maps[TILE_MAPS_SIZE + offset]
+                } else {
+                    0
+                };
+                let bank = ((attributes >> 3) & 1) as usize;
+                let palette = TilePalette::Background(attributes & 0x07);
+                let (x_flip, y_flip) = (attributes & (1 << 5) > 0, attributes & (1 << 6) > 0);
+
+                for y in 0..8 {
+                    let row = if y_flip { 7 - y } else { y };
+                    let (low, high) = self.get_tile_data(bank, baseline, id, row);
+                    for x in 0..8 {
+                        let pixel_x = if x_flip { 7 - x } else { x };
+                        let bit = 7 - pixel_x;
+                        let index = (((high >> bit) & 1) << 1) | ((low >> bit) & 1);
+                        image.set(
+                            left + entry % 32 * 8 + x,
+                            entry / 32 * 8 + y,
+                            self.debug_color(palette, index),
+                        );
+                    }
+                }
+            }
+        }
+
+        let processor = &self.processor;
+        let map_left = |address: usize| (address - 0x9800) / 0x400 * (MAP_SIZE + GAP);
+        if processor.is_window_enabled() && processor.wx <= 166 && processor.wy < 144 {
+            let size = (
+                (167 - processor.wx as usize).min(MAP_SIZE),
+                144 - processor.wy as usize,
+            );
+            let left = map_left(processor.get_tile_map(true));
+            image.wrapping_rect((left, MAP_SIZE), (0, 0), size, WINDOW_COLOR);
+        }
+        let left = map_left(processor.get_tile_map(false));
+        let position = (processor.scx as usize, processor.scy as usize);
+        image.wrapping_rect((left, MAP_SIZE), position, (160, 144), VIEWPORT_COLOR);
+        image
+    }
+
+    fn oam_image(&self) -> Image {
+        let rows = 40 / OAM_PER_ROW;
+        let mut image = blank_image(OAM_PER_ROW * OAM_CELL_WIDTH, rows * OAM_CELL_HEIGHT);
+        let double_size = self.processor.is_object_double_size();
+        let height = if double_size { 16 } else { 8 };
+        for (i, object) in self.oam_entries().iter().enumerate() {
+            let left = i % OAM_PER_ROW * OAM_CELL_WIDTH + 4;
+            let top = i / OAM_PER_ROW * OAM_CELL_HEIGHT + 4;
+            let (bank, palette) = if self.cgb {
+                (object.bank(), TilePalette::Object(object.cgb_palette()))
+            } else {
+                (0, TilePalette::Object(object.dmg_palette()))
+            };
+            for y in 0..height {
+                // as if the object was drawn from line 0
+                let (tile, row) = Tile::new(16, 0, object.tile_index, object.flags)
+                    .tile_row(y as u8, double_size);
+                for x in 0..8 {
+                    let pixel_x = if object.is_x_flipped() { 7 - x } else { x };
+                    let index = self.tile_pixel(bank, tile as usize, pixel_x, row);
+                    if index != 0 {
+                        image.set(left + x, top + y, self.debug_color(palette, index));
+                    }
+                }
+            }
+        }
+        image
+    }
+
+    fn palettes_image(&self) -> Image {
+        let palettes = if self.cgb { 8 } else { 1 };
+        let (columns, rows) = if self.cgb { (2, 8) } else { (1, 3) };
+        let block_width = 4 * SWATCH_SIZE;
+        let mut image = blank_image(
+            columns * block_width + (columns - 1) * GAP,
+            rows * SWATCH_SIZE,
+        );
+
+        let mut swatches = Vec::new();
+        for n in 0..palettes {
+            swatches.push((0, n, TilePalette::Background(n as u8)));
+        }
+        if self.cgb {
+            for n in 0..8 {
+                swatches.push((1, n, TilePalette::Object(n as u8)));
+            }
+        } else {
+            swatches.push((0, 1, TilePalette::Object(0)));
+            swatches.push((0, 2, TilePalette::Object(1)));
+        }
+
+        for (column, row, palette) in swatches {
+            for index in 0..4 {
+                let color = self.debug_color(palette, index);
+                let left = column * (block_width + GAP) + index as usize * SWATCH_SIZE;
+                for y in 0..SWATCH_SIZE {
+                    for x in 0..SWATCH_SIZE {
+                        image.set(left + x, row * SWATCH_SIZE + y, color);
+                    }
+                }
+            }
+        }
+        image
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TilePalette, VramView, BACKDROP, VIEWPORT_COLOR};
+    use crate::gameboy::graphics::Display;
+    use crate::gameboy::memory_bus::MemoryAccessor;
+
+    #[test]
+    fn tiles_through_palette() {
+        let mut display = Display::new(false);
+        // tile 1, first row: color 3 for the leftmost pixel
+        display.write(0x8010, 0x80);
+        display.write(0x8011, 0x80);
+        // color 3 shown as white
+        display.write(0xff47, 0x00);
+
+        let raw = display.vram_view(VramView::Tiles(TilePalette::Raw));
+        assert_eq!((raw.width, raw.height), (128, 192));
+        assert_eq!(raw.pixels[8], 0x000000);
+        assert_eq!(raw.pixels[9], 0xffffff);
+
+        let bgp = display.vram_view(VramView::Tiles(TilePalette::Background(0)));
+        assert_eq!(bgp.pixels[8], 0xffffff);
+    }
+
+    #[test]
+    fn tile_maps_show_viewport() {
+        let mut display = Display::new(false);
+        display.write(0xff42, 8);
+        display.write(0xff43, 250);
+
+        let image = display.vram_view(VramView::TileMaps);
+        assert_eq!((image.width, image.height), (520, 256));
+        // BG map at 9800, the viewport wrapping around horizontally
+        assert_eq!(image.pixels[8 * 520 + 250], VIEWPORT_COLOR);
+        assert_eq!(image.pixels[8 * 520 + 10], VIEWPORT_COLOR);
+        assert_eq!(image.pixels[151 * 520 + 100], VIEWPORT_COLOR);
+        assert_ne!(image.pixels[9 * 520 + 100], VIEWPORT_COLOR);
+        assert_ne!(image.pixels[8 * 520 + 264 + 250], VIEWPORT_COLOR);
+    }
+
+    #[test]
+    fn oam_entries_decoded() {
+        let mut display = Display::new(false);
+        display.oam[4..8].copy_from_slice(&[16, 8, 1, 1 << 5]);
+        // tile 1: color 1 on the leftmost pixel
+        display.write(0x8010, 0x80);
+
+        let entries = display.oam_entries();
+        assert_eq!(entries.len(), 40);
+        assert_eq!((entries[1].x, entries[1].tile_index), (8, 1));
+        assert!(entries[1].is_x_flipped());
+
+        let image = display.vram_view(VramView::Oam);
+        // second cell, flipped: the pixel is on the right
+        assert_eq!(image.pixels[4 * 128 + 16 + 4], BACKDROP);
+        assert_ne!(image.pixels[4 * 128 + 16 + 4 + 7], BACKDROP);
+    }
+
+    #[test]
+    fn cgb_palettes() {
+        let mut display = Display::new(true);
+        // object palette 2, color 1: red
+        display.write(0xff6a, 0x80 | 0x12);
+        display.write(0xff6b, 0x1f);
+        display.write(0xff6b, 0x00);
+
+        let image = display.vram_view(VramView::Palettes);
+        assert_eq!((image.width, image.height), (136, 128));
+        assert_eq!(image.pixels[2 * 16 * 136 + 72 + 16], 0xff0000);
+    }
+}
@@ -0,0 +1,147 @@
+use log::{debug, trace};
+
+use super::memory_bus::MemoryAccessor;
+
+/// Bytes copied per block, a block being copied every HBlank
+pub const BLOCK_LENGTH: usize = 0x10;
+
+/// CGB VRAM DMA (HDMA1-HDMA5, FF51-FF55)
+///
+/// Writing FF55 starts a transfer of `(value & 0x7f) + 1` blocks. With bit 7
+/// cleared it is a general purpose DMA, copying everything at once while the
+/// CPU is stalled. With bit 7 set a block is copied at the start of every
+/// HBlank, until the transfer completes or bit 7 is cleared again.
+pub struct Hdma {
+    cgb: bool,
+    source: usize,
+    destination: usize,
+
+    /// Blocks left to copy
+    remaining: usize,
+    hblank: bool,
+    /// A general purpose transfer waiting for the current step to end
+    general: bool,
+}
+
+impl Hdma {
+    /// Blocks to copy now: all of them for a general purpose DMA, one on
+    /// HBlank. Returns the (source, destination) of each byte.
+    pub fn transfer(&mut self, hblank_started: bool) -> Vec<(usize, usize)> {
+        let blocks = if self.general {
+            self.general = false;
+            self.remaining
+        } else if self.hblank && hblank_started {
+            1
+        } else {
+            0
+        };
+
+        let mut bytes = Vec::with_capacity(blocks * BLOCK_LENGTH);
+        for _ in 0..blocks {
+            trace!("VRAM DMA {:#x} -> {:#x}", self.source, self.destination);
+            for i in 0..BLOCK_LENGTH {
+                bytes.push((self.source + i, self.destination + i));
+            }
+            self.source += BLOCK_LENGTH;
+            self.destination = 0x8000 | ((self.destination + BLOCK_LENGTH) & 0x1ff0);
+            self.remaining -= 1;
+        }
+        if self.remaining == 0 {
+            self.hblank = false;
+        }
+        bytes
+    }
+
+    pub fn new(cgb: bool) -> Self {
+        Hdma {
+            cgb,
+            source: 0,
+            destination: 0x8000,
+            remaining: 0,
+            hblank: false,
+            general: false,
+        }
+    }
+}
+
+impl MemoryAccessor for Hdma {
+    fn get(&self, location: usize) -> u8 {
+        match location {
+            // only the remaining length can be read
+            0xff55 if self.cgb => {
+                let inactive = (!self.hblank as u8) << 7;
+                inactive | (self.remaining.wrapping_sub(1) & 0x7f) as u8
+            }
+            _ => 0xff,
+        }
+    }
+
+    fn write(&mut self, location: usize, value: u8) {
+        if !self.cgb {
+            return;
+        }
+        match location {
+            0xff51 => self.source = (self.source & 0x00ff) | (value as usize) << 8,
+            0xff52 => self.source = (self.source & 0xff00) | (value & 0xf0) as usize,
+            0xff53 => {
+                self.destination =
+                    0x8000 | (self.destination & 0x00ff) | ((value & 0x1f) as usize) << 8
+            }
+            0xff54 => self.destination = (self.destination & 0xff00) | (value & 0xf0) as usize,
+            0xff55 => {
+                if self.hblank && value & 0x80 == 0 {
+                    debug!("HBlank DMA cancelled");
+                    self.hblank = false;
+                    return;
+                }
+                self.remaining = (value & 0x7f) as usize + 1;
+                if value & 0x80 > 0 {
+                    debug!("HBlank DMA of {} blocks", self.remaining);
+                    self.hblank = true;
+                } else {
+                    debug!("General purpose DMA of {} blocks", self.remaining);
+                    self.general = true;
+                }
+            }
+            _ => panic!("HDMA location write: {:#x}", location),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Hdma;
+    use crate::gameboy::memory_bus::MemoryAccessor;
+
+    fn hdma(length: u8) -> Hdma {
+        let mut hdma = Hdma::new(true);
+        hdma.write(0xff51, 0xc1);
+        hdma.write(0xff52, 0x23);
+        hdma.write(0xff53, 0x91);
+        hdma.write(0xff54, 0x08);
+        hdma.write(0xff55, length);
+        hdma
+    }
+
+    #[test]
+    fn general_purpose_copies_everything() {
+        let mut hdma = hdma(0x01);
+        let bytes = hdma.transfer(false);
+        assert_eq!(bytes.len(), 0x20);
+        assert_eq!(bytes[0], (0xc120, 0x9100));
+        assert_eq!(bytes[0x1f], (0xc13f, 0x911f));
+        assert_eq!(hdma.get(0xff55), 0xff);
+    }
+
+    #[test]
+    fn hblank_copies_a_block_per_hblank() {
+        let mut hdma = hdma(0x81);
+        assert!(hdma.transfer(false).is_empty());
+        assert_eq!(hdma.transfer(true).len(), 0x10);
+        assert_eq!(hdma.get(0xff55), 0x00);
+
+        hdma.write(0xff55, 0x00);
+        assert!(hdma.transfer(true).is_empty());
+        assert_eq!(hdma.get(0xff55), 0x80);
+    }
+}
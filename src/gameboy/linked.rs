@@ -0,0 +1,66 @@
+use super::{GameBoy, VirtualCable};
+
+/// Two consoles connected by a link cable, running in lockstep.
+///
+/// The console that is behind is always stepped next, so both stay within one
+/// instruction of each other and serial bits are exchanged at the right time.
+/// This makes multiplayer fully deterministic.
+pub struct LinkedGameBoys {
+    consoles: [GameBoy; 2],
+    /// Time run by each console, in normal speed cycles: a console in double
+    /// speed runs two CPU cycles per cycle
+    cycles: [u64; 2],
+}
+
+impl LinkedGameBoys {
+    /// Runs a single instruction on the console that is behind
+    pub fn step(&mut self) {
+        let index = if self.cycles[0] <= self.cycles[1] {
+            0
+        } else {
+            1
+        };
+        let console = &mut self.consoles[index];
+        let cycles = console.step() as u64;
+        self.cycles[index] += if console.is_double_speed() {
+            cycles / 2
+        } else {
+            cycles
+        };
+    }
+
+    /// Steps both consoles until each has run at least `cycles` more normal
+    /// speed cycles
+    pub fn run_cycles(&mut self, cycles: u64) {
+        let target = self.cycles[0].max(self.cycles[1]) + cycles;
+        while self.cycles[0] < target || self.cycles[1] < target {
+            self.step();
+        }
+    }
+
+    pub fn console(&self, index: usize) -> &GameBoy {
+        &self.consoles[index]
+    }
+
+    pub fn console_mut(&mut self, index: usize) -> &mut GameBoy {
+        &mut self.consoles[index]
+    }
+
+    /// Runs forever, each console using its own frontend
+    pub fn start(&mut self) {
+        loop {
+            self.step();
+        }
+    }
+
+    pub fn new(mut first: GameBoy, mut second: GameBoy) -> Self {
+        let (first_end, second_end) = VirtualCable::pair();
+        first.connect_serial(Box::new(first_end));
+        second.connect_serial(Box::new(second_end));
+
+        LinkedGameBoys {
+            consoles: [first, second],
+            cycles: [0, 0],
+        }
+    }
+}
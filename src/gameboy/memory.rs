@@ -4,9 +4,16 @@ use super::memory_bus::MemoryAccessor;
 pub use io_registers::IORegisters;
 use log::{debug, trace};
 
+/// Size of a work RAM bank
+const WRAM_BANK_SIZE: usize = 0x1000;
+
 pub struct Memory {
     high_ram: Vec<u8>,
+    /// Bank 0 at C000-CFFF, then banks 1-7 (CGB only) switched in D000-DFFF
     work_ram: Vec<u8>,
+    /// FF70 (SVBK), CGB only
+    wram_bank: usize,
+    cgb: bool,
 
     /// I/O registers
     pub io_registers: IORegisters,
@@ -15,45 +22,21 @@ pub struct Memory {
 }
 
 impl Memory {
-    pub fn dump_tile_data(&self) {
-        // println!("DUMPING TILE DATA");
-        // for tile in 0..384 {
-        //     let mut sum = 0i32;
-        //     for i in 0..16 {
-        //         sum += self.tile_data[tile * 16 + i] as i32;
-        //     }
-        //     if sum > 0 {
-        //         for i in 0..16 {
-        //             print!("{:#04x} ", self.tile_data[tile * 16 + i]);
-        //         }
-        //         println!()
-        //     }
-        // }
-        // println!("DUMPING TILE DATA COMPLETED");
-    }
-
-    pub fn _dump_tile(&self, _tile_id: u8) {
-        // println!("DUMPING TILE DATA");
-        // for i in 0..16 {
-        //     print!("{:#04x} ", self.tile_data[tile_id as usize * 16 + i]);
-        // }
-        // println!();
-        // println!("DUMPING TILE DATA COMPLETED");
-    }
-
-    pub fn _dump_oam(&self) {
-        // println!("DUMPING OAM DATA");
-        // for object in 0..40 {
-        //     let tile = self.get_oam_object(object);
-        //     println!("{:?}", tile)
-        // }
-        // println!("DUMPING OAM DATA COMPLETED");
+    /// Offset in `work_ram` of a location in C000-DFFF
+    fn wram_offset(&self, location: usize) -> usize {
+        match location {
+            0xc000..=0xcfff => location - 0xc000,
+            _ => self.wram_bank * WRAM_BANK_SIZE + location - 0xd000,
+        }
     }
 
-    pub fn new() -> Self {
+    pub fn new(cgb: bool) -> Self {
+        let banks = if cgb { 8 } else { 2 };
         Memory {
             high_ram: vec![0; 0xfffe - 0xff80 + 1],
-            work_ram: vec![0; 0xdfff - 0xc000 + 1], // 4+4 but half could be rotatable..
+            work_ram: vec![0; banks * WRAM_BANK_SIZE],
+            wram_bank: 1,
+            cgb,
 
             io_registers: IORegisters::new(),
 
@@ -66,8 +49,9 @@ impl MemoryAccessor for Memory {
     fn get(&self, location: usize) -> u8 {
         match location {
             0xff80..=0xfffe => self.high_ram[location - 0xff80],
-            0xc000..=0xdfff => self.work_ram[location - 0xc000],
-            0xff00..=0xff77 => self.io_registers.get(location),
+            0xc000..=0xdfff => self.work_ram[self.wram_offset(location)],
+            0xff70 if self.cgb => 0xf8 | self.wram_bank as u8,
+            0xff00..=0xff7f => self.io_registers.get(location),
             0xffff => {
                 trace!("IME");
                 self.interrupt_enable
@@ -79,9 +63,15 @@ impl MemoryAccessor for Memory {
     fn write(&mut self, location: usize, value: u8) {
         match location {
             0xc000..=0xdfff => {
-                // in CGB mode, the 2nd 4k are rotatable
                 trace!("Writting to WRAM: {:#x}", location);
-                self.work_ram[location - 0xc000] = value;
+                let offset = self.wram_offset(location);
+                self.work_ram[offset] = value;
+            }
+
+            0xff70 if self.cgb => {
+                // bank 0 selects bank 1
+                self.wram_bank = (value as usize & 0x07).max(1);
+                debug!("WRAM bank {}", self.wram_bank);
             }
 
             0xff00..=0xff7f => self.io_registers.write(location, value),
@@ -0,0 +1,210 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use log::info;
+
+use super::Frame;
+
+/// The PPU clock, and dots per frame: about 59.73 frames per second
+const CLOCK: u64 = 4194304;
+const FRAME_DOTS: u64 = 70224;
+
+/// Records the frames as a Y4M video, and the audio as a WAV file next to it
+/// (same name, `.wav` extension).
+///
+/// Every frame completed by the PPU is written, so the video runs at exactly
+/// the Game Boy frame rate. The audio is padded with silence if samples are
+/// missing, to stay in sync.
+pub struct Recorder {
+    video: BufWriter<File>,
+    audio: WavWriter,
+    /// Size of the frames in the video header
+    size: Option<(usize, usize)>,
+    frames: u64,
+}
+
+impl Recorder {
+    pub fn create(path: &Path, sample_rate: u32) -> io::Result<Self> {
+        let video = BufWriter::new(File::create(path)?);
+        let audio = WavWriter::create(&path.with_extension("wav"), sample_rate)?;
+        info!("Recording to {:?}", path);
+
+        Ok(Recorder {
+            video,
+            audio,
+            size: None,
+            frames: 0,
+        })
+    }
+
+    /// Writes a frame and the stereo samples played during it
+    pub fn write_frame(&mut self, frame: &Frame, samples: &[(i16, i16)]) -> io::Result<()> {
+        match self.size {
+            None => {
+                writeln!(
+                    self.video,
+                    "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C444",
+                    frame.width, frame.height, CLOCK, FRAME_DOTS
+                )?;
+                self.size = Some((frame.width, frame.height));
+            }
+            Some(size) if size != (frame.width, frame.height) => {
+                return Err(io::Error::other("frame size changed while recording"));
+            }
+            _ => (),
+        }
+
+        writeln!(self.video, "FRAME")?;
+        let yuv: Vec<(u8, u8, u8)> = frame
+            .pixels
+            .iter()
+            .map(|&color| rgb_to_yuv(color))
+            .collect();
+        let planes: [Vec<u8>; 3] = [
+            yuv.iter().map(|p| p.0).collect(),
+            yuv.iter().map(|p| p.1).collect(),
+            yuv.iter().map(|p| p.2).collect(),
+        ];
+        for plane in &planes {
+            self.video.write_all(plane)?;
+        }
+        self.frames += 1;
+
+        self.audio.write(samples)?;
+        let expected = self.frames * FRAME_DOTS * self.audio.sample_rate as u64 / CLOCK;
+        if self.audio.samples < expected {
+            let silence = vec![(0, 0); (expected - self.audio.samples) as usize];
+            self.audio.write(&silence)?;
+        }
+        Ok(())
+    }
+
+    /// Completes the WAV header, now that its length is known
+    pub fn finish(mut self) -> io::Result<()> {
+        self.video.flush()?;
+        self.audio.complete()?;
+        info!("Recorded {} frames", self.frames);
+        Ok(())
+    }
+}
+
+/// Writes 16-bit stereo samples to a WAV file
+pub struct WavWriter {
+    writer: BufWriter<File>,
+    sample_rate: u32,
+    samples: u64,
+}
+
+impl WavWriter {
+    pub fn create(path: &Path, sample_rate: u32) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        write_wav_header(&mut writer, sample_rate, 0)?;
+        Ok(WavWriter {
+            writer,
+            sample_rate,
+            samples: 0,
+        })
+    }
+
+    pub fn write(&mut self, samples: &[(i16, i16)]) -> io::Result<()> {
+        for &(left, right) in samples {
+            self.writer.write_all(&left.to_le_bytes())?;
+            self.writer.write_all(&right.to_le_bytes())?;
+        }
+        self.samples += samples.len() as u64;
+        Ok(())
+    }
+
+    /// Completes the header, now that the length is known
+    pub fn finish(mut self) -> io::Result<()> {
+        self.complete()
+    }
+
+    fn complete(&mut self) -> io::Result<()> {
+        self.writer.seek(SeekFrom::Start(0))?;
+        write_wav_header(&mut self.writer, self.sample_rate, self.samples as u32 * 4)?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()
+    }
+}
+
+/// Keeps the file playable when the emulator exits while writing it
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        let _ = self.complete();
+    }
+}
+
+/// BT.601, limited range
+fn rgb_to_yuv(color: u32) -> (u8, u8, u8) {
+    let (r, g, b) = (
+        ((color >> 16) & 0xff) as i32,
+        ((color >> 8) & 0xff) as i32,
+        (color & 0xff) as i32,
+    );
+    let y = ((66 * r + 129 * g + 25 * b + 128) >> 8) + 16;
+    let u = ((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128;
+    let v = ((112 * r - 94 * g - 18 * b + 128) >> 8) + 128;
+    (y as u8, u as u8, v as u8)
+}
+
+/// 16-bit stereo PCM
+fn write_wav_header(writer: &mut impl Write, sample_rate: u32, data_size: u32) -> io::Result<()> {
+    let channels: u16 = 2;
+    let bits: u16 = 16;
+    let block_align = channels * bits / 8;
+
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&(36 + data_size).to_le_bytes())?;
+    writer.write_all(b"WAVEfmt ")?;
+    writer.write_all(&16u32.to_le_bytes())?;
+    writer.write_all(&1u16.to_le_bytes())?;
+    writer.write_all(&channels.to_le_bytes())?;
+    writer.write_all(&sample_rate.to_le_bytes())?;
+    writer.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+    writer.write_all(&block_align.to_le_bytes())?;
+    writer.write_all(&bits.to_le_bytes())?;
+    writer.write_all(b"data")?;
+    writer.write_all(&data_size.to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{rgb_to_yuv, Recorder};
+    use crate::gameboy::Frame;
+    use std::fs;
+
+    #[test]
+    fn converts_to_yuv() {
+        assert_eq!(rgb_to_yuv(0x000000), (16, 128, 128));
+        assert_eq!(rgb_to_yuv(0xffffff), (235, 128, 128));
+    }
+
+    #[test]
+    fn writes_video_and_padded_audio() {
+        let path = std::env::temp_dir().join(format!("rs-boy-rec-{}.y4m", std::process::id()));
+        let mut recorder = Recorder::create(&path, 48000).unwrap();
+        let frame = Frame::new();
+        recorder.write_frame(&frame, &[(1, -1)]).unwrap();
+        recorder.write_frame(&frame, &[]).unwrap();
+        recorder.finish().unwrap();
+
+        let video = fs::read(&path).unwrap();
+        let header = b"YUV4MPEG2 W160 H144 F4194304:70224 Ip A1:1 C444\n";
+        assert!(video.starts_with(header));
+        assert_eq!(video.len(), header.len() + 2 * (6 + 160 * 144 * 3));
+
+        // 2 frames at 48 kHz
+        let audio = fs::read(path.with_extension("wav")).unwrap();
+        let samples = 2 * 70224 * 48000 / 4194304;
+        assert_eq!(audio.len(), 44 + samples * 4);
+        assert_eq!(&audio[40..44], &(samples as u32 * 4).to_le_bytes());
+        assert_eq!(&audio[44..48], &[1, 0, 0xff, 0xff]);
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(path.with_extension("wav")).unwrap();
+    }
+}
@@ -78,6 +78,23 @@ impl Registers {
         (result, f)
     }
 
+    /// Values left by the CGB boot ROM, A = 0x11 telling games they run on
+    /// a CGB
+    pub fn new_cgb() -> Self {
+        Registers {
+            pc: 0x100,
+            sp: 0xFFFE,
+            a: 0x11,
+            f: 0x80,
+            b: 0x00,
+            c: 0x00,
+            d: 0xff,
+            e: 0x56,
+            h: 0x00,
+            l: 0x0d,
+        }
+    }
+
     pub fn new() -> Self {
         Registers {
             // Classic
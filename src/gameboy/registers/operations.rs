@@ -196,7 +196,7 @@ impl Operations for u8 {
 
     fn swap(&mut self) -> u8 {
         let mut a = *self;
-        a = (a >> 4) | (a << 4);
+        a = a.rotate_left(4);
         let f = set_flag(0x0, Flag::Z, a == 0);
         *self = a;
         f
@@ -0,0 +1,354 @@
+mod link;
+mod printer;
+mod virtual_cable;
+
+use std::{
+    cell::RefCell,
+    io::{self, Write},
+    rc::Rc,
+};
+
+use log::{debug, trace};
+
+use super::memory_bus::MemoryAccessor;
+use super::timer;
+pub use link::{LinkAddress, LinkCable};
+pub use printer::{PrintedPage, Printer};
+pub use virtual_cable::VirtualCable;
+
+/// FF01 - SB
+pub const DATA_LOCATION: usize = 0xff01;
+/// FF02 - SC
+pub const CONTROL_LOCATION: usize = 0xff02;
+
+/// Something plugged into the other end of the link cable.
+///
+/// Bits are exchanged most significant first. The line is pulled high, so a
+/// peer with nothing to say should answer with `true`.
+pub trait SerialDevice {
+    /// Called when the console starts a transfer with the byte about to go out.
+    fn transfer_started(&mut self, _data: u8, _internal_clock: bool) {}
+
+    /// Called for every bit while this console provides the clock. Returns the
+    /// bit shifted in from the peer.
+    fn exchange_bit(&mut self, out: bool) -> bool;
+
+    /// Polled every M-cycle while this console provides the clock, before
+    /// `exchange_bit`. A peer that answers late holds the clock by returning
+    /// false, the transfer then stays in progress.
+    fn ready(&mut self) -> bool {
+        true
+    }
+
+    /// Polled every M-cycle while this console waits for an external clock.
+    /// If the peer pulses the clock it receives `out` and returns the bit that
+    /// is shifted in, otherwise None.
+    fn external_clock(&mut self, _out: bool) -> Option<bool> {
+        None
+    }
+}
+
+/// No cable connected: every transfer with the internal clock receives 0xFF
+/// and transfers waiting for an external clock never complete.
+pub struct Disconnected;
+
+impl SerialDevice for Disconnected {
+    fn exchange_bit(&mut self, _out: bool) -> bool {
+        true
+    }
+}
+
+/// Collects every byte sent by the console, optionally echoing it to stdout.
+///
+/// Test ROMs (blargg, mooneye) report their results this way.
+pub struct CaptureDevice {
+    output: Rc<RefCell<Vec<u8>>>,
+    echo: bool,
+
+    current: u8,
+    bits: u8,
+}
+
+impl CaptureDevice {
+    /// Shared handle to the captured bytes
+    pub fn output(&self) -> Rc<RefCell<Vec<u8>>> {
+        self.output.clone()
+    }
+
+    pub fn new(echo: bool) -> Self {
+        CaptureDevice {
+            output: Rc::new(RefCell::new(Vec::new())),
+            echo,
+            current: 0,
+            bits: 0,
+        }
+    }
+}
+
+impl SerialDevice for CaptureDevice {
+    fn exchange_bit(&mut self, out: bool) -> bool {
+        self.current = self.current << 1 | out as u8;
+        self.bits += 1;
+
+        if self.bits == 8 {
+            trace!("Captured serial byte: {:#x}", self.current);
+            self.output.borrow_mut().push(self.current);
+            if self.echo {
+                print!("{}", self.current as char);
+                io::stdout().flush().ok();
+            }
+            self.current = 0;
+            self.bits = 0;
+        }
+        true
+    }
+}
+
+pub struct Serial {
+    /// FF01
+    ///
+    /// Before a transfer, it holds the next byte that will go out.
+    /// During a transfer, it has a blend of the outgoing and incoming bytes.
+    data: u8,
+    /// FF02
+    ///
+    /// 7 - Transfer enable
+    ///
+    /// 1 - Clock speed (CGB only): 0 = 8192Hz; 1 = 262144Hz
+    ///
+    /// 0 - Clock select: 0 = External clock; 1 = Internal clock
+    control: u8,
+    cgb: bool,
+
+    device: Box<dyn SerialDevice>,
+
+    // helpers
+    transferred_bits: u8,
+}
+
+impl Serial {
+    fn transfer_enabled(&self) -> bool {
+        self.control & (1 << 7) > 0
+    }
+
+    fn internal_clock(&self) -> bool {
+        self.control & 1 > 0
+    }
+
+    /// Bit of the system counter whose falling edges shift the internal clock
+    fn clock_bit(&self) -> u16 {
+        if self.control & (1 << 1) > 0 {
+            timer::SERIAL_FAST_CLOCK_BIT
+        } else {
+            timer::SERIAL_CLOCK_BIT
+        }
+    }
+
+    pub fn connect(&mut self, device: Box<dyn SerialDevice>) {
+        self.device = device;
+    }
+
+    /// Advances the port by one M-cycle. `falling_edges` are the bits of the
+    /// timer's system counter that fell during the cycle, the internal clock
+    /// being derived from them.
+    ///
+    /// Returns true when a transfer completes and the interrupt should be raised
+    pub fn step(&mut self, falling_edges: u16) -> bool {
+        if !self.transfer_enabled() {
+            return false;
+        }
+
+        let bit = if self.internal_clock() {
+            if !self.device.ready() || falling_edges & self.clock_bit() == 0 {
+                return false;
+            }
+            Some(self.device.exchange_bit(self.outgoing_bit()))
+        } else {
+            self.device.external_clock(self.outgoing_bit())
+        };
+
+        match bit {
+            Some(bit) => self.shift(bit),
+            None => false,
+        }
+    }
+
+    fn outgoing_bit(&self) -> bool {
+        self.data & (1 << 7) > 0
+    }
+
+    /// Shifts in a bit. Returns true if the transfer is complete.
+    fn shift(&mut self, bit: bool) -> bool {
+        self.data = self.data << 1 | bit as u8;
+        self.transferred_bits += 1;
+        if self.transferred_bits < 8 {
+            return false;
+        }
+
+        debug!("Serial transfer completed, received {:#x}", self.data);
+        self.control &= !(1 << 7);
+        self.transferred_bits = 0;
+        true
+    }
+
+    pub fn new(cgb: bool) -> Self {
+        Serial {
+            data: 0,
+            control: 0x7e,
+            cgb,
+            device: Box::new(Disconnected),
+            transferred_bits: 0,
+        }
+    }
+}
+
+impl MemoryAccessor for Serial {
+    fn get(&self, location: usize) -> u8 {
+        match location {
+            DATA_LOCATION => self.data,
+            // unused bits read as 1
+            CONTROL_LOCATION if self.cgb => self.control | 0x7c,
+            CONTROL_LOCATION => self.control | 0x7e,
+            _ => panic!("serial location read: {:#x}", location),
+        }
+    }
+
+    fn write(&mut self, location: usize, value: u8) {
+        trace!("Writting to Serial Register: {:#x}: {:#b}", location, value);
+        match location {
+            DATA_LOCATION => self.data = value,
+            CONTROL_LOCATION => {
+                // the clock speed bit only exists on CGB
+                self.control = value & if self.cgb { 0x83 } else { 0x81 };
+                if self.transfer_enabled() {
+                    debug!("Starting serial transfer of {:#x}", self.data);
+                    self.transferred_bits = 0;
+                    self.device
+                        .transfer_started(self.data, self.internal_clock());
+                }
+            }
+            _ => panic!("serial location write: {:#x} - {:#x}", location, value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CaptureDevice, Serial, SerialDevice};
+    use crate::gameboy::{memory_bus::MemoryAccessor, timer};
+
+    struct Echo;
+
+    /// Runs `cycles` M-cycles, with the system counter starting at 0
+    fn run(serial: &mut Serial, cycles: u32) -> bool {
+        let mut interrupt = false;
+        for cycle in 1..=cycles {
+            let counter = (cycle * 4) as u16;
+            interrupt |= serial.step(counter.wrapping_sub(4) & !counter);
+        }
+        interrupt
+    }
+
+    impl SerialDevice for Echo {
+        fn exchange_bit(&mut self, out: bool) -> bool {
+            out
+        }
+
+        fn external_clock(&mut self, out: bool) -> Option<bool> {
+            Some(!out)
+        }
+    }
+
+    #[test]
+    fn internal_clock_transfer_takes_4096_cycles() {
+        let mut serial = Serial::new(false);
+        serial.write(0xff01, 0x42);
+        serial.write(0xff02, 0x81);
+
+        assert!(!run(&mut serial, 1023));
+        assert_eq!(serial.get(0xff02), 0xff);
+        assert!(serial.step(timer::SERIAL_CLOCK_BIT));
+        assert_eq!(serial.get(0xff02), 0x7f);
+        // nothing connected
+        assert_eq!(serial.get(0xff01), 0xff);
+    }
+
+    /// Holds the clock for the first `0` polls
+    struct Late(u32);
+
+    impl SerialDevice for Late {
+        fn exchange_bit(&mut self, _out: bool) -> bool {
+            true
+        }
+
+        fn ready(&mut self) -> bool {
+            self.0 = self.0.saturating_sub(1);
+            self.0 == 0
+        }
+    }
+
+    #[test]
+    fn late_peer_holds_the_clock() {
+        let mut serial = Serial::new(false);
+        serial.connect(Box::new(Late(2000)));
+        serial.write(0xff02, 0x81);
+
+        // the edges of the first 2000 cycles are missed
+        assert!(!run(&mut serial, 2048));
+        assert_eq!(serial.get(0xff02), 0xff);
+        assert!(run(&mut serial, 1024));
+        assert_eq!(serial.get(0xff02), 0x7f);
+    }
+
+    #[test]
+    fn cgb_fast_clock_is_32_times_faster() {
+        let mut serial = Serial::new(true);
+        serial.write(0xff02, 0x83);
+        assert_eq!(serial.get(0xff02), 0xff);
+        assert!(!run(&mut serial, 31));
+        assert!(serial.step(timer::SERIAL_FAST_CLOCK_BIT));
+
+        // no such bit on DMG
+        let mut serial = Serial::new(false);
+        serial.write(0xff02, 0x83);
+        assert_eq!(serial.get(0xff02), 0xff);
+        assert!(!run(&mut serial, 32));
+    }
+
+    #[test]
+    fn capture_device_collects_bytes() {
+        let device = CaptureDevice::new(false);
+        let output = device.output();
+
+        let mut serial = Serial::new(false);
+        serial.connect(Box::new(device));
+        for value in [3, 5, 8] {
+            serial.write(0xff01, value);
+            serial.write(0xff02, 0x81);
+            run(&mut serial, 1024);
+        }
+
+        assert_eq!(*output.borrow(), vec![3, 5, 8]);
+    }
+
+    #[test]
+    fn external_clock_is_driven_by_peer() {
+        let mut serial = Serial::new(false);
+        serial.connect(Box::new(Echo));
+        serial.write(0xff01, 0xf0);
+        serial.write(0xff02, 0x80);
+
+        assert!(!run(&mut serial, 7));
+        assert!(serial.step(0));
+        assert_eq!(serial.get(0xff01), 0x0f);
+    }
+
+    #[test]
+    fn external_clock_without_peer_never_completes() {
+        let mut serial = Serial::new(false);
+        serial.write(0xff02, 0x80);
+
+        assert!(!run(&mut serial, 100_000));
+        assert_eq!(serial.get(0xff02), 0xfe);
+    }
+}
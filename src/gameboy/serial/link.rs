@@ -0,0 +1,456 @@
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+};
+
+#[cfg(unix)]
+use std::{
+    os::unix::net::{UnixListener, UnixStream},
+    path::PathBuf,
+};
+
+use log::{info, warn};
+
+use super::SerialDevice;
+
+/// M-cycles the clocking side waits for the peer before giving up on a byte,
+/// about half a second
+const REPLY_TIMEOUT_CYCLES: u32 = 1 << 19;
+
+/// Sent by the side providing the clock, carrying its outgoing byte
+const MESSAGE_TRANSFER: u8 = 0x01;
+/// Answer to a transfer, carrying the byte of the externally clocked side
+const MESSAGE_REPLY: u8 = 0x02;
+/// Sent by the side providing the clock when it gave up on a transfer
+const MESSAGE_CANCEL: u8 = 0x03;
+/// Kind, sequence number and byte. A reply carries the sequence number of
+/// the transfer it answers.
+const MESSAGE_SIZE: usize = 3;
+
+pub enum LinkAddress {
+    /// `host:port`
+    Tcp(String),
+    /// `unix:/path/to/socket`
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl LinkAddress {
+    pub fn parse(address: &str) -> LinkAddress {
+        #[cfg(unix)]
+        if let Some(path) = address.strip_prefix("unix:") {
+            return LinkAddress::Unix(PathBuf::from(path));
+        }
+        LinkAddress::Tcp(address.to_string())
+    }
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Link cable to another rs-boy process.
+///
+/// Bytes are exchanged as a whole: the side providing the clock sends its byte
+/// and holds its clock until the other side, once it has armed a transfer on
+/// the external clock, answers with its own. The received byte is then shifted
+/// in bit by bit at the pace of the local clock, so both consoles see the same
+/// handshake as with a real cable. When the clocking side gives up it cancels
+/// the transfer, and a reply arriving anyway is recognised by its sequence
+/// number and dropped.
+pub struct LinkCable {
+    stream: Option<Stream>,
+    /// Partially received message
+    received: Vec<u8>,
+    /// Sequence number of the last transfer sent
+    sequence: u8,
+    /// M-cycles spent waiting for the reply to the last transfer sent
+    waiting: Option<u32>,
+    /// A transfer waits for the external clock
+    pending: bool,
+
+    /// Byte to send for the current transfer
+    outgoing: u8,
+    /// Byte received for the current transfer
+    incoming: Option<u8>,
+    shifted_bits: u8,
+}
+
+impl LinkCable {
+    /// Waits for the other console to connect
+    pub fn listen(address: &LinkAddress) -> io::Result<LinkCable> {
+        let stream = match address {
+            LinkAddress::Tcp(address) => {
+                let listener = TcpListener::bind(address)?;
+                info!("Waiting for link cable connection on {}", address);
+                let (stream, peer) = listener.accept()?;
+                info!("Link cable connected to {}", peer);
+                Stream::Tcp(stream)
+            }
+            #[cfg(unix)]
+            LinkAddress::Unix(path) => {
+                if path.exists() {
+                    std::fs::remove_file(path)?;
+                }
+                let listener = UnixListener::bind(path)?;
+                info!("Waiting for link cable connection on {:?}", path);
+                let (stream, _) = listener.accept()?;
+                info!("Link cable connected");
+                Stream::Unix(stream)
+            }
+        };
+        LinkCable::new(stream)
+    }
+
+    /// Connects to a console waiting with [`LinkCable::listen`]
+    pub fn connect(address: &LinkAddress) -> io::Result<LinkCable> {
+        let stream = match address {
+            LinkAddress::Tcp(address) => Stream::Tcp(TcpStream::connect(address)?),
+            #[cfg(unix)]
+            LinkAddress::Unix(path) => Stream::Unix(UnixStream::connect(path)?),
+        };
+        info!("Link cable connected");
+        LinkCable::new(stream)
+    }
+
+    fn new(stream: Stream) -> io::Result<LinkCable> {
+        if let Stream::Tcp(stream) = &stream {
+            stream.set_nodelay(true)?;
+        }
+        stream.set_nonblocking(true)?;
+        Ok(LinkCable {
+            stream: Some(stream),
+            received: Vec::new(),
+            sequence: 0,
+            waiting: None,
+            pending: false,
+            outgoing: 0xff,
+            incoming: None,
+            shifted_bits: 0,
+        })
+    }
+
+    fn send(&mut self, kind: u8, sequence: u8, value: u8) {
+        if let Some(stream) = self.stream.as_mut() {
+            if let Err(e) = stream.write_all(&[kind, sequence, value]) {
+                self.disconnect(e);
+            }
+        }
+    }
+
+    /// Reads the next message if it has arrived: kind, sequence number and
+    /// byte
+    fn receive(&mut self) -> Option<(u8, u8, u8)> {
+        while self.received.len() < MESSAGE_SIZE {
+            let stream = self.stream.as_mut()?;
+            let mut buffer = [0; MESSAGE_SIZE];
+            match stream.read(&mut buffer[..MESSAGE_SIZE - self.received.len()]) {
+                Ok(0) => {
+                    self.disconnect(io::Error::from(ErrorKind::UnexpectedEof));
+                    return None;
+                }
+                Ok(n) => self.received.extend_from_slice(&buffer[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return None,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.disconnect(e);
+                    return None;
+                }
+            }
+        }
+
+        let message = (self.received[0], self.received[1], self.received[2]);
+        self.received.clear();
+        Some(message)
+    }
+
+    fn disconnect(&mut self, error: io::Error) {
+        warn!("Link cable disconnected: {}", error);
+        self.stream = None;
+    }
+
+    /// Shifts out the next bit of the received byte
+    fn next_bit(&mut self, incoming: u8) -> bool {
+        let bit = incoming & (1 << (7 - self.shifted_bits)) > 0;
+        self.shifted_bits += 1;
+        if self.shifted_bits == 8 {
+            self.shifted_bits = 0;
+            self.incoming = None;
+        }
+        bit
+    }
+}
+
+impl SerialDevice for LinkCable {
+    fn transfer_started(&mut self, data: u8, internal_clock: bool) {
+        self.outgoing = data;
+        self.incoming = None;
+        self.shifted_bits = 0;
+        self.pending = !internal_clock;
+        // restarted before the peer answered
+        if self.waiting.take().is_some() {
+            self.send(MESSAGE_CANCEL, self.sequence, 0);
+        }
+    }
+
+    fn ready(&mut self) -> bool {
+        if self.incoming.is_some() {
+            return true;
+        }
+        let waited = match self.waiting {
+            Some(waited) => waited,
+            None => {
+                self.sequence = self.sequence.wrapping_add(1);
+                self.send(MESSAGE_TRANSFER, self.sequence, self.outgoing);
+                0
+            }
+        };
+
+        while self.incoming.is_none() {
+            match self.receive() {
+                Some((MESSAGE_REPLY, sequence, value)) if sequence == self.sequence => {
+                    self.incoming = Some(value)
+                }
+                // late answer to a transfer that already timed out
+                Some((MESSAGE_REPLY, _, _)) => (),
+                // both sides are providing the clock; each gets the other's byte
+                Some((MESSAGE_TRANSFER, _, value)) => self.incoming = Some(value),
+                Some((MESSAGE_CANCEL, _, _)) => (),
+                Some((kind, _, _)) => warn!("Unknown link cable message: {:#x}", kind),
+                None => break,
+            }
+        }
+        if self.incoming.is_some() {
+            self.waiting = None;
+            return true;
+        }
+
+        if self.stream.is_none() {
+            self.incoming = Some(0xff);
+        } else if waited >= REPLY_TIMEOUT_CYCLES {
+            warn!("No answer over the link cable");
+            self.send(MESSAGE_CANCEL, self.sequence, 0);
+            self.incoming = Some(0xff);
+        } else {
+            self.waiting = Some(waited + 1);
+            return false;
+        }
+        self.waiting = None;
+        true
+    }
+
+    fn exchange_bit(&mut self, _out: bool) -> bool {
+        // the peer is polled by `ready` first
+        let incoming = self.incoming.unwrap_or(0xff);
+        self.next_bit(incoming)
+    }
+
+    fn external_clock(&mut self, _out: bool) -> Option<bool> {
+        if !self.pending {
+            return None;
+        }
+        if self.incoming.is_none() {
+            // the last transfer offered, unless the clocking side gave up on it
+            let mut offered = None;
+            while let Some(message) = self.receive() {
+                match message {
+                    (MESSAGE_TRANSFER, sequence, value) => offered = Some((sequence, value)),
+                    (MESSAGE_CANCEL, sequence, _) => {
+                        if offered.is_some_and(|(offered, _)| offered == sequence) {
+                            offered = None;
+                        }
+                    }
+                    // late answer to a transfer that already timed out
+                    (MESSAGE_REPLY, _, _) => (),
+                    (kind, _, _) => warn!("Unknown link cable message: {:#x}", kind),
+                }
+            }
+            let (sequence, value) = offered?;
+            self.send(MESSAGE_REPLY, sequence, self.outgoing);
+            self.incoming = Some(value);
+        }
+
+        let incoming = self.incoming.unwrap();
+        let bit = self.next_bit(incoming);
+        self.pending = self.incoming.is_some();
+        Some(bit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LinkCable, Stream, REPLY_TIMEOUT_CYCLES};
+    use crate::gameboy::serial::SerialDevice;
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    fn cable(stream: TcpStream) -> LinkCable {
+        LinkCable::new(Stream::Tcp(stream)).unwrap()
+    }
+
+    /// A pair of cables connected over loopback
+    fn pair() -> (LinkCable, LinkCable) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let connected = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (cable(connected), cable(listener.accept().unwrap().0))
+    }
+
+    /// One M-cycle of the serial port, with a clock edge every time
+    fn step(cable: &mut LinkCable, out: bool, internal_clock: bool) -> Option<bool> {
+        if internal_clock {
+            cable.ready().then(|| cable.exchange_bit(out))
+        } else {
+            cable.external_clock(out)
+        }
+    }
+
+    fn transfer(cable: &mut LinkCable, data: u8, internal_clock: bool) -> u8 {
+        cable.transfer_started(data, internal_clock);
+        finish(cable, data, internal_clock)
+    }
+
+    /// Runs the transfer in progress to the end
+    fn finish(cable: &mut LinkCable, data: u8, internal_clock: bool) -> u8 {
+        let mut received = 0;
+        let mut bits = 0;
+        while bits < 8 {
+            if let Some(bit) = step(cable, data & (1 << (7 - bits)) > 0, internal_clock) {
+                received = received << 1 | bit as u8;
+                bits += 1;
+            }
+        }
+        received
+    }
+
+    /// Transfers of the slave, in a thread
+    fn slave_transfers(mut slave: LinkCable, data: &'static [u8]) -> thread::JoinHandle<Vec<u8>> {
+        thread::spawn(move || {
+            data.iter()
+                .map(|&value| transfer(&mut slave, value, false))
+                .collect()
+        })
+    }
+
+    #[test]
+    fn exchanges_bytes_over_loopback() {
+        let (mut master, slave) = pair();
+        let slave = slave_transfers(slave, &[0x99, 0x01]);
+
+        assert_eq!(transfer(&mut master, 0x42, true), 0x99);
+        assert_eq!(transfer(&mut master, 0x43, true), 0x01);
+
+        assert_eq!(slave.join().unwrap(), [0x42, 0x43]);
+    }
+
+    #[test]
+    fn holds_the_clock_until_the_reply() {
+        let (mut master, mut slave) = pair();
+        master.transfer_started(0x42, true);
+        for _ in 0..1000 {
+            assert!(!master.ready());
+        }
+
+        slave.transfer_started(0x99, false);
+        while slave.external_clock(false).is_none() {}
+        while !master.ready() {}
+        assert!(master.exchange_bit(false));
+    }
+
+    #[test]
+    fn drops_replies_after_the_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut master = cable(TcpStream::connect(listener.local_addr().unwrap()).unwrap());
+        let mut peer = listener.accept().unwrap().0;
+
+        master.transfer_started(0x42, true);
+        for _ in 0..REPLY_TIMEOUT_CYCLES {
+            assert!(!master.ready());
+        }
+        let mut message = [0; 3];
+        peer.read_exact(&mut message).unwrap();
+        assert_eq!(message[2], 0x42);
+        assert_eq!(finish(&mut master, 0x42, true), 0xff);
+
+        // the transfer is cancelled, and its reply comes too late
+        let mut cancel = [0; 3];
+        peer.read_exact(&mut cancel).unwrap();
+        assert_eq!(cancel[..2], [0x03, message[1]]);
+        peer.write_all(&[0x02, message[1], 0x11]).unwrap();
+
+        // the next byte is not the late one
+        master.transfer_started(0x43, true);
+        assert!(!master.ready());
+        peer.read_exact(&mut message).unwrap();
+        assert_eq!(message[2], 0x43);
+        peer.write_all(&[0x02, message[1], 0x22]).unwrap();
+        assert_eq!(finish(&mut master, 0x43, true), 0x22);
+    }
+
+    #[test]
+    fn slave_skips_cancelled_transfers() {
+        let (mut master, slave) = pair();
+        master.transfer_started(0x42, true);
+        for _ in 0..REPLY_TIMEOUT_CYCLES {
+            assert!(!master.ready());
+        }
+        assert_eq!(finish(&mut master, 0x42, true), 0xff);
+
+        // armed after the timeout, the slave answers the next transfer
+        let slave = slave_transfers(slave, &[0x99, 0x01]);
+        assert_eq!(transfer(&mut master, 0x43, true), 0x99);
+        assert_eq!(transfer(&mut master, 0x44, true), 0x01);
+        assert_eq!(slave.join().unwrap(), [0x43, 0x44]);
+    }
+
+    #[test]
+    fn closed_peer_reads_as_disconnected() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut master = cable(TcpStream::connect(listener.local_addr().unwrap()).unwrap());
+        drop(listener.accept().unwrap());
+
+        assert_eq!(transfer(&mut master, 0x42, true), 0xff);
+    }
+}
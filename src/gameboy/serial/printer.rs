@@ -0,0 +1,484 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, info, warn};
+
+use super::SerialDevice;
+
+/// Width of the printed image in pixels (20 tiles)
+pub const PAGE_WIDTH: usize = 160;
+
+/// Bytes of tile data per 8 pixel high row of the image
+const ROW_BYTES: usize = PAGE_WIDTH / 8 * 16;
+/// The printer buffers up to 9 data packets of two tile rows each
+const BUFFER_SIZE: usize = 0x280 * 9;
+/// Pixel rows fed for each line feed requested in the print margins
+const LINE_FEED_HEIGHT: usize = 8;
+/// Status inquiries answered with busy after a print command
+const PRINT_BUSY_INQUIRIES: u8 = 3;
+
+const COMMAND_INIT: u8 = 0x01;
+const COMMAND_PRINT: u8 = 0x02;
+const COMMAND_DATA: u8 = 0x04;
+const COMMAND_BREAK: u8 = 0x08;
+const COMMAND_STATUS: u8 = 0x0f;
+
+const STATUS_CHECKSUM_ERROR: u8 = 1 << 0;
+const STATUS_BUSY: u8 = 1 << 1;
+const STATUS_IMAGE_FULL: u8 = 1 << 2;
+const STATUS_UNPROCESSED: u8 = 1 << 3;
+const STATUS_PACKET_ERROR: u8 = 1 << 4;
+
+/// Answer to the first byte after the checksum
+const ALIVE: u8 = 0x81;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+/// Called by the printer with every finished page
+pub type PageCallback = Box<dyn FnMut(&PrintedPage)>;
+
+/// A page coming out of the printer
+pub struct PrintedPage {
+    pub width: usize,
+    pub height: usize,
+    /// Shades from 0 (white) to 3 (black), row by row
+    pub pixels: Vec<u8>,
+}
+
+impl PrintedPage {
+    /// Writes the page as a grayscale PNG
+    pub fn save_png(&self, path: &Path) -> io::Result<()> {
+        let file = File::create(path)?;
+        let mut encoder =
+            png::Encoder::new(BufWriter::new(file), self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        let data: Vec<u8> = self
+            .pixels
+            .iter()
+            .map(|&shade| 0xff - shade * 0x55)
+            .collect();
+        encoder
+            .write_header()
+            .and_then(|mut writer| writer.write_image_data(&data))
+            .map_err(io::Error::other)
+    }
+}
+
+/// Game Boy Printer, plugged into the link port.
+///
+/// The console sends packets of the form
+/// `0x88 0x33 command compression length(2) data checksum(2) 0x00 0x00`. The
+/// printer answers 0x81 to the first trailing byte and its status to the last.
+/// Data packets carry tile data, optionally RLE compressed, and a print command
+/// renders it with the given palette and margins. Every finished page is saved
+/// as PNG and handed to the callback, if any.
+pub struct Printer {
+    output_directory: Option<PathBuf>,
+    callback: Option<PageCallback>,
+    printed_pages: u32,
+
+    // packet being received
+    state: State,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    data: Vec<u8>,
+    checksum: u16,
+    received_checksum: u16,
+
+    /// Answer for the transfer in progress
+    response: u8,
+    shifted_bits: u8,
+
+    /// Decompressed tile data waiting to be printed
+    buffer: Vec<u8>,
+    status: u8,
+    busy_inquiries: u8,
+    /// Page being printed, as shades
+    page: Vec<u8>,
+}
+
+impl Printer {
+    /// Pages are saved as PNG in `output_directory`, if given
+    pub fn new(output_directory: Option<PathBuf>) -> Self {
+        Printer {
+            output_directory,
+            callback: None,
+            printed_pages: 0,
+            state: State::Magic1,
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            checksum: 0,
+            received_checksum: 0,
+            response: 0,
+            shifted_bits: 0,
+            buffer: Vec::new(),
+            status: 0,
+            busy_inquiries: 0,
+            page: Vec::new(),
+        }
+    }
+
+    /// Called with every finished page
+    pub fn set_callback(&mut self, callback: PageCallback) {
+        self.callback = Some(callback);
+    }
+
+    /// Handles a byte sent by the console and returns the answer
+    fn receive(&mut self, byte: u8) -> u8 {
+        let (next, response) = match self.state {
+            State::Magic1 if byte == 0x88 => (State::Magic2, 0),
+            State::Magic1 => (State::Magic1, 0),
+            State::Magic2 if byte == 0x33 => (State::Command, 0),
+            State::Magic2 => (State::Magic1, 0),
+            State::Command => {
+                self.command = byte;
+                self.checksum = byte as u16;
+                (State::Compression, 0)
+            }
+            State::Compression => {
+                self.compressed = byte & 1 > 0;
+                self.checksum += byte as u16;
+                (State::LengthLow, 0)
+            }
+            State::LengthLow => {
+                self.length = byte as u16;
+                self.checksum += byte as u16;
+                (State::LengthHigh, 0)
+            }
+            State::LengthHigh => {
+                self.length |= (byte as u16) << 8;
+                self.checksum += byte as u16;
+                self.data.clear();
+                if self.length == 0 {
+                    (State::ChecksumLow, 0)
+                } else {
+                    (State::Data, 0)
+                }
+            }
+            State::Data => {
+                self.data.push(byte);
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                if self.data.len() == self.length as usize {
+                    (State::ChecksumLow, 0)
+                } else {
+                    (State::Data, 0)
+                }
+            }
+            State::ChecksumLow => {
+                self.received_checksum = byte as u16;
+                (State::ChecksumHigh, 0)
+            }
+            State::ChecksumHigh => {
+                self.received_checksum |= (byte as u16) << 8;
+                (State::Alive, 0)
+            }
+            State::Alive => (State::Status, ALIVE),
+            State::Status => {
+                // the status sent back is the one from before the command runs
+                let status = self.current_status();
+                self.run_command();
+                (State::Magic1, status)
+            }
+        };
+        self.state = next;
+        response
+    }
+
+    fn current_status(&mut self) -> u8 {
+        let mut status = self.status;
+        if self.busy_inquiries > 0 {
+            self.busy_inquiries -= 1;
+            status |= STATUS_BUSY;
+        }
+        status
+    }
+
+    fn run_command(&mut self) {
+        if self.checksum != self.received_checksum {
+            warn!(
+                "Printer checksum error: {:#x} != {:#x}",
+                self.checksum, self.received_checksum
+            );
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !STATUS_CHECKSUM_ERROR;
+
+        match self.command {
+            COMMAND_INIT => {
+                debug!("Printer: init");
+                self.buffer.clear();
+                self.status = 0;
+                self.busy_inquiries = 0;
+            }
+            COMMAND_DATA => {
+                let data = if self.compressed {
+                    decompress(&self.data)
+                } else {
+                    self.data.clone()
+                };
+                debug!("Printer: {} bytes of data", data.len());
+                self.buffer.extend(data);
+                if self.buffer.len() >= BUFFER_SIZE {
+                    self.buffer.truncate(BUFFER_SIZE);
+                    self.status |= STATUS_IMAGE_FULL;
+                }
+                if !self.buffer.is_empty() {
+                    self.status |= STATUS_UNPROCESSED;
+                }
+            }
+            COMMAND_PRINT if self.data.len() == 4 => {
+                let (sheets, margins, palette) = (self.data[0], self.data[1], self.data[2]);
+                self.print(sheets, margins >> 4, margins & 0xf, palette);
+                self.buffer.clear();
+                self.status &= !(STATUS_UNPROCESSED | STATUS_IMAGE_FULL);
+                self.busy_inquiries = PRINT_BUSY_INQUIRIES;
+            }
+            COMMAND_BREAK => {
+                debug!("Printer: break");
+                self.buffer.clear();
+                self.status &= !(STATUS_UNPROCESSED | STATUS_IMAGE_FULL);
+                self.busy_inquiries = 0;
+            }
+            COMMAND_STATUS => (),
+            command => {
+                warn!("Printer: unknown command {:#x}", command);
+                self.status |= STATUS_PACKET_ERROR;
+            }
+        }
+    }
+
+    fn print(&mut self, sheets: u8, margin_before: u8, margin_after: u8, palette: u8) {
+        debug!(
+            "Printer: print {} sheets, margins {}/{}, palette {:#b}",
+            sheets, margin_before, margin_after, palette
+        );
+        // most games send 0 for the default palette
+        let palette = if palette == 0 { 0xe4 } else { palette };
+
+        self.feed(margin_before);
+        if sheets > 0 {
+            let rows = self.buffer.len() / ROW_BYTES * 8;
+            for y in 0..rows {
+                for x in 0..PAGE_WIDTH {
+                    let tile = (y / 8) * PAGE_WIDTH / 8 + x / 8;
+                    let offset = tile * 16 + (y % 8) * 2;
+                    let bit = 7 - (x % 8);
+                    let lsb = (self.buffer[offset] >> bit) & 1;
+                    let msb = (self.buffer[offset + 1] >> bit) & 1;
+                    let color = msb << 1 | lsb;
+                    self.page.push((palette >> (color * 2)) & 0x3);
+                }
+            }
+        }
+        self.feed(margin_after);
+
+        if margin_after > 0 {
+            self.finish_page();
+        }
+    }
+
+    fn feed(&mut self, lines: u8) {
+        let pixels = lines as usize * LINE_FEED_HEIGHT * PAGE_WIDTH;
+        self.page.extend(std::iter::repeat_n(0, pixels));
+    }
+
+    fn finish_page(&mut self) {
+        if self.page.is_empty() {
+            return;
+        }
+
+        let page = PrintedPage {
+            width: PAGE_WIDTH,
+            height: self.page.len() / PAGE_WIDTH,
+            pixels: std::mem::take(&mut self.page),
+        };
+        self.printed_pages += 1;
+
+        if let Some(directory) = &self.output_directory {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            let path = directory.join(format!("print-{}-{:03}.png", timestamp, self.printed_pages));
+            let saved = fs::create_dir_all(directory).and_then(|_| page.save_png(&path));
+            match saved {
+                Ok(_) => info!("Printed page saved to {:?}", path),
+                Err(e) => warn!("Could not save printed page to {:?}: {}", path, e),
+            }
+        }
+
+        if let Some(callback) = self.callback.as_mut() {
+            callback(&page);
+        }
+    }
+}
+
+/// RLE used by data packets: a control byte with the MSB set repeats the next
+/// byte `(control & 0x7f) + 2` times, otherwise `control + 1` bytes follow as-is.
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut result = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let control = data[i];
+        i += 1;
+        if control & 0x80 > 0 {
+            let length = (control & 0x7f) as usize + 2;
+            if let Some(&value) = data.get(i) {
+                result.extend(std::iter::repeat_n(value, length));
+            }
+            i += 1;
+        } else {
+            let length = control as usize + 1;
+            let end = (i + length).min(data.len());
+            result.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+    result
+}
+
+impl SerialDevice for Printer {
+    fn transfer_started(&mut self, data: u8, _internal_clock: bool) {
+        self.response = self.receive(data);
+        self.shifted_bits = 0;
+    }
+
+    fn exchange_bit(&mut self, _out: bool) -> bool {
+        let bit = self.response & (1 << (7 - self.shifted_bits)) > 0;
+        self.shifted_bits = (self.shifted_bits + 1) % 8;
+        bit
+    }
+}
+
+impl Drop for Printer {
+    fn drop(&mut self) {
+        self.finish_page();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decompress, PrintedPage, Printer};
+    use crate::gameboy::serial::SerialDevice;
+    use std::{cell::RefCell, rc::Rc};
+
+    fn send(printer: &mut Printer, byte: u8) -> u8 {
+        printer.transfer_started(byte, true);
+        let mut response = 0;
+        for _ in 0..8 {
+            response = response << 1 | printer.exchange_bit(false) as u8;
+        }
+        response
+    }
+
+    /// Sends a whole packet and returns the alive byte and status
+    fn packet(printer: &mut Printer, command: u8, compression: u8, data: &[u8]) -> (u8, u8) {
+        let length = data.len() as u16;
+        let mut bytes = vec![
+            0x88,
+            0x33,
+            command,
+            compression,
+            length as u8,
+            (length >> 8) as u8,
+        ];
+        bytes.extend_from_slice(data);
+        let checksum = bytes[2..]
+            .iter()
+            .fold(0u16, |sum, &b| sum.wrapping_add(b as u16));
+        bytes.push(checksum as u8);
+        bytes.push((checksum >> 8) as u8);
+
+        for byte in bytes {
+            assert_eq!(send(printer, byte), 0);
+        }
+        (send(printer, 0), send(printer, 0))
+    }
+
+    #[test]
+    fn decompresses_runs_and_literals() {
+        assert_eq!(
+            decompress(&[0x81, 0xaa, 0x01, 0x01, 0x02]),
+            vec![0xaa, 0xaa, 0xaa, 0x01, 0x02]
+        );
+    }
+
+    #[test]
+    fn answers_alive_and_status() {
+        let mut printer = Printer::new(None);
+        assert_eq!(packet(&mut printer, 0x01, 0, &[]), (0x81, 0x00));
+        assert_eq!(packet(&mut printer, 0x04, 0, &[0; 0x280]), (0x81, 0x00));
+        assert_eq!(packet(&mut printer, 0x0f, 0, &[]), (0x81, 0x08));
+    }
+
+    #[test]
+    fn reports_checksum_errors() {
+        let mut printer = Printer::new(None);
+        for byte in [0x88, 0x33, 0x0f, 0x00, 0x00, 0x00, 0xff, 0xff] {
+            send(&mut printer, byte);
+        }
+        send(&mut printer, 0);
+        send(&mut printer, 0);
+        assert_eq!(packet(&mut printer, 0x0f, 0, &[]), (0x81, 0x01));
+    }
+
+    #[test]
+    fn prints_a_page() {
+        let pages: Rc<RefCell<Vec<PrintedPage>>> = Rc::new(RefCell::new(Vec::new()));
+        let mut printer = Printer::new(None);
+        let printed = pages.clone();
+        printer.set_callback(Box::new(move |page| {
+            printed.borrow_mut().push(PrintedPage {
+                width: page.width,
+                height: page.height,
+                pixels: page.pixels.clone(),
+            })
+        }));
+
+        packet(&mut printer, 0x01, 0, &[]);
+        // two rows of tiles with color 3, compressed
+        packet(
+            &mut printer,
+            0x04,
+            1,
+            &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+        );
+        packet(&mut printer, 0x04, 0, &[]);
+        assert!(pages.borrow().is_empty());
+
+        packet(&mut printer, 0x02, 0, &[1, 0x01, 0xe4, 0x40]);
+        // busy while printing
+        assert_eq!(packet(&mut printer, 0x0f, 0, &[]), (0x81, 0x02));
+
+        let pages = pages.borrow();
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].width, 160);
+        // 645 bytes make two full tile rows, plus one line feed
+        assert_eq!(pages[0].height, 24);
+        assert_eq!(pages[0].pixels[0], 3);
+        assert_eq!(*pages[0].pixels.last().unwrap(), 0);
+    }
+}
@@ -0,0 +1,119 @@
+use std::{cell::RefCell, collections::VecDeque, rc::Rc};
+
+use super::SerialDevice;
+
+#[derive(Default)]
+struct Port {
+    /// Bit presented on the SO line, the MSB of the shift register
+    out: bool,
+    /// Waiting for a transfer on the external clock
+    armed: bool,
+    /// Bits clocked in by the peer, not yet shifted
+    clocked: VecDeque<bool>,
+    shifted_bits: u8,
+}
+
+/// Link cable between two consoles in the same process.
+///
+/// Bits are exchanged as the clock pulses happen, so both consoles have to be
+/// stepped in lockstep, see [`crate::gameboy::LinkedGameBoys`].
+pub struct VirtualCable {
+    ports: Rc<RefCell<[Port; 2]>>,
+    index: usize,
+}
+
+impl VirtualCable {
+    /// Both ends of a new cable
+    pub fn pair() -> (VirtualCable, VirtualCable) {
+        let ports = Rc::new(RefCell::new([Port::default(), Port::default()]));
+        (
+            VirtualCable {
+                ports: ports.clone(),
+                index: 0,
+            },
+            VirtualCable { ports, index: 1 },
+        )
+    }
+
+    fn peer(&self) -> usize {
+        1 - self.index
+    }
+}
+
+impl SerialDevice for VirtualCable {
+    fn transfer_started(&mut self, data: u8, internal_clock: bool) {
+        let mut ports = self.ports.borrow_mut();
+        let port = &mut ports[self.index];
+        port.out = data & (1 << 7) > 0;
+        port.armed = !internal_clock;
+        port.clocked.clear();
+        port.shifted_bits = 0;
+    }
+
+    fn exchange_bit(&mut self, out: bool) -> bool {
+        let mut ports = self.ports.borrow_mut();
+        let peer = &mut ports[self.peer()];
+        if !peer.armed {
+            return true;
+        }
+        peer.clocked.push_back(out);
+        peer.out
+    }
+
+    fn external_clock(&mut self, out: bool) -> Option<bool> {
+        let mut ports = self.ports.borrow_mut();
+        let port = &mut ports[self.index];
+        port.out = out;
+
+        let bit = port.clocked.pop_front()?;
+        port.shifted_bits += 1;
+        if port.shifted_bits == 8 {
+            port.armed = false;
+            port.shifted_bits = 0;
+        }
+        Some(bit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VirtualCable;
+    use crate::gameboy::memory_bus::MemoryAccessor;
+    use crate::gameboy::serial::{Serial, SerialDevice};
+    use crate::gameboy::timer;
+
+    #[test]
+    fn exchanges_bytes_between_consoles() {
+        let (first, second) = VirtualCable::pair();
+        let mut master = Serial::new(false);
+        let mut slave = Serial::new(false);
+        master.connect(Box::new(first));
+        slave.connect(Box::new(second));
+
+        slave.write(0xff01, 0x99);
+        slave.write(0xff02, 0x80);
+        master.write(0xff01, 0x42);
+        master.write(0xff02, 0x81);
+
+        let mut interrupts = (false, false);
+        for cycle in 1..=1024 {
+            interrupts.0 |= master.step(if cycle % 128 == 0 {
+                timer::SERIAL_CLOCK_BIT
+            } else {
+                0
+            });
+            interrupts.1 |= slave.step(0);
+        }
+
+        assert_eq!(interrupts, (true, true));
+        assert_eq!(master.get(0xff01), 0x99);
+        assert_eq!(slave.get(0xff01), 0x42);
+    }
+
+    #[test]
+    fn unarmed_peer_reads_as_disconnected() {
+        let (mut first, _second) = VirtualCable::pair();
+        first.transfer_started(0x42, true);
+        assert!(first.exchange_bit(false));
+    }
+}
@@ -0,0 +1,85 @@
+use log::info;
+
+use super::memory_bus::MemoryAccessor;
+
+pub const REGISTER_LOCATION: usize = 0xff4d;
+
+/// CGB double speed mode (KEY1)
+///
+/// Writing bit 0 arms a speed switch, which happens on the next STOP. In
+/// double speed the CPU, timer, serial port and OAM DMA run twice as fast,
+/// while the PPU keeps its normal clock.
+pub struct Speed {
+    cgb: bool,
+    double: bool,
+    armed: bool,
+}
+
+impl Speed {
+    pub fn is_double(&self) -> bool {
+        self.double
+    }
+
+    /// Called on STOP. Returns true if the speed was switched.
+    pub fn switch(&mut self) -> bool {
+        if !self.armed {
+            return false;
+        }
+        self.armed = false;
+        self.double = !self.double;
+        info!(
+            "Switched to {} speed",
+            if self.double { "double" } else { "normal" }
+        );
+        true
+    }
+
+    pub fn new(cgb: bool) -> Self {
+        Speed {
+            cgb,
+            double: false,
+            armed: false,
+        }
+    }
+}
+
+impl MemoryAccessor for Speed {
+    fn get(&self, _location: usize) -> u8 {
+        if !self.cgb {
+            return 0xff;
+        }
+        0x7e | (self.double as u8) << 7 | self.armed as u8
+    }
+
+    fn write(&mut self, _location: usize, value: u8) {
+        if self.cgb {
+            self.armed = value & 0x01 > 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Speed;
+    use crate::gameboy::memory_bus::MemoryAccessor;
+
+    #[test]
+    fn switches_only_when_armed() {
+        let mut speed = Speed::new(true);
+        assert!(!speed.switch());
+
+        speed.write(0xff4d, 0x01);
+        assert_eq!(speed.get(0xff4d), 0x7f);
+        assert!(speed.switch());
+        assert!(speed.is_double());
+        assert_eq!(speed.get(0xff4d), 0xfe);
+    }
+
+    #[test]
+    fn dmg_ignores_key1() {
+        let mut speed = Speed::new(false);
+        speed.write(0xff4d, 0x01);
+        assert!(!speed.switch());
+        assert_eq!(speed.get(0xff4d), 0xff);
+    }
+}
@@ -1,29 +1,48 @@
-use log::{info, trace};
+use log::trace;
 
 use super::memory_bus::MemoryAccessor;
 
+/// Bit of the system counter clocking the serial port (8192Hz)
+pub const SERIAL_CLOCK_BIT: u16 = 1 << 8;
+/// The same with the CGB fast clock (262144Hz)
+pub const SERIAL_FAST_CLOCK_BIT: u16 = 1 << 3;
+/// Bit of the system counter clocking the APU frame sequencer (512Hz), the
+/// next one in double speed
+pub const APU_CLOCK_BIT: u16 = 1 << 12;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Reload {
+    None,
+    /// TIMA overflowed during the last M-cycle and reads as 0
+    Pending,
+    /// TMA was loaded into TIMA during the last M-cycle
+    Reloading,
+}
+
 pub struct Timer {
-    /// FF04
-    /// This register is incremented at a rate of 16384Hz (~16779Hz on SGB).
-    /// Writing any value to this register resets it to $00. Additionally,
-    /// this register is reset when executing the stop instruction, and only
-    /// begins ticking again once stop mode ends.
-    div: u8,
+    /// Internal 16-bit counter, incremented every T-cycle.
+    ///
+    /// FF04 (DIV) exposes its upper byte. Writing any value to DIV resets the
+    /// whole counter.
+    counter: u16,
     /// FF05
-    /// This timer is incremented at the clock frequency specified by the TAC
-    /// register ($FF07). When the value overflows (exceeds $FF) it is reset to
-    /// the value specified in TMA (FF06) and an interrupt is requested, as
-    /// described below.
+    /// Incremented on the falling edge of the counter bit selected by TAC.
+    /// When it overflows it reads as 0 for one M-cycle, then it is reloaded
+    /// with TMA (FF06) and an interrupt is requested.
     tima: u8,
     /// FF06
     tma: u8,
     /// FF07
+    ///
+    /// 2 - Enable
+    ///
+    /// 0-1 - Clock select: counter bit 9, 3, 5 or 7
     tac: u8,
 
-    // temporary:
-    div_counter: u32,
-    tima_counter: u32,
-    tima_clock: u32,
+    reload: Reload,
+    /// Counter bits that went from 1 to 0 since the last call to
+    /// `take_falling_edges`
+    falling_edges: u16,
 }
 
 impl Timer {
@@ -31,61 +50,69 @@ impl Timer {
         (self.tac & (1 << 2)) > 0
     }
 
-    fn clock(&self) -> u32 {
-        let selected = self.tac & 0x3;
-        info!("selected {}", selected);
-
-        match selected {
-            0 => 1024,
-            1 => 16,
-            2 => 64,
-            3 => 256,
-            _clock => panic!("unknown tima clock: {}", _clock),
+    fn selected_bit(&self) -> u16 {
+        match self.tac & 0x3 {
+            0 => 1 << 9,
+            1 => 1 << 3,
+            2 => 1 << 5,
+            _ => 1 << 7,
         }
     }
 
-    pub fn step_timer(&mut self, ticks: u32) -> bool {
-        // a dot is: 4194000 Hz
-        // div step:   16384 Hz
-        // so a div is stepped every 255.981445313 dots
-        self.div_counter += ticks;
-        if self.div_counter >= 256 {
-            self.div_counter -= 256;
-            self.div = self.div.wrapping_add(1);
-            // debug!("div: {}", self.div)
-        }
-
-        if !self.tima_enabled() {
-            return false;
-        }
+    /// Input of the falling edge detector incrementing TIMA
+    fn tima_signal(&self) -> bool {
+        self.tima_enabled() && self.counter & self.selected_bit() > 0
+    }
 
-        self.tima_counter += ticks;
-        let clock = self.tima_clock;
+    fn set_counter(&mut self, counter: u16) {
+        self.falling_edges |= self.counter & !counter;
+        self.counter = counter;
+    }
 
-        if self.tima_counter >= clock {
-            self.tima_counter -= clock;
-            self.tima = self.tima.wrapping_add(1);
-            // debug!("tima: {}", self.tima);
+    fn increment_tima(&mut self) {
+        self.tima = self.tima.wrapping_add(1);
+        if self.tima == 0 {
+            trace!("TIMA overflow");
+            self.reload = Reload::Pending;
+        }
+    }
 
-            if self.tima == 0 {
+    /// Advances the timer by one M-cycle. Returns true if the timer interrupt
+    /// should be requested.
+    pub fn step(&mut self) -> bool {
+        let mut interrupt = false;
+        match self.reload {
+            Reload::Pending => {
                 self.tima = self.tma;
-                return true;
-                // todo!("trigger timer interrupt");
+                self.reload = Reload::Reloading;
+                interrupt = true;
             }
+            Reload::Reloading => self.reload = Reload::None,
+            Reload::None => (),
         }
-        false
+
+        let signal = self.tima_signal();
+        self.set_counter(self.counter.wrapping_add(4));
+        if signal && !self.tima_signal() {
+            self.increment_tima();
+        }
+        interrupt
+    }
+
+    /// Counter bits that fell since the last call. Other components (serial
+    /// port, APU frame sequencer) are clocked by these edges.
+    pub fn take_falling_edges(&mut self) -> u16 {
+        std::mem::take(&mut self.falling_edges)
     }
 
     pub fn new() -> Self {
         Timer {
-            div: 0xab,
+            counter: 0xabcc,
             tima: 0,
             tma: 0,
             tac: 0xf8,
-            // helpers
-            div_counter: 0,
-            tima_counter: 0,
-            tima_clock: 0,
+            reload: Reload::None,
+            falling_edges: 0,
         }
     }
 }
@@ -94,10 +121,10 @@ impl MemoryAccessor for Timer {
     fn get(&self, location: usize) -> u8 {
         trace!("Read Timer: {:#x}", location);
         match location {
-            0xFF04 => self.div,
+            0xFF04 => (self.counter >> 8) as u8,
             0xFF05 => self.tima,
             0xFF06 => self.tma,
-            0xFF07 => self.tac,
+            0xFF07 => self.tac | 0xf8,
             _ => panic!("timer register location read: {:#x}", location),
         }
     }
@@ -106,15 +133,37 @@ impl MemoryAccessor for Timer {
         trace!("Writting to Timer Register: {:#x}: {:#b}", location, value);
         match location {
             0xFF04 => {
-                // writing any value resets it
-                self.div = 0;
-                self.tima_counter = 0;
+                // writing any value resets it, which can be a falling edge
+                let signal = self.tima_signal();
+                self.set_counter(0);
+                if signal {
+                    self.increment_tima();
+                }
+            }
+            0xFF05 => match self.reload {
+                // writing during the overflow cycle cancels the reload
+                Reload::Pending => {
+                    self.tima = value;
+                    self.reload = Reload::None;
+                }
+                // TMA wins on the cycle it is loaded
+                Reload::Reloading => (),
+                Reload::None => self.tima = value,
+            },
+            0xFF06 => {
+                self.tma = value;
+                if self.reload == Reload::Reloading {
+                    self.tima = value;
+                }
             }
-            0xFF05 => self.tima = value,
-            0xFF06 => self.tma = value,
             0xFF07 => {
+                // disabling the timer or switching to a cleared bit is seen as
+                // a falling edge
+                let signal = self.tima_signal();
                 self.tac = value;
-                self.tima_clock = self.clock();
+                if signal && !self.tima_signal() {
+                    self.increment_tima();
+                }
             }
             _ => panic!(
                 "timer register location write: {:#x} - {:#x}",
@@ -123,3 +172,106 @@ impl MemoryAccessor for Timer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Timer;
+    use crate::gameboy::memory_bus::MemoryAccessor;
+
+    fn timer(tac: u8) -> Timer {
+        let mut timer = Timer::new();
+        timer.write(0xff04, 0);
+        timer.write(0xff07, tac);
+        timer.write(0xff05, 0);
+        timer
+    }
+
+    #[test]
+    fn tima_increments_every_16_cycles() {
+        let mut timer = timer(0x05);
+        for _ in 0..4 {
+            timer.step();
+        }
+        assert_eq!(timer.get(0xff05), 1);
+    }
+
+    #[test]
+    fn div_is_upper_byte_of_counter() {
+        let mut timer = timer(0);
+        for _ in 0..64 {
+            timer.step();
+        }
+        assert_eq!(timer.get(0xff04), 1);
+    }
+
+    #[test]
+    fn overflow_reloads_after_one_cycle() {
+        let mut timer = timer(0x05);
+        timer.write(0xff06, 0x42);
+        timer.write(0xff05, 0xff);
+
+        for _ in 0..4 {
+            assert!(!timer.step());
+        }
+        assert_eq!(timer.get(0xff05), 0);
+        assert!(timer.step());
+        assert_eq!(timer.get(0xff05), 0x42);
+    }
+
+    #[test]
+    fn tima_write_cancels_pending_reload() {
+        let mut timer = timer(0x05);
+        timer.write(0xff06, 0x42);
+        timer.write(0xff05, 0xff);
+        for _ in 0..4 {
+            timer.step();
+        }
+
+        timer.write(0xff05, 0x10);
+        assert!(!timer.step());
+        assert_eq!(timer.get(0xff05), 0x10);
+    }
+
+    #[test]
+    fn tma_write_while_reloading_goes_to_tima() {
+        let mut timer = timer(0x05);
+        timer.write(0xff05, 0xff);
+        for _ in 0..5 {
+            timer.step();
+        }
+
+        timer.write(0xff05, 0x10);
+        timer.write(0xff06, 0x20);
+        assert_eq!(timer.get(0xff05), 0x20);
+    }
+
+    #[test]
+    fn div_write_triggers_falling_edge() {
+        let mut timer = timer(0x05);
+        // bit 3 set
+        timer.step();
+        timer.step();
+        timer.write(0xff04, 0);
+        assert_eq!(timer.get(0xff05), 1);
+    }
+
+    #[test]
+    fn disabling_triggers_falling_edge() {
+        let mut timer = timer(0x05);
+        timer.step();
+        timer.step();
+        timer.write(0xff07, 0x01);
+        assert_eq!(timer.get(0xff05), 1);
+    }
+
+    #[test]
+    fn falling_edges_include_div_reset() {
+        let mut timer = timer(0);
+        for _ in 0..64 {
+            timer.step();
+        }
+        timer.take_falling_edges();
+        timer.write(0xff04, 0);
+        assert_eq!(timer.take_falling_edges(), 1 << 8);
+    }
+}
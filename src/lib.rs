@@ -1 +1,3 @@
+#[cfg(feature = "frontend")]
+pub mod frontend;
 pub mod gameboy;
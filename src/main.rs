@@ -1,6 +1,12 @@
 use env_logger::Env;
-use rs_boy::gameboy::GameBoy;
+use log::warn;
+use rs_boy::frontend::{AudioOutput, KeyBindings, Screen, SplitScreen, VramWindow};
+use rs_boy::gameboy::{
+    DmgPalettes, GameBoy, Gbs, GbsPlayer, LinkAddress, LinkCable, LinkedGameBoys, PostProcessing,
+    Printer, VramView, WavWriter,
+};
 use std::env;
+use std::path::{Path, PathBuf};
 
 fn main() {
     env_logger::Builder::from_env(Env::default().default_filter_or("info"))
@@ -14,7 +20,274 @@ fn main() {
     }
 
     let path = args[1].as_str();
+    if path.to_lowercase().ends_with(".gbs") {
+        play_gbs(path, &args[2..]);
+        return;
+    }
 
     let mut gb = GameBoy::new(path);
-    gb.start();
+
+    // a .gbs file is played instead, with the options of play_gbs
+    // --link-listen <address> / --link-connect <address>
+    // where address is host:port or unix:/path/to/socket
+    // --link-local <rom> runs a second console in the same window
+    // --printer <directory> connects a Game Boy Printer saving pages as PNG
+    // --palette <preset|gbc|file> sets the DMG colors, P cycles the presets
+    // --filter <filter,...> post-processes the frames, F cycles some chains
+    // --screenshots <directory> is where F12 saves screenshots
+    // --headless <frames> runs that many frames without a window
+    // --screenshot <file> saves a PNG when the headless run ends
+    // --screenshot-at <frame> ends the headless run at that frame instead
+    // --recordings <directory> is where R saves videos
+    // --record <file.y4m> records from the start, a WAV is written next to it
+    // --vram <tiles|maps|oam|palettes> opens a window showing the VRAM
+    // --volume <0-100> sets the audio volume, - and = change it
+    // --keys <file> binds keys to the buttons, keys.cfg by default
+    let mut filters = PostProcessing::default();
+    let mut second_rom = None;
+    let mut screenshots = PathBuf::from("screenshots");
+    let mut headless_frames = None;
+    let mut screenshot = None;
+    let mut screenshot_at = None;
+    let mut recordings = PathBuf::from("recordings");
+    let mut vram_view = None;
+    let mut volume = 100;
+    let mut keys = None;
+    let mut options = args[2..].iter();
+    while let Some(option) = options.next() {
+        let value = options
+            .next()
+            .unwrap_or_else(|| panic!("Missing value for {}", option));
+        let cable = match option.as_str() {
+            "--link-listen" => LinkCable::listen(&LinkAddress::parse(value)),
+            "--link-connect" => LinkCable::connect(&LinkAddress::parse(value)),
+            "--printer" => {
+                let printer = Printer::new(Some(PathBuf::from(value)));
+                gb.connect_serial(Box::new(printer));
+                continue;
+            }
+            "--palette" => {
+                let palettes = match value.as_str() {
+                    "gbc" => gb.gbc_boot_palettes(),
+                    name => DmgPalettes::preset(name).unwrap_or_else(|| {
+                        DmgPalettes::load(Path::new(name)).unwrap_or_else(|e| {
+                            let presets: Vec<_> = DmgPalettes::preset_names().collect();
+                            panic!(
+                                "Unknown palette {} ({}), presets: {}",
+                                name,
+                                e,
+                                presets.join(", ")
+                            )
+                        })
+                    }),
+                };
+                gb.set_dmg_palettes(palettes);
+                continue;
+            }
+            "--filter" => {
+                filters = PostProcessing::parse(value).unwrap_or_else(|e| panic!("{}", e));
+                continue;
+            }
+            "--link-local" => {
+                second_rom = Some(value);
+                continue;
+            }
+            "--screenshots" => {
+                screenshots = PathBuf::from(value);
+                continue;
+            }
+            "--headless" => {
+                headless_frames = Some(parse_number(option, value));
+                continue;
+            }
+            "--screenshot" => {
+                screenshot = Some(PathBuf::from(value));
+                continue;
+            }
+            "--screenshot-at" => {
+                screenshot_at = Some(parse_number(option, value));
+                continue;
+            }
+            "--recordings" => {
+                recordings = PathBuf::from(value);
+                continue;
+            }
+            "--vram" => {
+                vram_view = Some(VramView::parse(value).unwrap_or_else(|| {
+                    panic!(
+                        "Unknown VRAM view {}, expected tiles, maps, oam or palettes",
+                        value
+                    )
+                }));
+                continue;
+            }
+            "--volume" => {
+                volume = parse_number(option, value).min(100);
+                continue;
+            }
+            "--keys" => {
+                keys = Some(PathBuf::from(value));
+                continue;
+            }
+            "--record" => {
+                gb.start_recording(Path::new(value))
+                    .unwrap_or_else(|e| panic!("Could not record to {}: {}", value, e));
+                continue;
+            }
+            _ => panic!("Unknown option {}", option),
+        };
+        gb.connect_serial(Box::new(cable.expect("could not set up the link cable")));
+    }
+
+    if (screenshot.is_some() || screenshot_at.is_some()) && headless_frames.is_none() {
+        panic!("--screenshot and --screenshot-at need --headless");
+    }
+
+    if let Some(frames) = headless_frames {
+        for _ in 0..frames {
+            gb.run_frame();
+            if screenshot_at == Some(gb.frame_count()) {
+                break;
+            }
+        }
+        if let Some(path) = screenshot {
+            let image = gb.screenshot().filter(&mut filters);
+            image
+                .save_png(&path)
+                .unwrap_or_else(|e| panic!("Could not save {:?}: {}", path, e));
+        }
+        gb.stop_recording()
+            .unwrap_or_else(|e| panic!("Could not finish the recording: {}", e));
+        return;
+    }
+
+    if let Some(second_rom) = second_rom {
+        let mut second = GameBoy::new(second_rom);
+        let (mut left, mut right) = SplitScreen::open();
+        left.set_key_bindings(key_bindings(keys.as_deref(), &gb.title()));
+        right.set_key_bindings(key_bindings(keys.as_deref(), &second.title()));
+        gb.set_frontend(left);
+        second.set_frontend(right);
+        LinkedGameBoys::new(gb, second).start();
+        return;
+    }
+
+    let frame = gb.frame();
+    let screen = Screen::open(frame.width, frame.height, filters);
+    screen.save_screenshots(screenshots, &gb.title());
+    screen.save_recordings(recordings, &gb.title());
+    screen.set_key_bindings(key_bindings(keys.as_deref(), &gb.title()));
+    let mut audio = AudioOutput::open(gb.sample_rate());
+    audio.set_volume(volume as f32 / 100.0);
+    screen.set_audio(audio);
+    gb.set_audio_sink(Box::new(screen.clone()));
+    gb.set_frontend(screen);
+    match vram_view {
+        Some(view) => {
+            let mut vram_window = VramWindow::open(view);
+            loop {
+                gb.run_frame();
+                vram_window.update(&gb);
+            }
+        }
+        None => gb.start(),
+    }
+}
+
+/// Plays a GBS file, Right/Left switch tracks in the window
+///
+/// --track <number> starts from that track instead of the default one
+/// --headless <frames> plays that many frames without a window
+/// --wav <file> writes what the headless run played
+/// --volume <0-100> sets the audio volume
+/// --keys <file> binds keys to the buttons, keys.cfg by default
+fn play_gbs(path: &str, args: &[String]) {
+    let gbs = Gbs::load(Path::new(path)).unwrap_or_else(|e| panic!("{}", e));
+    let mut player = GbsPlayer::new(gbs);
+
+    let mut headless_frames = None;
+    let mut wav = None;
+    let mut volume = 100;
+    let mut keys = None;
+    let mut options = args.iter();
+    while let Some(option) = options.next() {
+        let value = options
+            .next()
+            .unwrap_or_else(|| panic!("Missing value for {}", option));
+        match option.as_str() {
+            "--track" => {
+                let tracks = player.gbs().tracks;
+                let track = parse_number(option, value);
+                if !(1..=tracks as u64).contains(&track) {
+                    panic!(
+                        "{} expects a track from 1 to {}, got {}",
+                        option, tracks, value
+                    );
+                }
+                player.play_track(track as u8 - 1);
+            }
+            "--headless" => headless_frames = Some(parse_number(option, value)),
+            "--wav" => wav = Some(PathBuf::from(value)),
+            "--volume" => volume = parse_number(option, value).min(100),
+            "--keys" => keys = Some(PathBuf::from(value)),
+            _ => panic!("Unknown option {}", option),
+        }
+    }
+
+    if wav.is_some() && headless_frames.is_none() {
+        panic!("--wav needs --headless");
+    }
+
+    if let Some(frames) = headless_frames {
+        let mut writer = wav.map(|path| {
+            WavWriter::create(&path, player.sample_rate())
+                .unwrap_or_else(|e| panic!("Could not write {:?}: {}", path, e))
+        });
+        for _ in 0..frames {
+            player.run_frame();
+            if let Some(writer) = writer.as_mut() {
+                writer
+                    .write(&player.take_samples())
+                    .unwrap_or_else(|e| panic!("Could not write the WAV file: {}", e));
+            }
+        }
+        if let Some(writer) = writer {
+            writer
+                .finish()
+                .unwrap_or_else(|e| panic!("Could not finish the WAV file: {}", e));
+        }
+        return;
+    }
+
+    let frame = player.run_frame();
+    let screen = Screen::open(frame.width, frame.height, PostProcessing::default());
+    screen.set_key_bindings(key_bindings(keys.as_deref(), &player.gbs().title));
+    let mut audio = AudioOutput::open(player.sample_rate());
+    audio.set_volume(volume as f32 / 100.0);
+    screen.set_audio(audio);
+    player.set_audio_sink(Box::new(screen.clone()));
+    player.set_frontend(screen);
+    player.start();
+}
+
+/// The bindings of `path`, or of keys.cfg if it exists, with the overrides
+/// for `title`. Conflicts are only reported.
+fn key_bindings(path: Option<&Path>, title: &str) -> KeyBindings {
+    let default = Path::new("keys.cfg");
+    let path = match path {
+        Some(path) => path,
+        None if default.exists() => default,
+        None => return KeyBindings::default(),
+    };
+    let bindings = KeyBindings::load(path, title).unwrap_or_else(|e| panic!("{}", e));
+    for conflict in bindings.conflicts() {
+        warn!("Key binding conflict: {}", conflict);
+    }
+    bindings
+}
+
+fn parse_number(option: &str, value: &str) -> u64 {
+    value
+        .parse()
+        .unwrap_or_else(|_| panic!("{} expects a number, got {}", option, value))
 }
@@ -1,4 +1,4 @@
-use rs_boy::gameboy::GameBoy;
+use rs_boy::gameboy::{CaptureDevice, GameBoy};
 use std::path::Path;
 
 const ROMPATH: &str = "test/mooneye/acceptance";
@@ -9,7 +9,9 @@ macro_rules! test {
         fn $fn_name() {
             let mut gb = GameBoy::new(Path::new(ROMPATH).join($rom).to_str().unwrap());
 
-            let mut output: Vec<u8> = Vec::new();
+            let serial = CaptureDevice::new(false);
+            let output = serial.output();
+            gb.connect_serial(Box::new(serial));
 
             let mut found = false;
             loop {
@@ -22,10 +24,6 @@ macro_rules! test {
                     }
                 }
 
-                let (v, ok) = is_serial_write(&gb);
-                if ok {
-                    output.push(v);
-                }
                 gb.step();
             }
 
@@ -36,35 +34,11 @@ macro_rules! test {
             assert_eq!(gb.registers.h, 21);
             assert_eq!(gb.registers.l, 34);
 
-            assert_eq!(output, vec![3, 5, 8, 13, 21, 34])
+            assert_eq!(*output.borrow(), vec![3, 5, 8, 13, 21, 34]);
         }
     };
 }
 
-// TODO checking serial port.. is it needed?
-fn is_serial_write(gb: &GameBoy) -> (u8, bool) {
-    let op = gb.memory_read(gb.registers.pc as usize);
-    if op == 0xe0 && gb.memory_read(gb.registers.pc as usize + 1) == 1 {
-        return (gb.registers.a, true);
-    }
-
-    // if op == 0xea {
-    //     let location1 = gb.memory_read(gb.registers.pc as usize + 1) as u16;
-    //     let location2 = gb.memory_read(gb.registers.pc as usize + 2) as u16;
-    //     println!("Location: {:#x}", location2 << 8 | location1);
-    // }
-    // if op == 0xe2 {
-    //     println!("Location ffxx: {:#x}", gb.registers.c);
-    // }
-    // if op == 0x02 {
-    //     println!("Location bc: {:#x}", gb.registers.get_bc());
-    // }
-    // if op == 0x12 {
-    //     println!("Location de: {:#x}", gb.registers.get_de());
-    // }
-    (0, false)
-}
-
 test!(rapid_di_ei, "rapid_di_ei.gb");
 test!(oam_dma_start, "oam_dma_start.gb");
 test!(boot_regs_dmg_abc, "boot_regs-dmgABC.gb");
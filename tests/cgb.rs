@@ -0,0 +1,43 @@
+mod common;
+
+use rs_boy::gameboy::GameBoy;
+
+/// JR -2
+const LOOP: [u8; 2] = [0x18, 0xfe];
+
+#[test]
+fn detects_cgb_games() {
+    let gb = GameBoy::new(common::cgb_rom("cgb", &LOOP).to_str().unwrap());
+    assert_eq!(gb.registers.a, 0x11);
+
+    let gb = GameBoy::new(common::rom("dmg", &LOOP).to_str().unwrap());
+    assert_eq!(gb.registers.a, 0x01);
+    assert_eq!(gb.memory_read(0xff4d), 0xff);
+}
+
+#[test]
+fn switches_wram_banks() {
+    let mut gb = GameBoy::new(common::cgb_rom("wram-banks", &LOOP).to_str().unwrap());
+    for bank in 1..8 {
+        gb.memory_write(0xff70, bank);
+        gb.memory_write(0xd000, bank * 2);
+    }
+
+    gb.memory_write(0xff70, 0);
+    assert_eq!(gb.memory_read(0xff70), 0xf9);
+    assert_eq!(gb.memory_read(0xd000), 2);
+    gb.memory_write(0xff70, 5);
+    assert_eq!(gb.memory_read(0xd000), 10);
+}
+
+#[test]
+fn stop_switches_speed() {
+    // LD A,1; LDH (KEY1),A; STOP
+    let program = [0x3e, 0x01, 0xe0, 0x4d, 0x10, 0x00, 0x18, 0xfe];
+    let mut gb = GameBoy::new(common::cgb_rom("double-speed", &program).to_str().unwrap());
+    for _ in 0..4 {
+        gb.step();
+    }
+    assert_eq!(gb.memory_read(0xff4d), 0xfe);
+    assert_eq!(gb.memory_read(0xff04), 0);
+}
@@ -0,0 +1,24 @@
+use std::{env, fs, path::PathBuf};
+
+/// Writes a 32KB NoMBC ROM running `program` from 0x100 and returns its path
+pub fn rom(name: &str, program: &[u8]) -> PathBuf {
+    write_rom(name, program, 0)
+}
+
+/// Same as `rom`, flagged as a CGB game
+#[allow(dead_code)]
+pub fn cgb_rom(name: &str, program: &[u8]) -> PathBuf {
+    write_rom(name, program, 0x80)
+}
+
+fn write_rom(name: &str, program: &[u8], cgb_flag: u8) -> PathBuf {
+    let mut buffer = vec![0; 0x8000];
+    buffer[0x100..0x100 + program.len()].copy_from_slice(program);
+    buffer[0x134..0x134 + name.len().min(15)]
+        .copy_from_slice(&name.as_bytes()[..name.len().min(15)]);
+    buffer[0x143] = cgb_flag;
+
+    let path = env::temp_dir().join(format!("rs-boy-{}-{}.gb", name, std::process::id()));
+    fs::write(&path, buffer).unwrap();
+    path
+}
@@ -0,0 +1,37 @@
+use rs_boy::gameboy::GameBoy;
+use std::{fs::File, path::Path};
+
+const ROMPATH: &str = "test/dmg-acid2";
+
+/// Shades of the reference image, 0 being white
+fn reference(name: &str) -> Vec<u8> {
+    let file = File::open(Path::new(ROMPATH).join(name)).unwrap();
+    let mut decoder = png::Decoder::new(file);
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let mut reader = decoder.read_info().unwrap();
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer).unwrap();
+
+    let channels = info.color_type.samples();
+    buffer[..info.buffer_size()]
+        .chunks(channels)
+        .map(|pixel| 3 - pixel[0] / 0x55)
+        .collect()
+}
+
+#[test]
+fn dmg_acid2() {
+    let mut gb = GameBoy::new(Path::new(ROMPATH).join("dmg-acid2.gb").to_str().unwrap());
+
+    // LD B,B once the test is done, then let one more frame be drawn
+    gb.run_until(|gb| gb.memory_read(gb.registers.pc as usize) == 0x40);
+    gb.run_frame();
+    let screen = &gb.run_frame().shades;
+    let expected = reference("reference-dmg.png");
+    let mismatches = screen
+        .iter()
+        .zip(expected.iter())
+        .filter(|(a, b)| a != b)
+        .count();
+    assert_eq!(mismatches, 0, "{} pixels differ", mismatches);
+}
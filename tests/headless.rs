@@ -0,0 +1,290 @@
+use rs_boy::gameboy::{AudioSink, Button, Frame, FrameSink, GameBoy, Gbs, GbsPlayer, InputSource};
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+};
+
+mod common;
+
+/// JR -2
+const LOOP: [u8; 2] = [0x18, 0xfe];
+
+#[test]
+fn run_frame_counts_frames_and_cycles() {
+    let mut gb = GameBoy::new(common::rom("frames", &LOOP).to_str().unwrap());
+    assert_eq!(gb.frame_count(), 0);
+
+    assert_eq!(gb.run_frame().number, 1);
+    let start = gb.cycles();
+    assert_eq!(gb.run_frame().number, 2);
+    let cycles = gb.cycles() - start;
+    // a frame is 70224 cycles, give or take an instruction
+    assert!((70224..70224 + 12).contains(&cycles), "{}", cycles);
+}
+
+#[test]
+fn exposes_shades() {
+    // LD A,$FF; LDH (BGP),A
+    let program = [0x3e, 0xff, 0xe0, 0x47, 0x18, 0xfe];
+    let mut gb = GameBoy::new(common::rom("shades", &program).to_str().unwrap());
+    gb.run_frame();
+    let frame = gb.run_frame();
+
+    assert_eq!((frame.width, frame.height), (160, 144));
+    assert!(frame.shades.iter().all(|&shade| shade == 3));
+    assert!(frame.pixels.iter().all(|&color| color == 0));
+}
+
+#[test]
+fn screenshots() {
+    // LD A,$FF; LDH (BGP),A
+    let program = [0x3e, 0xff, 0xe0, 0x47, 0x18, 0xfe];
+    let mut gb = GameBoy::new(common::rom("screenshot", &program).to_str().unwrap());
+    assert_eq!(gb.title(), "screenshot");
+    gb.run_frame();
+    gb.run_frame();
+
+    let image = gb.screenshot().scale(2).unwrap();
+    assert_eq!((image.width, image.height), (320, 288));
+    assert!(image.pixels.iter().all(|&color| color == 0));
+}
+
+#[test]
+fn frames_continue_with_lcd_off() {
+    // LD A,0; LDH (LCDC),A
+    let program = [0x3e, 0x00, 0xe0, 0x40, 0x18, 0xfe];
+    let mut gb = GameBoy::new(common::rom("lcd-off", &program).to_str().unwrap());
+    gb.run_frame();
+    let frame = gb.run_frame();
+
+    assert_eq!(frame.number, 2);
+    assert!(frame.pixels.iter().all(|&color| color == 0xffffff));
+}
+
+#[test]
+fn run_cycles_and_run_until() {
+    // INC B; JR -3
+    let program = [0x04, 0x18, 0xfd];
+    let mut gb = GameBoy::new(common::rom("run-until", &program).to_str().unwrap());
+
+    let cycles = gb.run_until(|gb| gb.registers.b == 10);
+    assert_eq!(gb.registers.b, 10);
+    assert_eq!(cycles, gb.cycles());
+
+    assert!(gb.run_cycles(1000) >= 1000);
+    assert_eq!(gb.frame_count(), 0);
+}
+
+#[test]
+fn oam_dma_blocks_oam_from_the_second_cycle() {
+    let program = [
+        0x3e, 0x00, 0xe0, 0x40, // LD A,0; LDH (LCDC),A
+        0x21, 0x00, 0xfe, // LD HL,$FE00
+        0x3e, 0x42, 0x77, // LD A,$42; LD (HL),A
+        0x3e, 0xc0, 0xe0, 0x46, // LD A,$C0; LDH (DMA),A
+        0x46, // LD B,(HL)
+        0x18, 0xfe,
+    ];
+    let mut gb = GameBoy::new(common::rom("oam-dma", &program).to_str().unwrap());
+    gb.run_until(|gb| gb.registers.pc == 0x10f);
+    // the opcode was fetched during the startup cycle, the read of OAM
+    // happened while the first byte was copied
+    assert_eq!(gb.registers.b, 0xff);
+}
+
+#[test]
+fn timer_sees_writes_on_their_m_cycle() {
+    let program = [
+        0x31, 0x06, 0xff, // LD SP,$FF06
+        0x3e, 0x05, 0xe0, 0x07, // LD A,5; LDH (TAC),A: TIMA every 16 cycles
+        0x01, 0x00, 0x00, // LD BC,0
+        0xc5, // PUSH BC: TIMA then DIV cleared, after an internal cycle
+        0x00, // NOP
+        0xf0, 0x05, // LDH A,(TIMA)
+        0x18, 0xfe,
+    ];
+    let mut gb = GameBoy::new(common::rom("timer-push", &program).to_str().unwrap());
+    gb.run_until(|gb| gb.registers.pc == 0x10e);
+    // the DIV reset (a falling edge here), then 16 cycles until the read
+    assert_eq!(gb.registers.a, 2);
+}
+
+#[test]
+fn stop_waits_for_a_button() {
+    let program = [
+        0x3e, 0x10, 0xe0, 0x00, // LD A,$10; LDH (P1),A: buttons selected
+        0x10, 0x00, // STOP
+        0x04, // INC B
+        0x18, 0xfe,
+    ];
+    let mut gb = GameBoy::new(common::rom("stop", &program).to_str().unwrap());
+    gb.run_frame();
+    let frame = gb.run_frame();
+    assert!(frame.pixels.iter().all(|&color| color == 0xffffff));
+    assert_eq!(gb.registers.b, 0);
+    assert_eq!(gb.get_ffxx(0x04), 0);
+
+    // the d-pad is not selected
+    gb.set_pressed(vec![Button::Up]);
+    gb.run_frame();
+    assert_eq!(gb.registers.b, 0);
+
+    gb.set_pressed(vec![Button::A]);
+    gb.run_frame();
+    assert_eq!(gb.registers.b, 1);
+}
+
+/// Holds A and records the frames it is given
+#[derive(Clone, Default)]
+struct Recorder {
+    frames: Rc<RefCell<Vec<u64>>>,
+}
+
+impl FrameSink for Recorder {
+    fn present(&mut self, frame: &Frame) {
+        self.frames.borrow_mut().push(frame.number);
+    }
+}
+
+impl InputSource for Recorder {
+    fn pressed_buttons(&mut self) -> Vec<Button> {
+        vec![Button::A]
+    }
+}
+
+#[test]
+fn frontend_gets_frames_and_gives_buttons() {
+    let mut gb = GameBoy::new(common::rom("frontend", &LOOP).to_str().unwrap());
+    let recorder = Recorder::default();
+    gb.set_frontend(recorder.clone());
+
+    // select the buttons
+    gb.memory_write(0xff00, 0x10);
+    assert_eq!(gb.memory_read(0xff00) & 0x0f, 0x0f);
+    gb.run_frame();
+    gb.run_frame();
+    assert_eq!(*recorder.frames.borrow(), vec![1, 2]);
+    assert_eq!(gb.memory_read(0xff00) & 0x0f, 0x0e);
+
+    gb.set_pressed(vec![Button::B]);
+    assert_eq!(gb.memory_read(0xff00) & 0x0f, 0x0d);
+}
+
+#[test]
+fn records_one_video_frame_per_frame() {
+    let mut gb = GameBoy::new(common::rom("record", &LOOP).to_str().unwrap());
+    let path = std::env::temp_dir().join(format!("rs-boy-record-{}.y4m", std::process::id()));
+    gb.start_recording(&path).unwrap();
+    assert!(gb.is_recording());
+    for _ in 0..3 {
+        gb.run_frame();
+    }
+    gb.stop_recording().unwrap();
+    gb.run_frame();
+
+    let video = std::fs::read(&path).unwrap();
+    let header = b"YUV4MPEG2 W160 H144 F4194304:70224 Ip A1:1 C444\n";
+    assert_eq!(video.len(), header.len() + 3 * (6 + 160 * 144 * 3));
+    let audio = std::fs::read(path.with_extension("wav")).unwrap();
+    assert_eq!(audio.len(), 44 + (3 * 70224 * 48000 / 4194304) * 4);
+
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(path.with_extension("wav")).unwrap();
+
+    // the WAV track keeps the rate it started with
+    gb.start_recording(&path).unwrap();
+    gb.set_sample_rate(48000);
+    assert!(gb.is_recording());
+    gb.set_sample_rate(32768);
+    assert!(!gb.is_recording());
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(path.with_extension("wav")).unwrap();
+}
+
+#[test]
+fn pulls_audio_samples() {
+    // channel 2 at full volume and 512Hz: NR22 = $F0, NR23 = 0, NR24 = $87
+    let program = [
+        0x3e, 0xf0, 0xe0, 0x17, 0x3e, 0x00, 0xe0, 0x18, 0x3e, 0x87, 0xe0, 0x19, 0x18, 0xfe,
+    ];
+    let mut gb = GameBoy::new(common::rom("audio", &program).to_str().unwrap());
+    gb.set_sample_rate(32768);
+    gb.run_frame();
+    gb.take_samples();
+    gb.run_frame();
+
+    let samples = gb.take_samples();
+    // 70224 T-cycles per frame
+    assert!((548..=549).contains(&samples.len()), "{}", samples.len());
+    assert!(samples.iter().any(|(left, _)| left.abs() > 1000));
+    assert!(gb.take_samples().is_empty());
+}
+
+struct Speaker {
+    samples: Rc<RefCell<Vec<(i16, i16)>>>,
+    sample_rate: Rc<Cell<u32>>,
+}
+
+impl AudioSink for Speaker {
+    fn play(&mut self, samples: &[(i16, i16)]) {
+        self.samples.borrow_mut().extend(samples);
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate.set(sample_rate);
+    }
+}
+
+#[test]
+fn pushes_audio_samples_to_the_sink() {
+    let mut gb = GameBoy::new(common::rom("audio_sink", &LOOP).to_str().unwrap());
+    let samples = Rc::new(RefCell::new(Vec::new()));
+    let sample_rate = Rc::new(Cell::new(0));
+    gb.set_audio_sink(Box::new(Speaker {
+        samples: samples.clone(),
+        sample_rate: sample_rate.clone(),
+    }));
+    assert_eq!(sample_rate.get(), gb.sample_rate());
+    gb.run_frame();
+    gb.run_frame();
+
+    assert!(samples.borrow().len() > 800);
+    assert!(gb.take_samples().is_empty());
+
+    gb.set_sample_rate(32768);
+    assert_eq!(sample_rate.get(), 32768);
+}
+
+#[test]
+fn plays_gbs_tracks() {
+    let mut gbs = vec![0; 0x70];
+    // 2 tracks from the first, load and init at 0x400, play at 0x410,
+    // stack at 0xfffe, played on VBlank
+    gbs[..16].copy_from_slice(&[
+        b'G', b'B', b'S', 1, 2, 1, 0x00, 0x04, 0x00, 0x04, 0x10, 0x04, 0xfe, 0xff, 0, 0,
+    ]);
+    // init: channel 2 at full volume and 512Hz, the track number as length
+    let mut code = vec![
+        0xe0, 0x16, 0x3e, 0xf0, 0xe0, 0x17, 0x3e, 0x00, 0xe0, 0x18, 0x3e, 0x87, 0xe0, 0x19, 0xc9,
+    ];
+    code.resize(0x10, 0);
+    // play: RET
+    code.push(0xc9);
+    gbs.extend(code);
+
+    let mut player = GbsPlayer::new(Gbs::parse(&gbs).unwrap());
+    assert_eq!((player.gbs().tracks, player.track()), (2, 0));
+    player.run_frame();
+    player.take_samples();
+    let frame = player.run_frame();
+    // the current track is drawn in black
+    assert_eq!(frame.pixels[2 * frame.width + 2], 0x000000);
+    assert_eq!(frame.pixels[2 * frame.width + 7], 0xa0a0a0);
+
+    let samples = player.take_samples();
+    assert!((803..=804).contains(&samples.len()), "{}", samples.len());
+    assert!(samples.iter().any(|(left, _)| left.abs() > 1000));
+
+    player.play_track(2);
+    assert_eq!(player.track(), 0);
+}
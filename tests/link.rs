@@ -0,0 +1,77 @@
+mod common;
+
+use rs_boy::gameboy::{GameBoy, LinkedGameBoys};
+
+/// SB = data, SC = control, then loop forever
+fn start_transfer(data: u8, control: u8) -> Vec<u8> {
+    vec![
+        0x3e, data, // LD A,data
+        0xe0, 0x01, // LDH (SB),A
+        0x3e, control, // LD A,control
+        0xe0, 0x02, // LDH (SC),A
+        0x18, 0xfe, // JR -2
+    ]
+}
+
+#[test]
+fn exchanges_a_byte() {
+    let master = common::rom("link-master", &start_transfer(0x42, 0x81));
+    let slave = common::rom("link-slave", &start_transfer(0x99, 0x80));
+
+    let mut link = LinkedGameBoys::new(
+        GameBoy::new(master.to_str().unwrap()),
+        GameBoy::new(slave.to_str().unwrap()),
+    );
+    link.run_cycles(8192);
+
+    assert_eq!(link.console(0).memory_read(0xff01), 0x99);
+    assert_eq!(link.console(1).memory_read(0xff01), 0x42);
+    // transfers completed
+    assert_eq!(link.console(0).memory_read(0xff02), 0x7f);
+    assert_eq!(link.console(1).memory_read(0xff02), 0x7e);
+    // serial interrupt requested on both sides
+    assert_eq!(link.console(0).memory_read(0xff0f) & 0x08, 0x08);
+    assert_eq!(link.console(1).memory_read(0xff0f) & 0x08, 0x08);
+}
+
+#[test]
+fn slave_waits_for_the_clock() {
+    let slave = common::rom("link-lonely-slave", &start_transfer(0x99, 0x80));
+    let idle = common::rom("link-idle", &[0x18, 0xfe]);
+
+    let mut link = LinkedGameBoys::new(
+        GameBoy::new(slave.to_str().unwrap()),
+        GameBoy::new(idle.to_str().unwrap()),
+    );
+    link.run_cycles(70224 * 2);
+
+    assert_eq!(link.console(0).memory_read(0xff01), 0x99);
+    assert_eq!(link.console(0).memory_read(0xff02), 0xfe);
+}
+
+#[test]
+fn double_speed_console_runs_twice_the_cycles() {
+    let program = [
+        0x3e, 0x01, 0xe0, 0x4d, // LD A,1; LDH (KEY1),A
+        0x10, 0x00, // STOP
+        0x18, 0xfe, // JR -2
+    ];
+    let fast = common::cgb_rom("link-double-speed", &program);
+    let idle = common::rom("link-normal-speed", &[0x18, 0xfe]);
+
+    let mut link = LinkedGameBoys::new(
+        GameBoy::new(fast.to_str().unwrap()),
+        GameBoy::new(idle.to_str().unwrap()),
+    );
+    link.run_cycles(70224);
+
+    assert!(link.console(0).is_double_speed());
+    let (fast, normal) = (link.console(0).cycles(), link.console(1).cycles());
+    assert!((70224..70224 + 12).contains(&normal), "{}", normal);
+    // the switch itself is at normal speed
+    assert!(
+        (2 * 70224 - 200..2 * 70224 + 24).contains(&fast),
+        "{}",
+        fast
+    );
+}